@@ -21,7 +21,13 @@ generate_commitment_hash() {
     fi
     
     local secret=$(cat .mixer_secret.txt)
-    echo -n "$secret" | openssl dgst -sha256 -hex | sed 's/^.* //'
+    # The contract account is part of the preimage (domain separation)
+    echo -n "commit:$CONTRACT_ID:$secret" | openssl dgst -sha256 -hex | sed 's/^.* //'
+}
+
+generate_recipient_commitment() {
+    local secret=$(cat .mixer_secret.txt)
+    echo -n "recipient:$CONTRACT_ID:$secret:$1" | openssl dgst -sha256 -hex | sed 's/^.* //'
 }
 
 # Main functions
@@ -32,7 +38,7 @@ print_help() {
     echo "  $0 init <your-account> <contract-id> [network]  - Initialize CLI"
     echo "  $0 deploy                                       - Deploy the contract"
     echo "  $0 secret                                       - Generate a new secret"
-    echo "  $0 deposit <amount>                             - Deposit NEAR to the mixer"
+    echo "  $0 deposit <amount> [recipient]                 - Deposit NEAR to the mixer"
     echo "  $0 withdraw <recipient>                         - Withdraw NEAR to an account"
     echo "  $0 stats                                        - Show mixer pool statistics"
     echo ""
@@ -41,6 +47,7 @@ print_help() {
     echo "  $0 deploy"
     echo "  $0 secret"
     echo "  $0 deposit 1"
+    echo "  $0 deposit 1 bob.testnet"
     echo "  $0 withdraw bob.testnet"
 }
 
@@ -103,11 +110,12 @@ deposit() {
     load_config
     
     if [ -z "$1" ]; then
-        echo "Error: Missing amount parameter. Usage: $0 deposit <amount>"
+        echo "Error: Missing amount parameter. Usage: $0 deposit <amount> [recipient]"
         exit 1
     fi
     
     local amount="$1"
+    local recipient="$2"
     
     # Validate denomination
     if [[ ! "$amount" =~ ^(1|10|100)$ ]]; then
@@ -124,8 +132,15 @@ deposit() {
     echo "Calculating commitment hash from your secret..."
     local commitment_hash=$(generate_commitment_hash)
     
+    local args="{\"commitment_hash\": \"$commitment_hash\"}"
+    if [ ! -z "$recipient" ]; then
+        echo "Binding the deposit to recipient $recipient..."
+        local recipient_commitment=$(generate_recipient_commitment "$recipient")
+        args="{\"commitment_hash\": \"$commitment_hash\", \"recipient_commitment\": \"$recipient_commitment\"}"
+    fi
+    
     echo "Depositing $amount NEAR to the mixer..."
-    near call $CONTRACT_ID deposit "{\"commitment_hash\": \"$commitment_hash\"}" \
+    near call $CONTRACT_ID deposit "$args" \
         --accountId $NEAR_ACCOUNT --networkId $NETWORK --deposit $amount --gas 300000000000000
     
     echo "Deposit complete!"
@@ -194,7 +209,7 @@ case "$1" in
         create_secret
         ;;
     deposit)
-        deposit "$2"
+        deposit "$2" "$3"
         ;;
     withdraw)
         withdraw "$2"
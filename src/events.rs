@@ -0,0 +1,104 @@
+use near_sdk::serde_json::{json, Value};
+use near_sdk::{env, AccountId, NearToken};
+
+// Eventos NEP-297: cada log tiene la forma `EVENT_JSON:{"standard", "version", "event", "data"}`.
+// `standard` es el namespace configurado en `new`, para distinguir varios despliegues del mixer
+pub(crate) const DEFAULT_EVENT_NAMESPACE: &str = "near-mixer";
+const EVENT_VERSION: &str = "1.0.0";
+
+fn emit(namespace: &str, event: &str, data: Value) {
+    let log = json!({
+        "standard": namespace,
+        "version": EVENT_VERSION,
+        "event": event,
+        "data": [data],
+    });
+    env::log_str(&format!("EVENT_JSON:{}", log));
+}
+
+/// Cambio de un parámetro por parte del owner, con el valor anterior y el nuevo. En los cambios con
+/// plazo, `new_value` es el valor programado
+pub(crate) fn config_change(namespace: &str, field: &str, old_value: Value, new_value: Value) {
+    emit(namespace, "config_change", json!({
+        "field": field,
+        "old_value": old_value,
+        "new_value": new_value,
+    }));
+}
+
+/// `pool` es "fixed" o "flexible", para que los indexadores distingan las notas de privacidad más débil
+pub(crate) fn deposit(namespace: &str, commitment_hash: &str, denomination: NearToken, pool: &str) {
+    emit(namespace, "deposit", json!({
+        "commitment_hash": commitment_hash,
+        "denomination": denomination.as_yoctonear().to_string(),
+        "pool": pool,
+    }));
+}
+
+pub(crate) fn withdraw(namespace: &str, withdrawal_hash: &str, recipient: &AccountId, amount: u128, fee: u128) {
+    emit(namespace, "withdraw", json!({
+        "withdrawal_hash": withdrawal_hash,
+        "recipient": recipient,
+        "amount": amount.to_string(),
+        "fee": fee.to_string(),
+    }));
+}
+
+pub(crate) fn withdraw_failed(namespace: &str, withdrawal_hash: &str, recipient: &AccountId) {
+    emit(namespace, "withdraw_failed", json!({
+        "withdrawal_hash": withdrawal_hash,
+        "recipient": recipient,
+    }));
+}
+
+pub(crate) fn split_withdraw(namespace: &str, withdrawal_hash: &str, payouts: &[(AccountId, u128)], fee: u128) {
+    let payouts: Vec<Value> = payouts
+        .iter()
+        .map(|(recipient, amount)| json!({ "recipient": recipient, "amount": amount.to_string() }))
+        .collect();
+    emit(namespace, "split_withdraw", json!({
+        "withdrawal_hash": withdrawal_hash,
+        "payouts": payouts,
+        "fee": fee.to_string(),
+    }));
+}
+
+pub(crate) fn payout_failed(namespace: &str, recipient: &AccountId, amount: NearToken) {
+    emit(namespace, "payout_failed", json!({
+        "recipient": recipient,
+        "amount": amount.as_yoctonear().to_string(),
+    }));
+}
+
+/// Nota vencida por `commitment_ttl` cuyo valor pasó al fondo de decomisos
+pub(crate) fn note_forfeited(namespace: &str, commitment_hash: &str, amount: NearToken) {
+    emit(namespace, "note_forfeited", json!({
+        "commitment_hash": commitment_hash,
+        "amount": amount.as_yoctonear().to_string(),
+    }));
+}
+
+/// Alerta: `deposit` o `withdraw` encontraron el saldo por debajo de lo reservado y el contrato se
+/// pausó solo, con los retiros detenidos
+pub(crate) fn insolvency_detected(namespace: &str, balance: NearToken, reserved: NearToken) {
+    emit(namespace, "insolvency_detected", json!({
+        "balance": balance.as_yoctonear().to_string(),
+        "reserved": reserved.as_yoctonear().to_string(),
+    }));
+}
+
+/// La nota vieja queda gastada y su valor pasa a la nota nueva sin salir del contrato
+pub(crate) fn note_migrated(
+    namespace: &str,
+    withdrawal_hash: &str,
+    commitment_hash: &str,
+    from_denomination: NearToken,
+    to_denomination: NearToken,
+) {
+    emit(namespace, "note_migrated", json!({
+        "withdrawal_hash": withdrawal_hash,
+        "commitment_hash": commitment_hash,
+        "from_denomination": from_denomination.as_yoctonear().to_string(),
+        "to_denomination": to_denomination.as_yoctonear().to_string(),
+    }));
+}
@@ -1,120 +1,3478 @@
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
-use near_sdk::collections::{LookupMap, UnorderedSet};
-use near_sdk::{env, near_bindgen, AccountId, PanicOnDefault, Promise, Timestamp, NearToken};
+use near_sdk::collections::{LookupMap, LookupSet, UnorderedSet, Vector};
+use near_sdk::json_types::{Base64VecU8, U128};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::serde_json::json;
+use near_sdk::{env, near_bindgen, AccountId, CurveType, Gas, PanicOnDefault, Promise, PromiseError, PromiseOrValue, PublicKey, Timestamp, NearToken};
 use sha2::{Digest, Sha256};
 
+mod events;
+mod merkle;
+mod migration;
+mod storage;
+
+pub use storage::{StorageBalance, StorageBalanceBounds};
+
 // const MIN_DELAY: u64 = 3600 * 24; // 24 hours in seconds
 const MIN_DELAY: u64 = 180; // 3 mins in second
+// En orden ascendente: las vistas de estadísticas recorren esta lista y no los mapas, así que sus
+// filas salen siempre en este orden
 const DENOMINATIONS: [NearToken; 3] = [
     NearToken::from_near(1),    // 1 NEAR
     NearToken::from_near(10),   // 10 NEAR
     NearToken::from_near(100),  // 100 NEAR
 ];
+// Tope de comisión por defecto (500 = 5%)
+const DEFAULT_MAX_FEE_BASIS_POINTS: u16 = 500;
+// Límite absoluto que ningún tope configurado puede superar (2000 = 20%)
+const HARD_MAX_FEE_BASIS_POINTS: u16 = 2000;
+// Tope por defecto de la comisión de relayer, sobre la denominación de la nota (500 = 5%)
+const DEFAULT_MAX_RELAYER_FEE_BASIS_POINTS: u16 = 500;
+// Versiones del esquema de commitment/nullifier. La 1 es el esquema original, SHA-256 del
+// secreto sin separador de dominio; la 2 agrega la cuenta del contrato al preimage; la 3 además
+// prefija el commitment con "commit:", así ningún commitment puede coincidir con un hash de
+// retiro, que lleva "withdraw:", y etiqueta el hash de retiro con "v3:", así las notas de los
+// esquemas 2 y 3 de un mismo secreto no comparten hash de retiro.
+const SCHEME_V1: u8 = 1;
+const SCHEME_V2: u8 = 2;
+const SCHEME_V3: u8 = 3;
+// Esquema con el que se registran los depósitos nuevos
+const CURRENT_SCHEME_VERSION: u8 = SCHEME_V3;
+// Tiempo que debe pasar para que un cambio de comisión entre en vigor (24 horas en segundos)
+const FEE_CHANGE_DELAY: u64 = 3600 * 24;
+// Máximo de entradas del historial de depósitos recorridas por llamada de vista
+const MAX_HISTORY_SCAN: u64 = 500;
+// Máximo de filas que devuelve una llamada a una vista paginada, pida lo que pida `limit`
+const MAX_PAGE_LIMIT: u64 = 100;
+// Máximo de entradas guardadas en el historial de comisiones
+const MAX_FEE_HISTORY: usize = 50;
+// Máximo de destinatarios en un retiro repartido
+const MAX_SPLIT_PAYOUTS: usize = 5;
+// Gas reservado para el callback `withdraw_resolve`
+const WITHDRAW_RESOLVE_GAS: Gas = Gas::from_tgas(10);
+// Gas mínimo que debe quedar al entrar a `withdraw`: la ejecución del propio retiro más el
+// callback. Con menos, el callback podría quedarse sin gas y dejar el estado a medias.
+const MIN_WITHDRAW_GAS: Gas = Gas::from_tgas(30);
+// Gas mínimo que debe quedar al entrar a `deposit`, para fallar de entrada y no a mitad del registro
+const MIN_DEPOSIT_GAS: Gas = Gas::from_tgas(10);
+// Máximo de retiros por llamada a `batch_withdraw`; cada uno necesita `MIN_WITHDRAW_GAS`
+const MAX_BATCH_WITHDRAWALS: usize = 5;
+// Gas de la llamada al método del destinatario cuando el retiro va a un contrato; se suma a
+// `MIN_WITHDRAW_GAS`
+const RECIPIENT_CALL_GAS: Gas = Gas::from_tgas(30);
+// Gas de la llamada a `migrate` que sigue al despliegue del código nuevo en `upgrade`
+const UPGRADE_MIGRATE_GAS: Gas = Gas::from_tgas(100);
+// Gas reservado para el callback `upgrade_resolve`
+const UPGRADE_RESOLVE_GAS: Gas = Gas::from_tgas(10);
+// Máximo de commitments por llamada a `rekey_deposits`, para no agotar el gas
+const MAX_REKEY_BATCH: usize = 100;
+// Gas de la llamada `is_allowed` al contrato de screening AML
+const AML_CHECK_GAS: Gas = Gas::from_tgas(10);
+// Gas reservado para `aml_resolve`, incluido el de `withdraw_resolve`; si el retiro va al método de
+// un contrato se le suma `RECIPIENT_CALL_GAS`
+const AML_RESOLVE_GAS: Gas = Gas::from_tgas(20);
+// Largo máximo en bytes de la nota cifrada opcional de un depósito
+const MAX_ENCRYPTED_NOTE_LEN: usize = 512;
+// Máximo de commitments por llamada a `get_deposit_receipts`, para acotar el gas de la vista
+const MAX_RECEIPT_BATCH: usize = 100;
+// Máximo de commitments por llamada a `forfeit_expired_notes`, para no agotar el gas
+const MAX_FORFEIT_BATCH: usize = 100;
+// Máximo de notas creadas por una llamada a `deposit_optimal`; cada una necesita `MIN_DEPOSIT_GAS`
+const MAX_OPTIMAL_DEPOSIT_NOTES: usize = 10;
+// Módulo del campo escalar de BN254 en hex de 64 dígitos; con `strict_field_elements`, los
+// commitments deben ser menores para poder probarse en un circuito
+const BN254_FIELD_MODULUS_HEX: &str = "30644e72e131a029b85045b68181585d2833e84879b9709143e1f593f0000001";
 
 #[near_bindgen]
 #[derive(BorshDeserialize, BorshSerialize, PanicOnDefault)]
 pub struct UtxoMixer {
-    // Hash del secreto -> Información del depósito
+    // Hash del secreto -> Información del depósito, sin versión de formato. Solo contiene depósitos
+    // anteriores a `versioned_deposits`; los nuevos ya no se escriben aquí.
     deposits: LookupMap<String, DepositInfo>,
-    // Hash de retiro usado -> true (para prevenir doble gasto)
+    // Hash de retiro usado -> true (para prevenir doble gasto). `contains`, `insert` y `remove` leen o
+    // escriben claves puntuales del trie, así que su gas no crece con el número de retiros; no se
+    // debe recorrer el conjunto en un método que no sea de solo lectura.
     spent_outputs: UnorderedSet<String>,
-    // Owner para comisiones
+    // Owner que administra el contrato
     owner: AccountId,
+    // Cuenta que recibe las comisiones retiradas (por defecto, el owner)
+    treasury: AccountId,
     // Comisión en basis points (100 = 1%)
     fee_basis_points: u16,
     // Estadísticas por denominación
     deposit_counts: LookupMap<NearToken, u64>,
+    // Tope de comisión contra el que se valida cualquier cambio de comisión
+    max_fee_basis_points: u16,
+    // Cambio de comisión programado: (nueva comisión, timestamp desde el que aplica)
+    pending_fee: Option<(u16, Timestamp)>,
+    // Comisión específica de una denominación, que reemplaza a la global para esa denominación
+    denomination_fees: LookupMap<NearToken, DenominationFee>,
+    // Destinatarios que retiran sin pagar comisión (exención pública, visible en el retiro)
+    fee_exempt_recipients: UnorderedSet<AccountId>,
+    // Comisiones cobradas y aún no retiradas por el owner
+    accumulated_fees: NearToken,
+    // Ganancia neta mínima (comisiones menos gas estimado) para que valga la pena retirar comisiones
+    fee_sweep_threshold: NearToken,
+    // Pool flexible: acepta montos arbitrarios (privacidad más débil), separado de los pools fijos.
+    // Como `deposits`, solo guarda depósitos anteriores a `versioned_flexible_deposits`.
+    flexible_deposits: LookupMap<String, DepositInfo>,
+    flexible_pool_enabled: bool,
+    flexible_pool_min: NearToken,
+    flexible_deposit_count: u64,
+    flexible_pool_total: NearToken,
+    // Historial de depósitos en orden de llegada (solo datos ya públicos, nunca el depositante)
+    deposit_history: Vector<DepositRecord>,
+    // Pagos de retiros repartidos cuya transferencia falló, pendientes de reintento por destinatario
+    failed_payouts: LookupMap<AccountId, NearToken>,
+    failed_payouts_total: NearToken,
+    // Si es falso solo se emiten los eventos NEP-297, sin los mensajes legibles
+    verbose_logging: bool,
+    // Tope global al valor de una sola nota, independiente de las denominaciones aceptadas
+    max_note_value: Option<NearToken>,
+    // Modo de comisión de los nuevos depósitos en pools fijos
+    fee_mode: FeeMode,
+    // Monto neto mínimo de un retiro del pool flexible, contra notas de polvo
+    min_withdrawal_amount: NearToken,
+    // Árbol de Merkle con los commitments de todos los depósitos, en orden de llegada
+    tree: merkle::MerkleTree,
+    // Denominaciones fijas que ya no aceptan depósitos; sus notas se pueden migrar a otra activa
+    deprecated_denominations: UnorderedSet<NearToken>,
+    // Cuenta que creó cada nota activa, solo para la excepción del piso de anonimato. Solo se guarda
+    // si el depositante registró saldo de almacenamiento (NEP-145) que cubra la entrada
+    depositors: LookupMap<String, AccountId>,
+    // Piso de anonimato: notas mínimas en un pool fijo para poder retirar de él (0 = desactivado)
+    min_pool_size_for_withdrawal: u64,
+    // Saldos de almacenamiento NEP-145 por cuenta
+    storage_accounts: LookupMap<AccountId, storage::AccountStorage>,
+    // Últimos cambios de la comisión global: (timestamp desde el que aplica, comisión)
+    fee_history: Vec<(Timestamp, u16)>,
+    // Depósitos hechos con `deposit_raw`, con el commitment como bytes (solo denominaciones fijas)
+    raw_deposits: LookupMap<[u8; 32], VersionedDepositInfo>,
+    // Si `deposit` sigue aceptando commitments hex; se puede apagar para usar solo `deposit_raw`
+    hex_deposits_enabled: bool,
+    // Cuentas autorizadas a depositar cuando la lista de depositantes está activa
+    allowed_depositors: UnorderedSet<AccountId>,
+    // Si solo las cuentas de `allowed_depositors` pueden depositar; los retiros no se ven afectados
+    deposit_allowlist_enabled: bool,
+    // Activo entre `upgrade` y el final de `migrate`: depósitos y retiros quedan bloqueados
+    migration_in_progress: bool,
+    // Tope de la comisión que un relayer puede cobrar en `batch_withdraw`, en puntos básicos de la denominación
+    max_relayer_fee_basis_points: u16,
+    // Timestamp del último depósito en cada denominación fija, para detectar pools inactivos
+    last_deposit_timestamp: LookupMap<NearToken, Timestamp>,
+    // Depósitos fijos con commitment hex, guardados con su versión de formato. Los depósitos
+    // escritos antes de que existiera el formato versionado siguen en `deposits`, que solo se lee y
+    // se limpia. Las notas flexibles que guardaron aquí versiones anteriores se leen igual.
+    versioned_deposits: LookupMap<String, VersionedDepositInfo>,
+    // Suma de los saldos de almacenamiento NEP-145 de todas las cuentas registradas
+    storage_balances_total: NearToken,
+    // Comisiones (de protocolo y de relayer) de retiros cuyo callback aún no se ejecutó. Se
+    // reservan al consumir la nota y se liquidan en el callback, para que no cuenten como excedente
+    // ni se pierdan entre ambos pasos.
+    in_flight_fees: NearToken,
+    // Comisión de `withdraw` antes de `MIN_DELAY` para quien acepta la penalización; 0 desactiva el
+    // retiro anticipado
+    early_withdrawal_penalty_basis_points: u16,
+    // Funciones de hash aceptadas para depósitos nuevos; las notas existentes se retiran con la suya
+    // aunque deje de aceptarse
+    accepted_hash_schemes: Vec<HashScheme>,
+    // Commitments (en hex) registrados alguna vez, en cualquier esquema, función de hash o formato de
+    // clave; impide volver a depositar uno ya retirado. Las notas anteriores a este registro entran
+    // al consumirse, y siguen protegidas mientras estén activas porque se buscan en todos los mapas.
+    registered_commitments: LookupSet<String>,
+    // Si las comisiones acumuladas superan este monto, `withdraw` las envía a la tesorería en la
+    // misma transacción; `None` desactiva el envío automático
+    auto_sweep_threshold: Option<NearToken>,
+    // Denominaciones fijas congeladas por el owner: no aceptan depósitos ni migraciones hacia ellas
+    frozen_denominations: UnorderedSet<NearToken>,
+    // Si el congelamiento también bloquea los retiros de notas existentes; por defecto siempre pueden salir
+    freeze_blocks_withdrawals: bool,
+    // Contrato de screening AML que aprueba cada destinatario de `withdraw`; `None` lo desactiva
+    aml_contract: Option<AccountId>,
+    // Pausa de depósitos del owner; los retiros siguen funcionando
+    deposits_paused: bool,
+    // Si la pausa se declaró por una emergencia: mientras dure, los retiros no esperan `MIN_DELAY`
+    emergency_exit: bool,
+    // Redondeo de las comisiones calculadas en basis points
+    fee_rounding: FeeRounding,
+    // Recargo que cada depósito adjunta además de la denominación para subsidiar el gas de los
+    // relayers; 0 lo desactiva
+    deposit_surcharge: NearToken,
+    // Recargos cobrados y aún no reclamados por los relayers aprobados
+    relayer_subsidy_pool: NearToken,
+    approved_relayers: UnorderedSet<AccountId>,
+    // `standard` de los eventos NEP-297, fijado en `new`
+    event_namespace: String,
+    // Rechazar commitments que no sean elementos del campo de BN254
+    strict_field_elements: bool,
+    // Posición en el árbol de cada commitment (en hex) insertado desde que existe este mapa
+    leaf_indices: LookupMap<String, u64>,
+    // Comisión de `withdraw` mientras el pool fijo de la nota tenga menos de `low_anonymity_pool_floor`
+    // notas, contando la que se retira; 0 la desactiva
+    low_anonymity_fee_basis_points: u16,
+    low_anonymity_pool_floor: u64,
+    // Topes de valor bloqueado en notas activas, global y por denominación fija; sin tope por defecto
+    max_tvl_global: Option<NearToken>,
+    max_tvl_per_denomination: LookupMap<NearToken, NearToken>,
+    // Vida máxima de una nota en segundos y desde cuándo rige; solo vencen las notas depositadas
+    // después de activarla. `None` la desactiva
+    commitment_ttl: Option<(u64, Timestamp)>,
+    // Valor de las notas vencidas retiradas por el owner, pendiente de enviar con `sweep_forfeited`
+    forfeited_pool: NearToken,
+    // Etiquetas para mostrar de las denominaciones fijas, p. ej. "10 USDC"
+    denomination_labels: LookupMap<NearToken, String>,
+    // Máximo de hojas insertadas en el árbol por bloque, para acotar el gas; 0 lo desactiva
+    max_inserts_per_block: u32,
+    // (altura del bloque, hojas insertadas en ese bloque)
+    block_inserts: (u64, u32),
+    // Espera en segundos desde que una denominación vuelve a estar disponible hasta que acepta
+    // depósitos, para que no se pueda depositar y retirar antes de que se forme el pool; 0 la desactiva
+    denomination_activation_delay: u64,
+    // Desde cuándo acepta depósitos cada denominación reactivada con espera
+    denomination_active_at: LookupMap<NearToken, Timestamp>,
+    // Largo mínimo en bytes del secreto revelado al retirar y desde cuándo rige; solo se exige a las
+    // notas depositadas después de activarlo. 0 lo desactiva
+    min_secret_bytes: (u32, Timestamp),
+    // Si `deposit` y `withdraw` pausan el contrato al encontrarlo insolvente, en lugar de seguir
+    auto_pause_on_insolvency: bool,
+    // Retiros detenidos por una insolvencia detectada, hasta que el owner lo levante
+    insolvency_halt: bool,
+    // Relayers que pueden enviar retiros de cada denominación fija en `batch_withdraw`; una
+    // denominación sin lista usa la global
+    denomination_relayers: LookupMap<NearToken, UnorderedSet<AccountId>>,
+    // Si la lista global de relayers (`approved_relayers`) restringe `batch_withdraw` en las
+    // denominaciones sin lista propia
+    relayer_allowlist_enabled: bool,
+    // Cuentas a las que se puede retirar cuando `withdrawal_allowlist_enabled` está activo, p. ej.
+    // las cuentas de custodia de un despliegue cerrado
+    withdrawal_allowlist: UnorderedSet<AccountId>,
+    withdrawal_allowlist_enabled: bool,
+    // Saldo aportado con `add_reserve` para cubrir el crecimiento del almacenamiento o el redondeo;
+    // no es comisión ni obligación, y el excedente no lo incluye
+    reserve_buffer: NearToken,
+    // Auditoría de contadores en curso, si `audit_counts` todavía no recorrió todo el historial
+    count_audit: Option<CountAudit>,
+    // Bytes que pagan los saldos de almacenamiento de las cuentas registradas (registro y entradas a
+    // su nombre); ya cuentan en el staking de almacenamiento del contrato
+    storage_locked_bytes: u64,
+    // Depósitos del pool flexible con su versión de formato, con su propio prefijo como los
+    // heredados de `flexible_deposits`
+    versioned_flexible_deposits: LookupMap<String, VersionedDepositInfo>,
+}
+
+#[derive(BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct DepositInfo {
+    denomination: NearToken,
+    timestamp: Timestamp,
+    // Vinculación opcional con el destinatario: Sha256 de secreto + destinatario
+    recipient_commitment: Option<String>,
+    // Versión del esquema con el que se creó la nota; el retiro se verifica con esa versión
+    scheme_version: u8,
+    // Modo de comisión vigente al depositar; el retiro respeta este modo y no el actual
+    fee_mode: FeeMode,
+    // Función de hash del commitment y del hash de retiro, declarada al depositar
+    hash_scheme: HashScheme,
+    // Datos de la nota cifrados por el usuario con su propia clave, para recuperarla; el contrato
+    // los guarda como bytes opacos
+    encrypted_note: Option<Vec<u8>>,
+    // Clave ed25519 que debe firmar cada retiro por relayer; con clave, la nota solo se retira con
+    // `batch_withdraw` y una firma válida
+    relay_key: Option<PublicKey>,
+}
+
+impl DepositInfo {
+    /// Nota nueva del esquema vigente, depositada ahora y sin datos opcionales
+    fn new(denomination: NearToken, fee_mode: FeeMode, hash_scheme: HashScheme) -> Self {
+        Self {
+            denomination,
+            timestamp: env::block_timestamp(),
+            recipient_commitment: None,
+            scheme_version: CURRENT_SCHEME_VERSION,
+            fee_mode,
+            hash_scheme,
+            encrypted_note: None,
+            relay_key: None,
+        }
+    }
+}
+
+/// Función de hash con la que se derivan el commitment y el hash de retiro de una nota. Durante una
+/// transición se pueden aceptar varias para depósitos nuevos; el retiro usa siempre la de la nota.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde", rename_all = "snake_case")]
+pub enum HashScheme {
+    Sha256,
+    Keccak256,
+}
+
+impl HashScheme {
+    /// Digest en hex de `preimage`
+    fn digest_hex(self, preimage: &str) -> String {
+        match self {
+            Self::Sha256 => format!("{:x}", Sha256::digest(preimage.as_bytes())),
+            Self::Keccak256 => to_hex(&env::keccak256(preimage.as_bytes())),
+        }
+    }
+    
+    /// Largo en caracteres hex de un commitment de este esquema
+    fn commitment_hex_len(self) -> usize {
+        match self {
+            Self::Sha256 | Self::Keccak256 => 64,
+        }
+    }
+}
+
+/// Momento en que se cobra la comisión de una nota
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde", rename_all = "snake_case")]
+pub enum FeeMode {
+    // Se descuenta del monto retirado
+    AtWithdraw,
+    // Se adjunta además de la denominación al depositar; el retiro paga la denominación completa
+    AtDeposit,
+}
+
+/// Tipo de pausa vigente. En mantenimiento solo se bloquean los depósitos y los retiros, también
+/// los de relayers ya enviados, se completan igual; en emergencia además se abre la salida de
+/// emergencia y los retiros no esperan `MIN_DELAY`.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde", rename_all = "snake_case")]
+pub enum PauseState {
+    Running,
+    Maintenance,
+    Emergency,
+}
+
+/// Redondeo de `monto * basis points / 10000` cuando no es exacto
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde", rename_all = "snake_case")]
+pub enum FeeRounding {
+    // Hacia abajo, a favor de quien retira
+    Down,
+    // Al entero más cercano; la mitad exacta redondea hacia arriba
+    Nearest,
+    // Hacia arriba, a favor del protocolo
+    Up,
+}
+
+impl FeeRounding {
+    /// `basis_points` de `amount`, redondeado según el modo y nunca mayor que `amount`
+    fn apply(self, amount: u128, basis_points: u16) -> u128 {
+        let scaled = amount * u128::from(basis_points);
+        let fee = match self {
+            Self::Down => scaled / 10000,
+            Self::Nearest => (scaled + 5000) / 10000,
+            Self::Up => scaled.div_ceil(10000),
+        };
+        fee.min(amount)
+    }
+}
+
+/// Configuración vigente del contrato, para consultarla en una sola llamada
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ContractConfig {
+    pub owner: AccountId,
+    pub treasury: AccountId,
+    // Comisión global vigente, con un cambio programado ya aplicado si cumplió su plazo
+    pub fee_basis_points: u16,
+    pub max_fee_basis_points: u16,
+    pub fee_mode: FeeMode,
+    pub fee_rounding: FeeRounding,
+    pub max_note_value: Option<U128>,
+    pub min_delay_seconds: u64,
+    pub deposits_paused: bool,
+    pub emergency_exit: bool,
+    pub event_namespace: String,
+}
+
+/// Resultado de `get_tree_snapshot`: el estado mínimo para seguir insertando hojas fuera de la
+/// cadena sin repetir todos los depósitos
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct TreeSnapshot {
+    // Subárbol completo más reciente de cada nivel, de las hojas (nivel 0) hacia arriba
+    pub filled_subtrees: Vec<String>,
+    pub next_index: u64,
+    pub current_root: String,
+    pub height: u8,
+}
+
+/// Resultado de `get_health`: el estado operativo en una sola consulta, para monitores de disponibilidad
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct HealthStatus {
+    pub is_solvent: bool,
+    pub pause_state: PauseState,
+    pub migration_in_progress: bool,
+    // Retiros detenidos por la pausa automática por insolvencia
+    pub insolvency_halt: bool,
+    pub total_deposits: u64,
+    // Ocupación del árbol de Merkle en puntos básicos de su capacidad (10000 = lleno)
+    pub tree_fill_basis_points: u16,
+    pub accumulated_fees: U128,
+}
+
+// Los depósitos de la primera versión solo guardaban denominación y timestamp. Se leen como notas
+// del esquema 1, sin vinculación con destinatario, para que sigan siendo retirables tras migrar.
+// Las notas anteriores a los modos de comisión no guardan el modo y siempre pagaron al retirar, y
+// las anteriores a los esquemas de hash usan SHA-256. Las anteriores a las notas cifradas no tienen.
+impl BorshDeserialize for DepositInfo {
+    fn deserialize_reader<R: borsh::io::Read>(reader: &mut R) -> borsh::io::Result<Self> {
+        let denomination = NearToken::deserialize_reader(reader)?;
+        let timestamp = Timestamp::deserialize_reader(reader)?;
+        let mut option_tag = [0u8; 1];
+        if reader.read(&mut option_tag)? == 0 {
+            return Ok(Self {
+                denomination,
+                timestamp,
+                recipient_commitment: None,
+                scheme_version: SCHEME_V1,
+                fee_mode: FeeMode::AtWithdraw,
+                hash_scheme: HashScheme::Sha256,
+                encrypted_note: None,
+                relay_key: None,
+            });
+        }
+        let recipient_commitment = match option_tag[0] {
+            0 => None,
+            1 => Some(String::deserialize_reader(reader)?),
+            _ => return Err(borsh::io::Error::new(borsh::io::ErrorKind::InvalidData, "Invalid Option tag")),
+        };
+        let scheme_version = u8::deserialize_reader(reader)?;
+        let mut fee_mode_tag = [0u8; 1];
+        let fee_mode = if reader.read(&mut fee_mode_tag)? == 0 {
+            FeeMode::AtWithdraw
+        } else {
+            <FeeMode as BorshDeserialize>::deserialize(&mut fee_mode_tag.as_slice())?
+        };
+        let mut hash_scheme_tag = [0u8; 1];
+        let hash_scheme = if reader.read(&mut hash_scheme_tag)? == 0 {
+            HashScheme::Sha256
+        } else {
+            <HashScheme as BorshDeserialize>::deserialize(&mut hash_scheme_tag.as_slice())?
+        };
+        let mut encrypted_note_tag = [0u8; 1];
+        let encrypted_note = if reader.read(&mut encrypted_note_tag)? == 0 {
+            None
+        } else {
+            match encrypted_note_tag[0] {
+                0 => None,
+                1 => Some(Vec::<u8>::deserialize_reader(reader)?),
+                _ => return Err(borsh::io::Error::new(borsh::io::ErrorKind::InvalidData, "Invalid Option tag")),
+            }
+        };
+        let mut relay_key_tag = [0u8; 1];
+        let relay_key = if reader.read(&mut relay_key_tag)? == 0 {
+            None
+        } else {
+            match relay_key_tag[0] {
+                0 => None,
+                1 => Some(PublicKey::deserialize_reader(reader)?),
+                _ => return Err(borsh::io::Error::new(borsh::io::ErrorKind::InvalidData, "Invalid Option tag")),
+            }
+        };
+        Ok(Self {
+            denomination,
+            timestamp,
+            recipient_commitment,
+            scheme_version,
+            fee_mode,
+            hash_scheme,
+            encrypted_note,
+            relay_key,
+        })
+    }
+}
+
+/// Formato de la primera versión de `DepositInfo`: solo denominación y timestamp
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct DepositInfoV1 {
+    denomination: NearToken,
+    timestamp: Timestamp,
+}
+
+/// Formato de la segunda versión de `DepositInfo`, anterior a los esquemas de hash (siempre SHA-256)
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct DepositInfoV2 {
+    denomination: NearToken,
+    timestamp: Timestamp,
+    recipient_commitment: Option<String>,
+    scheme_version: u8,
+    fee_mode: FeeMode,
+}
+
+/// `DepositInfo` tal como se almacena, precedido por un discriminante de versión para que cada
+/// entrada diga con qué formato fue escrita. Un cambio de formato agrega una variante nueva en lugar
+/// de modificar las existentes, y `into_current` convierte cualquier versión en la actual.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub enum VersionedDepositInfo {
+    V1(DepositInfoV1),
+    V2(DepositInfoV2),
+    V3(DepositInfo),
+}
+
+impl VersionedDepositInfo {
+    fn into_current(self) -> DepositInfo {
+        match self {
+            // Igual que las notas heredadas sin versión: esquema 1, sin destinatario, comisión al retirar
+            Self::V1(DepositInfoV1 { denomination, timestamp }) => DepositInfo {
+                denomination,
+                timestamp,
+                recipient_commitment: None,
+                scheme_version: SCHEME_V1,
+                fee_mode: FeeMode::AtWithdraw,
+                hash_scheme: HashScheme::Sha256,
+                encrypted_note: None,
+                relay_key: None,
+            },
+            Self::V2(DepositInfoV2 { denomination, timestamp, recipient_commitment, scheme_version, fee_mode }) => {
+                DepositInfo {
+                    denomination,
+                    timestamp,
+                    recipient_commitment,
+                    scheme_version,
+                    fee_mode,
+                    hash_scheme: HashScheme::Sha256,
+                    encrypted_note: None,
+                    relay_key: None,
+                }
+            }
+            Self::V3(deposit) => deposit,
+        }
+    }
+}
+
+/// Comisión específica de una denominación. Sus cambios siguen el mismo plazo que la comisión global:
+/// `pending` es (nuevo valor, timestamp desde el que aplica), donde `None` quita la comisión específica.
+#[derive(BorshDeserialize, BorshSerialize, Default)]
+struct DenominationFee {
+    fee_basis_points: Option<u16>,
+    pending: Option<(Option<u16>, Timestamp)>,
+}
+
+impl DenominationFee {
+    fn effective(&self) -> Option<u16> {
+        match self.pending {
+            Some((fee, effective_at)) if env::block_timestamp() >= effective_at => fee,
+            _ => self.fee_basis_points,
+        }
+    }
+    
+    /// La comisión más alta que esta denominación tiene vigente o programada
+    fn highest(&self) -> u16 {
+        let pending = self.pending.and_then(|(fee, _)| fee).unwrap_or(0);
+        self.effective().unwrap_or(0).max(pending)
+    }
+}
+
+/// Un retiro dentro de `batch_withdraw`. La comisión del relayer se descuenta del monto del
+/// destinatario y se paga a quien envía el lote.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct WithdrawRequest {
+    pub recipient: AccountId,
+    pub secret: String,
+    pub relayer_fee: Option<U128>,
+    // Firma ed25519 de `relay_message`, obligatoria si la nota tiene clave de relay
+    pub relay_signature: Option<Base64VecU8>,
+}
+
+/// Resultado de `withdraw`: lo que se transfiere y a quién, calculado con los mismos valores que el
+/// pago. Que el pago se complete lo confirman los eventos `withdraw_failed`: si la transferencia
+/// falla, la nota se restaura.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct WithdrawalReceipt {
+    pub recipient: AccountId,
+    pub net_amount: U128,
+    pub fee: U128,
+    // `withdraw` no tiene comisión de relayer; el campo la deja explícita en 0
+    pub relayer_fee: U128,
+    // Hash de retiro de la nota, que queda marcado como gastado
+    pub nullifier_hash: String,
+}
+
+/// Retiro de `withdraw` con la nota ya consumida y la comisión reservada, pendiente de la transferencia
+/// (o de la aprobación del screening AML)
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PendingWithdrawal {
+    recipient: AccountId,
+    recipient_method: Option<String>,
+    recipient_msg: Option<String>,
+    amount: U128,
+    commitment_hash: String,
+    withdrawal_hash: String,
+    deposit: DepositInfo,
+    fee: U128,
+}
+
+/// Foto del respaldo del contrato en un bloque, leída de `env`. No lleva firma: su autenticidad es
+/// la del nodo RPC que responde a la consulta, o la del bloque si se lee dentro de una transacción.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ReservesAttestation {
+    pub account_balance: U128,
+    pub total_obligations: U128,
+    pub accumulated_fees: U128,
+    pub block_height: u64,
+    pub block_timestamp: Timestamp,
+}
+
+/// Estado de un elemento de un lote
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde", rename_all = "snake_case")]
+pub enum BatchItemStatus {
+    Queued,
+    Skipped,
+}
+
+/// Resultado de un elemento de un lote: su posición en la entrada, su estado y el detalle (el hash
+/// de retiro si se encoló, el motivo si se omitió)
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct BatchResult {
+    pub index: u32,
+    pub status: BatchItemStatus,
+    pub detail: Option<String>,
 }
 
+/// Todo lo que el contrato sabe de una nota activa, para que una herramienta de recuperación
+/// confirme qué datos de la nota se pueden reconstruir
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct DepositReceipt {
+    // Commitment en hex, sin el prefijo de las claves raw
+    pub commitment_hash: String,
+    pub denomination: U128,
+    pub timestamp: Timestamp,
+    // Posición en el árbol; `None` para notas anteriores al registro de posiciones, p. ej. las migradas
+    pub leaf_index: Option<u64>,
+    pub scheme_version: u8,
+    pub hash_scheme: HashScheme,
+    // Desde cuándo se puede retirar sin penalización
+    pub unlock_timestamp: Timestamp,
+}
+
+/// Fila de `get_denomination_overview`: estado y configuración de una denominación fija
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct DenominationOverview {
+    pub denomination: U128,
+    pub active_notes: u64,
+    // Valor de las notas activas, en yoctoNEAR
+    pub total_amount: U128,
+    // Comisión vigente al retirar: la específica de la denominación o, si no tiene, la global
+    pub fee_basis_points: u16,
+    // Comisión en yoctoNEAR que pagaría ahora una nota madura depositada con el modo de comisión
+    // actual, calculada como en `withdraw` (sin exenciones de destinatario)
+    pub withdrawal_fee: U128,
+    // Espera mínima en segundos entre el depósito y el retiro
+    pub min_delay_seconds: u64,
+    pub deprecated: bool,
+    // Si `deposit` acepta ahora esta denominación: no obsoleta, no congelada, ya activa y dentro del
+    // valor máximo de nota
+    pub accepts_deposits: bool,
+    // Desde cuándo acepta depósitos tras su última reactivación con espera; `None` si nunca la tuvo
+    pub active_at: Option<Timestamp>,
+    pub last_deposit_timestamp: Option<Timestamp>,
+    // Valor máximo del pool fijado para esta denominación; `None` si solo rige el global
+    pub max_tvl: Option<U128>,
+}
+
+/// Avance de un `audit_counts` repartido en varias llamadas
 #[derive(BorshDeserialize, BorshSerialize)]
-struct DepositInfo {
+struct CountAudit {
+    // Próxima entrada del historial a recorrer
+    next_index: u64,
+    // Largo del historial y hashes gastados al empezar: si cambian, las notas contadas ya no valen
+    history_len: u64,
+    spent_len: u64,
+    // Notas activas contadas hasta ahora, en el orden de `DENOMINATIONS`
+    counts: Vec<u64>,
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+struct DepositRecord {
+    commitment_hash: String,
     denomination: NearToken,
     timestamp: Timestamp,
 }
 
+/// Monto en yoctoNEAR como `U128`, el tipo de los montos en la API JSON. Las denominaciones, que
+/// tienen valor en NEAR enteros, se exponen como `NearToken`.
+fn yocto(amount: NearToken) -> U128 {
+    U128(amount.as_yoctonear())
+}
+
+/// Inverso de `yocto`
+fn near_token(amount: U128) -> NearToken {
+    NearToken::from_yoctonear(amount.0)
+}
+
+// Prefijo de la clave de un commitment depositado como bytes con `deposit_raw`. Las claves hex
+// nunca lo llevan, así que el resto del contrato distingue ambos formatos por la clave.
+const RAW_COMMITMENT_PREFIX: &str = "0x";
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Bytes de una clave de commitment raw ("0x" + hex), o `None` si es una clave hex
+fn raw_commitment_bytes(commitment_key: &str) -> Option<[u8; 32]> {
+    let hex = commitment_key.strip_prefix(RAW_COMMITMENT_PREFIX)?;
+    if hex.len() != 64 {
+        return None;
+    }
+    let mut bytes = [0u8; 32];
+    for (byte, pair) in bytes.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+    Some(bytes)
+}
+
+/// Commitment en hex sin prefijo, el formato de las hojas del árbol y de los eventos
+fn commitment_hex(commitment_key: &str) -> &str {
+    commitment_key.strip_prefix(RAW_COMMITMENT_PREFIX).unwrap_or(commitment_key)
+}
+
+/// Deriva el commitment de un secreto con el esquema actual. El preimage incluye la cuenta del
+/// contrato como separador de dominio, así un commitment válido en un despliegue no sirve en otro.
+fn commitment_for(secret: &str) -> String {
+    commitment_for_scheme(secret, CURRENT_SCHEME_VERSION, HashScheme::Sha256)
+}
+
+/// Secreto de nota ligado a un pool fijo: "{denominación en yocto}:{secreto}", donde el secreto puede
+/// llevar una sal delante ("{sal}:{secreto}"). Así el mismo secreto da un commitment y un hash de
+/// retiro distintos en cada pool, y se puede reutilizar entre denominaciones sin que los depósitos
+/// choquen.
+fn pool_secret(secret: &str, denomination: NearToken) -> String {
+    format!("{}:{}", denomination.as_yoctonear(), secret)
+}
+
+/// Deriva el commitment de un secreto con una versión concreta del esquema y una función de hash
+fn commitment_for_scheme(secret: &str, scheme_version: u8, hash_scheme: HashScheme) -> String {
+    let preimage = match scheme_version {
+        SCHEME_V1 => secret.to_string(),
+        SCHEME_V2 => format!("{}:{}", env::current_account_id(), secret),
+        SCHEME_V3 => format!("commit:{}:{}", env::current_account_id(), secret),
+        _ => env::panic_str("Unsupported scheme version"),
+    };
+    hash_scheme.digest_hex(&preimage)
+}
+
+/// Deriva la vinculación de un secreto con un destinatario concreto. Si el depósito la registra,
+/// quien vea el secreto en la mempool no puede redirigir el retiro a otra cuenta.
+fn recipient_commitment_for(secret: &str, recipient: &AccountId) -> String {
+    let preimage = format!("recipient:{}:{}:{}", env::current_account_id(), secret, recipient);
+    format!("{:x}", Sha256::digest(preimage.as_bytes()))
+}
+
+/// Mensaje que autoriza a `relayer` a retirar la nota de `withdrawal_hash` hacia `recipient` cobrando
+/// `relayer_fee`. Incluye el contrato, para que la firma no valga en otro despliegue.
+fn relay_message(withdrawal_hash: &str, recipient: &AccountId, relayer: &AccountId, relayer_fee: u128) -> String {
+    format!("relay:{}:{}:{}:{}:{}", env::current_account_id(), withdrawal_hash, recipient, relayer, relayer_fee)
+}
+
+/// Si `signature` es una firma ed25519 válida de `message` con `relay_key`
+fn verify_relay_signature(relay_key: &PublicKey, signature: Option<&Base64VecU8>, message: &str) -> bool {
+    // El primer byte de la clave es su tipo de curva, que el depósito ya comprobó
+    let (Some(signature), Ok(key)) = (signature, <[u8; 32]>::try_from(&relay_key.as_bytes()[1..])) else {
+        return false;
+    };
+    <[u8; 64]>::try_from(signature.0.as_slice())
+        .is_ok_and(|signature| env::ed25519_verify(&signature, message.as_bytes(), &key))
+}
+
+/// Deriva el hash de retiro (anti doble gasto) de un secreto con una versión concreta del esquema y
+/// una función de hash
+fn withdrawal_hash_for_scheme(secret: &str, scheme_version: u8, hash_scheme: HashScheme) -> String {
+    let preimage = match scheme_version {
+        SCHEME_V1 => format!("withdraw:{}", secret),
+        SCHEME_V2 => format!("withdraw:{}:{}", env::current_account_id(), secret),
+        SCHEME_V3 => format!("withdraw:v3:{}:{}", env::current_account_id(), secret),
+        _ => env::panic_str("Unsupported scheme version"),
+    };
+    hash_scheme.digest_hex(&preimage)
+}
+
 #[near_bindgen]
 impl UtxoMixer {
+    /// Inicializar el contrato. Solo se puede llamar una vez: si ya hay estado guardado, falla
+    /// con un error claro en lugar de sobrescribirlo. `root_history_size` es cuántas raíces recientes
+    /// del árbol se aceptan como conocidas (entre 2 y 256, 30 si se omite); no se puede cambiar después.
+    /// `event_namespace` es el `standard` de los eventos ("near-mixer" si se omite), p. ej.
+    /// "near-mixer-usdc" para que los indexadores distingan este despliegue de otros.
     #[init]
-    pub fn new(owner: AccountId, fee_basis_points: u16) -> Self {
-        assert!(fee_basis_points <= 500, "Fee cannot exceed 5%");
-        
-        Self {
-            deposits: LookupMap::new(b"d"),
-            spent_outputs: UnorderedSet::new(b"s"),
-            owner,
-            fee_basis_points,
-            deposit_counts: LookupMap::new(b"c"),
+    pub fn new(
+        owner: AccountId,
+        fee_basis_points: u16,
+        max_fee_basis_points: Option<u16>,
+        root_history_size: Option<u32>,
+        event_namespace: Option<String>,
+    ) -> Self {
+        let mut contract = Self::initial_state(owner, fee_basis_points, max_fee_basis_points, root_history_size);
+        if let Some(event_namespace) = event_namespace {
+            assert!(!event_namespace.is_empty(), "Event namespace cannot be empty");
+            contract.event_namespace = event_namespace;
         }
+        contract
     }
     
-    /// El usuario genera un secreto localmente, calcula su hash, y envía solo ese hash
-    #[payable]
-    pub fn deposit(&mut self, commitment_hash: String) {
-        let deposit_amount = env::attached_deposit();
+    /// Si el contrato ya fue inicializado. Consulta directamente la clave de estado, así que
+    /// funciona antes de `new`, cuando cualquier otro método fallaría.
+    pub fn is_initialized() -> bool {
+        env::state_exists()
+    }
+    
+    /// Migrar el estado de la primera versión desplegada a la actual. Los depósitos, hashes de retiro
+    /// y contadores existentes se conservan; los campos nuevos toman sus valores por defecto. Si el
+    /// estado no coincide con el formato heredado, el error indica qué campo falló.
+    /// Al final de un `upgrade` que no cambia el formato del estado, solo desbloquea depósitos y retiros.
+    #[private]
+    #[init(ignore_state)]
+    pub fn migrate() -> Self {
+        let state = env::storage_read(b"STATE").unwrap_or_else(|| env::panic_str("No contract state to migrate"));
+        if let Ok(mut contract) = Self::try_from_slice(&state) {
+            assert!(contract.migration_in_progress, "Contract state is already migrated");
+            contract.migration_in_progress = false;
+            return contract;
+        }
         
-        // Verificar que es una denominación aceptada
-        let mut valid_denomination = false;
-        for denom in DENOMINATIONS.iter() {
-            if &deposit_amount == denom {
-                valid_denomination = true;
-                
-                // Incrementar contador para esta denominación
-                let current_count = self.deposit_counts.get(denom).unwrap_or(0);
-                self.deposit_counts.insert(denom, &(current_count + 1));
-                break;
+        let legacy = migration::read_legacy_state(&state);
+        let mut contract = Self::initial_state(legacy.owner, legacy.fee_basis_points, None, None);
+        contract.deposits = legacy.deposits;
+        contract.spent_outputs = legacy.spent_outputs;
+        contract.deposit_counts = legacy.deposit_counts;
+        contract
+    }
+    
+    /// Mover notas activas a otro mapa o formato de clave (solo owner), p. ej. tras un `migrate` que
+    /// cambie la codificación. Sin `to_raw`, las notas de los mapas heredados sin versión (prefijos
+    /// `d` y `f`) pasan a `versioned_deposits` o `versioned_flexible_deposits`, igual que las notas
+    /// flexibles que quedaron en `versioned_deposits`; con `to_raw`, las notas con clave hex pasan a
+    /// `raw_deposits`, con la clave en bytes (solo denominaciones fijas). Como un `LookupMap` no se
+    /// puede recorrer, la lista de commitments la aporta quien llama, p. ej. desde los eventos de
+    /// depósito o `get_deposits_in_range`; los que no correspondan a una nota movible se omiten.
+    /// Los contadores, el árbol y los hashes de retiro no cambian, y `withdraw` encuentra la nota
+    /// en su nueva ubicación. Admite hasta 100 commitments por llamada y devuelve cuántos movió.
+    pub fn rekey_deposits(&mut self, commitment_hashes: Vec<String>, to_raw: bool) -> u32 {
+        self.assert_owner();
+        assert!(!commitment_hashes.is_empty() && commitment_hashes.len() <= MAX_REKEY_BATCH,
+                "Rekey batch must have between 1 and 100 commitments");
+        let mut moved = 0;
+        for commitment_hash in commitment_hashes {
+            if raw_commitment_bytes(&commitment_hash).is_some() {
+                continue;
+            }
+            let Some(deposit) = self.get_deposit(&commitment_hash) else {
+                continue;
+            };
+            let target = if to_raw {
+                let raw = format!("{}{}", RAW_COMMITMENT_PREFIX, commitment_hash);
+                if raw_commitment_bytes(&raw).is_none() || self.matches_denomination(deposit.denomination).is_none() {
+                    continue;
+                }
+                raw
+            } else {
+                let stored = match self.matches_denomination(deposit.denomination) {
+                    Some(_) => self.versioned_deposits.get(&commitment_hash),
+                    None => self.versioned_flexible_deposits.get(&commitment_hash),
+                };
+                if stored.is_some() {
+                    continue;
+                }
+                commitment_hash.clone()
+            };
+            
+            self.remove_deposit(&commitment_hash, &deposit);
+            self.store_deposit(&target, &deposit);
+            if target != commitment_hash {
+                if let Some(depositor) = self.depositors.get(&commitment_hash) {
+                    self.remove_depositor(&commitment_hash);
+                    self.record_depositor(&target, &depositor);
+                }
             }
+            moved += 1;
         }
-        assert!(valid_denomination, "Deposit must be one of the accepted denominations");
+        self.log(&format!("Moved {} deposits to the {} map", moved, if to_raw { "raw" } else { "versioned" }));
+        moved
+    }
+    
+    /// Definir la vida máxima de las notas en segundos, o `None` para desactivarla (solo owner). Una
+    /// nota depositada desde este momento que siga sin retirar al vencer puede pasar al fondo de
+    /// decomisos con `forfeit_expired_notes`; las notas anteriores nunca vencen por este plazo.
+    pub fn set_commitment_ttl(&mut self, ttl_seconds: Option<u64>) {
+        self.assert_owner();
+        assert!(ttl_seconds.is_none_or(|ttl| ttl > MIN_DELAY), "Commitment TTL must exceed the withdrawal delay");
+        events::config_change(&self.event_namespace, "commitment_ttl", json!(self.commitment_ttl.map(|(ttl, _)| ttl)),
+            json!(ttl_seconds));
+        self.commitment_ttl = ttl_seconds.map(|ttl| (ttl, env::block_timestamp()));
+    }
+    
+    /// (vida máxima en segundos, timestamp desde el que rige), o `None` si está desactivada
+    pub fn get_commitment_ttl(&self) -> Option<(u64, Timestamp)> {
+        self.commitment_ttl
+    }
+    
+    /// Pasar al fondo de decomisos las notas vencidas de la lista (solo owner). Como un `LookupMap`
+    /// no se puede recorrer, los commitments los aporta el owner, p. ej. desde los eventos de
+    /// depósito; los que no existan o no hayan vencido se omiten. Devuelve cuántas notas vencieron.
+    pub fn forfeit_expired_notes(&mut self, commitment_hashes: Vec<String>) -> u32 {
+        self.assert_owner();
+        assert!(!commitment_hashes.is_empty() && commitment_hashes.len() <= MAX_FORFEIT_BATCH,
+                "Forfeit batch must have between 1 and 100 commitments");
+        let Some((ttl, since)) = self.commitment_ttl else {
+            env::panic_str("Commitment TTL is disabled");
+        };
+        let mut forfeited = 0;
+        for commitment_hash in commitment_hashes {
+            let hex = commitment_hex(&commitment_hash).to_string();
+            let raw = format!("{}{}", RAW_COMMITMENT_PREFIX, hex);
+            let Some((key, deposit)) = [hex.clone(), raw]
+                .into_iter()
+                .find_map(|key| self.get_deposit(&key).map(|deposit| (key, deposit)))
+            else {
+                continue;
+            };
+            if deposit.timestamp < since || env::block_timestamp() < deposit.timestamp + ttl * 1_000_000_000 {
+                continue;
+            }
+            
+            self.remove_deposit(&key, &deposit);
+            self.remove_depositor(&key);
+            self.forfeited_pool = self.forfeited_pool.saturating_add(deposit.denomination);
+            events::note_forfeited(&self.event_namespace, &hex, deposit.denomination);
+            forfeited += 1;
+        }
+        self.log(&format!("Forfeited {} expired notes", forfeited));
+        forfeited
+    }
+    
+    pub fn get_forfeited_pool(&self) -> U128 {
+        yocto(self.forfeited_pool)
+    }
+    
+    /// Enviar todo el fondo de decomisos a `recipient`, p. ej. una cuenta benéfica, o a la tesorería
+    /// si se omite (solo owner). Si la transferencia falla, queda guardada para `retry_failed_payout`.
+    pub fn sweep_forfeited(&mut self, recipient: Option<AccountId>) -> Promise {
+        self.assert_owner();
+        let amount = self.forfeited_pool;
+        assert!(!amount.is_zero(), "No forfeited funds to sweep");
+        let recipient = recipient.unwrap_or_else(|| self.treasury.clone());
+        self.forfeited_pool = NearToken::from_yoctonear(0);
         
-        // Verificar que este commitment no existe ya
-        assert!(!self.deposits.contains_key(&commitment_hash), "Commitment already exists");
+        self.log(&format!("Sweeping {} yoctoNEAR of forfeited notes to {}", amount.as_yoctonear(), recipient));
+        Promise::new(recipient.clone()).transfer(amount).then(
+            Self::ext(env::current_account_id())
+                .with_static_gas(WITHDRAW_RESOLVE_GAS)
+                .payouts_resolve(vec![(recipient, yocto(amount))], U128(0)),
+        )
+    }
+    
+    /// Desplegar código nuevo (solo owner). El wasm se pasa como entrada cruda de la llamada. Marca la
+    /// migración en curso, de modo que depósitos y retiros fallan hasta que `migrate`, llamado en el
+    /// mismo lote que el despliegue, la da por terminada. Si el lote falla, el código anterior sigue
+    /// activo y `upgrade_resolve` desbloquea el contrato.
+    pub fn upgrade(&mut self) -> Promise {
+        self.assert_owner();
+        let code = env::input().unwrap_or_else(|| env::panic_str("Upgrade requires the contract code as input"));
+        self.migration_in_progress = true;
+        Promise::new(env::current_account_id())
+            .deploy_contract(code)
+            .function_call("migrate".to_string(), b"{}".to_vec(), NearToken::from_yoctonear(0), UPGRADE_MIGRATE_GAS)
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(UPGRADE_RESOLVE_GAS)
+                    .upgrade_resolve(),
+            )
+    }
+    
+    /// Callback de `upgrade`: si el despliegue o `migrate` fallaron, quita la marca de migración en
+    /// curso para que el contrato vuelva a aceptar depósitos y retiros con el código anterior.
+    #[private]
+    pub fn upgrade_resolve(&mut self) -> bool {
+        if near_sdk::is_promise_success() {
+            return true;
+        }
+        self.migration_in_progress = false;
+        self.log("Upgrade failed, contract unlocked");
+        false
+    }
+    
+    pub fn is_migration_in_progress(&self) -> bool {
+        self.migration_in_progress
+    }
+    
+    /// Ruta de recuperación cuando `migrate` no puede leer el estado: reconstruye el contrato a partir
+    /// de los prefijos de almacenamiento conocidos, sin leer el estado raíz. Los depósitos, los hashes
+    /// de retiro usados y los contadores por denominación se conservan porque viven bajo sus propios
+    /// prefijos; se pierden la longitud del conjunto de hashes de retiro (las búsquedas siguen
+    /// funcionando) y la configuración, que vuelve a los valores pasados aquí.
+    #[private]
+    #[init(ignore_state)]
+    pub fn recover_state(owner: AccountId, fee_basis_points: u16) -> Self {
+        Self::initial_state(owner, fee_basis_points, None, None)
+    }
+    
+    /// El usuario genera un secreto localmente, calcula su hash, y envía solo ese hash.
+    /// Opcionalmente registra `recipient_commitment` para que solo ese destinatario pueda retirar.
+    /// Si el pool flexible está habilitado, también acepta montos arbitrarios por encima del mínimo.
+    /// En modo de comisión al depositar, los pools fijos esperan la denominación más su comisión, y
+    /// con `deposit_surcharge` todo depósito adjunta además ese recargo. `hash_scheme` es la función
+    /// de hash del commitment (SHA-256 si se omite) y debe estar entre las aceptadas. Con `relay_key`,
+    /// una clave ed25519, la nota solo se retira por relayer con una firma de esa clave. El llamador
+    /// debe adjuntar al menos 10 Tgas (`MIN_DEPOSIT_GAS`).
+    #[payable]
+    pub fn deposit(
+        &mut self,
+        commitment_hash: String,
+        recipient_commitment: Option<String>,
+        hash_scheme: Option<HashScheme>,
+        encrypted_note: Option<Base64VecU8>,
+        relay_key: Option<PublicKey>,
+    ) {
+        assert!(self.hex_deposits_enabled, "Hex commitments are disabled, use deposit_raw");
+        self.accept_deposit(commitment_hash, recipient_commitment, hash_scheme.unwrap_or(HashScheme::Sha256),
+            encrypted_note.map(Vec::from), relay_key);
+    }
+    
+    /// Igual que `deposit`, pero con el commitment como los 32 bytes del hash en lugar de su hex:
+    /// la clave ocupa la mitad y no hay ambigüedad de mayúsculas. Solo para denominaciones fijas.
+    /// El retiro es el mismo `withdraw`, que encuentra la nota en cualquiera de los dos formatos.
+    #[payable]
+    pub fn deposit_raw(
+        &mut self,
+        commitment: [u8; 32],
+        recipient_commitment: Option<String>,
+        hash_scheme: Option<HashScheme>,
+        encrypted_note: Option<Base64VecU8>,
+        relay_key: Option<PublicKey>,
+    ) {
+        self.accept_deposit(format!("{}{}", RAW_COMMITMENT_PREFIX, to_hex(&commitment)), recipient_commitment,
+            hash_scheme.unwrap_or(HashScheme::Sha256), encrypted_note.map(Vec::from), relay_key);
+    }
+    
+    /// Aceptar o dejar de aceptar una función de hash para depósitos nuevos (solo owner), p. ej. para
+    /// migrar de SHA-256 a otra función: se aceptan ambas durante la transición y luego se retira la
+    /// vieja. Las notas ya registradas se pueden retirar igual. Debe quedar al menos una aceptada.
+    pub fn set_hash_scheme_accepted(&mut self, hash_scheme: HashScheme, accepted: bool) {
+        self.assert_owner();
+        let was_accepted = self.accepted_hash_schemes.contains(&hash_scheme);
+        if accepted && !was_accepted {
+            self.accepted_hash_schemes.push(hash_scheme);
+        } else if !accepted {
+            assert!(self.accepted_hash_schemes != [hash_scheme], "At least one hash scheme must remain accepted");
+            self.accepted_hash_schemes.retain(|scheme| *scheme != hash_scheme);
+        }
+        events::config_change(&self.event_namespace, &format!("hash_scheme_accepted:{}", json!(hash_scheme).as_str().unwrap_or_default()),
+            json!(was_accepted), json!(accepted));
+    }
+    
+    pub fn get_accepted_hash_schemes(&self) -> Vec<HashScheme> {
+        self.accepted_hash_schemes.clone()
+    }
+    
+    /// Habilitar o deshabilitar los depósitos con commitment hex (solo owner). Las notas hex
+    /// existentes se pueden retirar igual.
+    pub fn set_hex_deposits_enabled(&mut self, enabled: bool) {
+        self.assert_owner();
+        events::config_change(&self.event_namespace, "hex_deposits_enabled", json!(self.hex_deposits_enabled), json!(enabled));
+        self.hex_deposits_enabled = enabled;
+    }
+    
+    pub fn get_hex_deposits_enabled(&self) -> bool {
+        self.hex_deposits_enabled
+    }
+    
+    /// Activar o desactivar el modo estricto (solo owner): los depósitos nuevos deben tener un
+    /// commitment menor que el módulo de BN254, porque uno fuera de rango no se podría retirar con
+    /// una prueba ZK. Las notas existentes no se revisan.
+    pub fn set_strict_field_elements(&mut self, strict: bool) {
+        self.assert_owner();
+        events::config_change(&self.event_namespace, "strict_field_elements", json!(self.strict_field_elements), json!(strict));
+        self.strict_field_elements = strict;
+    }
+    
+    pub fn get_strict_field_elements(&self) -> bool {
+        self.strict_field_elements
+    }
+    
+    /// Cuerpo común de `deposit` y `deposit_raw`; `commitment_hash` es la clave en cualquiera de
+    /// los dos formatos
+    fn accept_deposit(
+        &mut self,
+        commitment_hash: String,
+        recipient_commitment: Option<String>,
+        hash_scheme: HashScheme,
+        encrypted_note: Option<Vec<u8>>,
+        relay_key: Option<PublicKey>,
+    ) {
+        self.assert_not_migrating();
+        assert!(!self.deposits_paused, "Deposits are paused");
+        if self.pause_if_insolvent() {
+            self.refund_attached_deposit();
+            return;
+        }
+        self.assert_hash_scheme(&commitment_hash, hash_scheme);
+        assert!(!self.deposit_allowlist_enabled || self.allowed_depositors.contains(&env::predecessor_account_id()),
+                "Account is not allowed to deposit");
+        assert!(env::prepaid_gas().saturating_sub(env::used_gas()) >= MIN_DEPOSIT_GAS,
+                "Not enough gas attached, deposit requires at least 10 Tgas");
+        let attached = env::attached_deposit()
+            .checked_sub(self.deposit_surcharge)
+            .unwrap_or_else(|| env::panic_str("Deposit must include the deposit surcharge"));
+        let (deposit_amount, deposit_fee, fee_mode) = self.split_deposit_fee(attached);
         
-        // Almacenar la información del depósito asociada al hash del commitment
-        self.deposits.insert(&commitment_hash, &DepositInfo {
-            denomination: deposit_amount,
-            timestamp: env::block_timestamp(),
+        // Verificar que es una denominación aceptada, o un monto válido para el pool flexible
+        let fixed_denomination = self.matches_denomination(deposit_amount);
+        let flexible = fixed_denomination.is_none();
+        let deposit_amount = fixed_denomination.unwrap_or(deposit_amount);
+        if flexible {
+            assert!(raw_commitment_bytes(&commitment_hash).is_none(),
+                    "Raw commitments are only accepted for fixed denominations");
+            assert!(self.flexible_pool_enabled, "Deposit must be one of the accepted denominations");
+            assert!(deposit_amount >= self.flexible_pool_min, "Deposit is below the flexible pool minimum");
+        } else {
+            assert!(!self.deprecated_denominations.contains(&deposit_amount), "This denomination is deprecated");
+            assert!(!self.frozen_denominations.contains(&deposit_amount), "This denomination is frozen");
+            assert!(self.is_denomination_active(deposit_amount), "This denomination is not active yet");
+        }
+        self.register_note(&commitment_hash, DepositInfo {
+            recipient_commitment,
+            encrypted_note,
+            relay_key,
+            ..DepositInfo::new(deposit_amount, fee_mode, hash_scheme)
         });
+        self.accumulated_fees = self.accumulated_fees.saturating_add(NearToken::from_yoctonear(deposit_fee));
+        self.relayer_subsidy_pool = self.relayer_subsidy_pool.saturating_add(self.deposit_surcharge);
+        
+        if flexible {
+            self.log(&format!("Deposit of {} yoctoNEAR accepted into the flexible pool (weaker privacy)",
+                deposit_amount.as_yoctonear()));
+            events::deposit(&self.event_namespace, commitment_hex(&commitment_hash), deposit_amount, "flexible");
+        } else {
+            self.log(&format!("Deposit of {} NEAR accepted", deposit_amount.as_near()));
+            events::deposit(&self.event_namespace, commitment_hex(&commitment_hash), deposit_amount, "fixed");
+        }
+    }
+    
+    /// Depositar un monto arbitrario repartido en notas fijas en una sola transacción. El monto adjunto,
+    /// menos `deposit_surcharge` por nota, se descompone de forma voraz en las denominaciones que
+    /// aceptan depósitos, de mayor a menor; debe haber exactamente un commitment (SHA-256, en hex) por
+    /// nota de la descomposición, y se asignan en ese orden. Devuelve la denominación de cada
+    /// commitment. No disponible en modo de comisión al depositar.
+    #[payable]
+    pub fn deposit_optimal(&mut self, commitments: Vec<String>) -> Vec<NearToken> {
+        self.assert_not_migrating();
+        assert!(!self.deposits_paused, "Deposits are paused");
+        if self.pause_if_insolvent() {
+            self.refund_attached_deposit();
+            return Vec::new();
+        }
+        assert!(self.hex_deposits_enabled, "Hex commitments are disabled, use deposit_raw");
+        assert!(self.fee_mode == FeeMode::AtWithdraw, "Optimal deposits are not available in at-deposit fee mode");
+        assert!(!self.deposit_allowlist_enabled || self.allowed_depositors.contains(&env::predecessor_account_id()),
+                "Account is not allowed to deposit");
+        assert!(!commitments.is_empty() && commitments.len() <= MAX_OPTIMAL_DEPOSIT_NOTES,
+                "Optimal deposit must have between 1 and 10 commitments");
+        let required_gas = Gas::from_gas(MIN_DEPOSIT_GAS.as_gas() * commitments.len() as u64);
+        assert!(env::prepaid_gas().saturating_sub(env::used_gas()) >= required_gas,
+                "Not enough gas attached, optimal deposit requires 10 Tgas per note");
+        let surcharges = self.deposit_surcharge.saturating_mul(commitments.len() as u128);
+        let mut remaining = env::attached_deposit()
+            .checked_sub(surcharges)
+            .unwrap_or_else(|| env::panic_str("Deposit must include the deposit surcharge for each note"))
+            .as_yoctonear();
         
-        env::log_str(&format!("Deposit of {} NEAR accepted", deposit_amount.as_near()));
+        // Descomposición voraz en las denominaciones disponibles, de mayor a menor
+        let mut denominations = Vec::new();
+        for &denomination in DENOMINATIONS.iter().rev().filter(|&&denomination| self.accepts_denomination(denomination)) {
+            let count = remaining / denomination.as_yoctonear();
+            assert!(denominations.len() as u128 + count <= commitments.len() as u128,
+                    "Commitment count does not match the split of the amount");
+            remaining -= count * denomination.as_yoctonear();
+            denominations.extend(std::iter::repeat_n(denomination, count as usize));
+        }
+        assert_eq!(remaining, 0, "Amount cannot be split exactly into accepted denominations");
+        assert_eq!(denominations.len(), commitments.len(), "Commitment count does not match the split of the amount");
+        
+        for (commitment_hash, &denomination) in commitments.iter().zip(&denominations) {
+            self.assert_hash_scheme(commitment_hash, HashScheme::Sha256);
+            self.register_note(commitment_hash, DepositInfo::new(denomination, FeeMode::AtWithdraw, HashScheme::Sha256));
+            self.relayer_subsidy_pool = self.relayer_subsidy_pool.saturating_add(self.deposit_surcharge);
+            self.log(&format!("Deposit of {} NEAR accepted", denomination.as_near()));
+            events::deposit(&self.event_namespace, commitment_hash, denomination, "fixed");
+        }
+        denominations
     }
     
-    /// Retirar fondos presentando el secreto original
-    pub fn withdraw(&mut self, recipient: AccountId, secret: String) {
-        // 1. Generar el hash del secreto para buscar el depósito
-        let commitment_hash = format!("{:x}", Sha256::digest(secret.as_bytes()));
+    /// Migrar una nota de una denominación obsoleta a una activa sin que los fondos salgan del
+    /// contrato: consume la nota vieja (su hash de retiro queda usado) y registra `new_commitment_hash`
+    /// en `target_denomination`, en una sola transacción. La proporción es 1:1 en valor: la
+    /// denominación destino no puede ser menor y la diferencia se adjunta en el depósito. La nota
+    /// nueva paga su comisión al retirar. La migración enlaza públicamente ambas notas. La nota nueva
+    /// cuenta para los topes de valor como un depósito, sin la vieja, que ya no está en su pool, y
+    /// con la lista de depositantes activa solo pueden migrar las cuentas permitidas.
+    #[payable]
+    pub fn migrate_note(&mut self, secret: String, new_commitment_hash: String, target_denomination: NearToken) {
+        self.assert_not_migrating();
+        assert!(!self.deposits_paused, "Deposits are paused");
+        if self.pause_if_insolvent() {
+            self.refund_attached_deposit();
+            return;
+        }
+        assert!(!self.deposit_allowlist_enabled || self.allowed_depositors.contains(&env::predecessor_account_id()),
+                "Account is not allowed to deposit");
+        assert!(self.matches_denomination(target_denomination).is_some()
+                && !self.deprecated_denominations.contains(&target_denomination)
+                && !self.frozen_denominations.contains(&target_denomination)
+                && self.is_denomination_active(target_denomination),
+                "Target must be an active denomination");
         
-        // 2. Verificar que existe un depósito con este hash
-        let deposit = self.deposits.get(&commitment_hash).expect("No deposit found for this secret");
+        let (commitment_hash, withdrawal_hash, old, _) = self.spend_note(&secret, None, false);
+        self.remove_depositor(&commitment_hash);
+        assert!(self.deprecated_denominations.contains(&old.denomination),
+                "Only notes in a deprecated denomination can be migrated");
+        assert_eq!(old.denomination.saturating_add(env::attached_deposit()), target_denomination,
+                   "Attached deposit must cover the difference to the target denomination");
         
-        // 3. Generar un hash de retiro único
-        let withdrawal_hash = format!("{:x}", Sha256::digest(format!("withdraw:{}", secret).as_bytes()));
+        self.assert_hash_scheme(&new_commitment_hash, HashScheme::Sha256);
+        self.register_note(&new_commitment_hash, DepositInfo::new(target_denomination, FeeMode::AtWithdraw, HashScheme::Sha256));
         
-        // 4. Verificar que este hash de retiro no se ha usado antes (prevenir doble gasto)
-        assert!(!self.spent_outputs.contains(&withdrawal_hash), "This secret has already been used");
+        self.log(&format!("Note migrated from {} NEAR to {} NEAR",
+            old.denomination.as_near(), target_denomination.as_near()));
+        events::note_migrated(&self.event_namespace, &withdrawal_hash, &new_commitment_hash, old.denomination, target_denomination);
+    }
+    
+    /// Retirar fondos presentando el secreto original. El secreto viaja en claro en los argumentos:
+    /// sin un verificador de pruebas no hay forma de retirar sin revelarlo.
+    /// El llamador debe adjuntar al menos 30 Tgas (`MIN_WITHDRAW_GAS`), de los cuales
+    /// 10 Tgas (`WITHDRAW_RESOLVE_GAS`) quedan reservados para `withdraw_resolve`.
+    /// Si el destinatario es un contrato, `recipient_method` hace que el monto se adjunte a una
+    /// llamada a ese método en lugar de transferirse, con `recipient_msg` como argumentos JSON
+    /// (por defecto `{}`); requiere además 30 Tgas (`RECIPIENT_CALL_GAS`) para esa llamada. Si el
+    /// método falla, la nota no se restaura, porque el fallo puede venir de una promesa que el método
+    /// devolvió después de quedarse con los fondos: el monto queda como pago fallido del destinatario.
+    /// Con `accept_early_penalty` y la penalización habilitada, se puede retirar antes de `MIN_DELAY`
+    /// pagando `early_withdrawal_penalty_basis_points` en lugar de la comisión normal.
+    /// Con `aml_contract` definido, el pago solo se hace si ese contrato aprueba al destinatario, y el
+    /// retiro requiere 30 Tgas más (`AML_CHECK_GAS` y `AML_RESOLVE_GAS`).
+    /// Devuelve un recibo con el destinatario, el monto neto, la comisión y el hash de retiro, o `None`
+    /// si el contrato se pausó solo al encontrarse insolvente y el retiro no se procesó.
+    /// Si el secreto está ligado a varios pools fijos, retira la nota del pool menor; para elegir
+    /// la nota de un pool mayor, cada nota debe llevar su propia sal.
+    pub fn withdraw(
+        &mut self,
+        recipient: AccountId,
+        secret: String,
+        recipient_method: Option<String>,
+        recipient_msg: Option<String>,
+        accept_early_penalty: Option<bool>,
+    ) -> Option<WithdrawalReceipt> {
+        self.assert_not_migrating();
+        self.assert_not_halted();
+        if self.pause_if_insolvent() {
+            return None;
+        }
         
-        // 5. Verificar que ha pasado suficiente tiempo
-        assert!(env::block_timestamp() - deposit.timestamp >= MIN_DELAY * 1_000_000_000, 
-                "Withdrawal too early");
+        // 0. Verificar que queda gas suficiente para completar el callback (y la llamada al destinatario)
+        let available_gas = env::prepaid_gas().saturating_sub(env::used_gas());
+        assert!(available_gas >= MIN_WITHDRAW_GAS, "Not enough gas attached, withdraw requires at least 30 Tgas");
+        assert!(recipient_method.is_none() || available_gas >= MIN_WITHDRAW_GAS.saturating_add(RECIPIENT_CALL_GAS),
+                "Not enough gas attached, withdrawing to a recipient method requires at least 60 Tgas");
+        assert!(recipient_method.is_some() || recipient_msg.is_none(), "recipient_msg requires recipient_method");
+        let aml_resolve_gas = AML_RESOLVE_GAS.saturating_add(
+            if recipient_method.is_some() { RECIPIENT_CALL_GAS } else { Gas::from_gas(0) });
+        let aml_required_gas = MIN_WITHDRAW_GAS.saturating_add(AML_CHECK_GAS).saturating_add(aml_resolve_gas);
+        assert!(self.aml_contract.is_none() || available_gas >= aml_required_gas,
+                "Not enough gas attached, withdrawing with AML screening requires at least {} Tgas",
+                aml_required_gas.as_tgas());
         
-        // 6. Marcar como usado
-        self.spent_outputs.insert(&withdrawal_hash);
+        // 0a. Las comisiones salen siempre de la nota, nunca del saldo de quien firma: retirar no
+        // adjunta NEAR
+        assert!(env::attached_deposit().is_zero(), "Withdrawals must not attach NEAR");
         
-        // 7. Eliminar el depósito
-        self.deposits.remove(&commitment_hash);
+        // 0b. El contrato no puede ser destinatario: los fondos contarían como pagados pero seguirían aquí
+        assert_ne!(recipient, env::current_account_id(), "The mixer contract cannot be the recipient");
+        assert!(self.is_destination_allowed(&recipient), "Recipient is not an approved withdrawal destination");
         
-        // 8. Calcular comisión
-        let fee = deposit.denomination.as_yoctonear() * u128::from(self.fee_basis_points) / 10000;
+        // 1-7. Validar el secreto y consumir la nota
+        let allow_early = accept_early_penalty.unwrap_or(false) && self.early_withdrawal_penalty_basis_points > 0;
+        // 8. La comisión (ver `fee_for`) sale de `spend_note`, calculada con la nota aún en su pool
+        let (commitment_hash, withdrawal_hash, deposit, fee) = self.spend_note(&secret, Some(&recipient), allow_early);
         let withdrawal_amount = deposit.denomination.as_yoctonear() - fee;
+        self.check_min_withdrawal(&deposit, withdrawal_amount).unwrap_or_else(|err| env::panic_str(&err));
+        // La comisión queda reservada en la misma llamada que marca la nota como gastada
+        self.reserve_in_flight_fees(fee);
+        
+        self.log(&format!("Withdrawal of {} NEAR processed to {}", 
+            NearToken::from_yoctonear(withdrawal_amount).as_near(), recipient));
+        events::withdraw(&self.event_namespace, &withdrawal_hash, &recipient, withdrawal_amount, fee);
+        self.auto_sweep_fees(NearToken::from_yoctonear(withdrawal_amount));
+        
+        let pending = PendingWithdrawal {
+            recipient,
+            recipient_method,
+            recipient_msg,
+            amount: U128(withdrawal_amount),
+            commitment_hash,
+            withdrawal_hash,
+            deposit,
+            fee: U128(fee),
+        };
+        // El recibo sale del mismo retiro pendiente que se paga, así que no puede divergir del pago
+        let receipt = WithdrawalReceipt {
+            recipient: pending.recipient.clone(),
+            net_amount: pending.amount,
+            fee: pending.fee,
+            relayer_fee: U128(0),
+            nullifier_hash: pending.withdrawal_hash.clone(),
+        };
+        
+        // 8b. Con screening AML, el pago espera a que el contrato de screening apruebe al destinatario
+        if let Some(aml_contract) = self.aml_contract.clone() {
+            Promise::new(aml_contract)
+                .function_call(
+                    "is_allowed".to_string(),
+                    json!({ "account_id": pending.recipient }).to_string().into_bytes(),
+                    NearToken::from_yoctonear(0),
+                    AML_CHECK_GAS,
+                )
+                .then(
+                    Self::ext(env::current_account_id())
+                        .with_static_gas(aml_resolve_gas)
+                        .aml_resolve(pending),
+                );
+        } else {
+            // 9. Transferir fondos al destinatario
+            self.pay_out(pending);
+        }
+        Some(receipt)
+    }
+    
+    /// Callback del screening AML de `withdraw`. Si el contrato de screening aprobó al destinatario,
+    /// hace el pago como `withdraw`; si lo rechazó o la llamada falló, restaura la nota y libera la
+    /// comisión reservada, como con una transferencia fallida.
+    #[private]
+    pub fn aml_resolve(
+        &mut self,
+        pending: PendingWithdrawal,
+        #[callback_result] allowed: Result<bool, PromiseError>,
+    ) -> PromiseOrValue<bool> {
+        if allowed == Ok(true) {
+            return PromiseOrValue::Promise(self.pay_out(pending));
+        }
+        
+        self.in_flight_fees = self.in_flight_fees.saturating_sub(near_token(pending.fee));
+        self.restore_note(&pending.commitment_hash, &pending.withdrawal_hash, &pending.deposit);
+        
+        self.log(&format!("Withdrawal to {} rejected by AML screening, deposit restored", pending.recipient));
+        events::withdraw_failed(&self.event_namespace, &pending.withdrawal_hash, &pending.recipient);
+        PromiseOrValue::Value(false)
+    }
+    
+    /// Pausar o reanudar los depósitos (solo owner). Con `emergency`, la pausa además habilita la
+    /// salida de emergencia: mientras dure, cualquier nota se puede retirar sin esperar `MIN_DELAY`
+    /// ni pagar penalización, con las demás validaciones intactas. Reanudar la desactiva.
+    pub fn set_deposits_paused(&mut self, paused: bool, emergency: bool) {
+        self.assert_owner();
+        assert!(paused || !emergency, "Only a pause can be declared an emergency");
+        events::config_change(&self.event_namespace, "deposits_paused", json!(self.deposits_paused), json!(paused));
+        events::config_change(&self.event_namespace, "emergency_exit", json!(self.emergency_exit), json!(emergency));
+        self.deposits_paused = paused;
+        self.emergency_exit = emergency;
+        if emergency {
+            self.log("Emergency pause: deposits halted and withdrawals no longer wait for the time-lock");
+        }
+    }
+    
+    pub fn get_deposits_paused(&self) -> bool {
+        self.deposits_paused
+    }
+    
+    /// Activar o desactivar la pausa automática por insolvencia (solo owner). Activa, `deposit` y
+    /// `withdraw` comprueban `is_solvent` antes de procesar nada; si falla, pausan los depósitos,
+    /// detienen todos los retiros y emiten `insolvency_detected`, y la llamada termina sin efecto.
+    pub fn set_auto_pause_on_insolvency(&mut self, enabled: bool) {
+        self.assert_owner();
+        events::config_change(&self.event_namespace, "auto_pause_on_insolvency", json!(self.auto_pause_on_insolvency), json!(enabled));
+        self.auto_pause_on_insolvency = enabled;
+    }
+    
+    pub fn get_auto_pause_on_insolvency(&self) -> bool {
+        self.auto_pause_on_insolvency
+    }
+    
+    /// Reanudar los retiros tras una pausa por insolvencia (solo owner), una vez repuesto el saldo.
+    /// Los depósitos siguen pausados hasta `set_deposits_paused`.
+    pub fn clear_insolvency_halt(&mut self) {
+        self.assert_owner();
+        assert!(self.is_solvent(), "Contract is still insolvent");
+        events::config_change(&self.event_namespace, "insolvency_halt", json!(self.insolvency_halt), json!(false));
+        self.insolvency_halt = false;
+    }
+    
+    pub fn is_insolvency_halt_active(&self) -> bool {
+        self.insolvency_halt
+    }
+    
+    /// Si la salida de emergencia está activa (depósitos pausados por una emergencia)
+    pub fn is_emergency_exit_active(&self) -> bool {
+        self.deposits_paused && self.emergency_exit
+    }
+    
+    /// Tipo de pausa vigente: sin pausa, de mantenimiento (`set_deposits_paused` sin `emergency`)
+    /// o de emergencia
+    pub fn get_pause_state(&self) -> PauseState {
+        match (self.deposits_paused, self.emergency_exit) {
+            (false, _) => PauseState::Running,
+            (true, false) => PauseState::Maintenance,
+            (true, true) => PauseState::Emergency,
+        }
+    }
+    
+    /// Definir el contrato de screening AML que debe aprobar a cada destinatario de `withdraw`, o
+    /// `None` para desactivarlo (solo owner). El contrato debe exponer `is_allowed(account_id) -> bool`.
+    /// Mientras esté activo, `batch_withdraw` y `withdraw_split` no están disponibles, ni se aceptan
+    /// depósitos con `relay_key`; las notas con clave ya depositadas esperan a que se desactive.
+    pub fn set_aml_contract(&mut self, aml_contract: Option<AccountId>) {
+        self.assert_owner();
+        events::config_change(&self.event_namespace, "aml_contract", json!(self.aml_contract), json!(aml_contract));
+        self.aml_contract = aml_contract;
+    }
+    
+    pub fn get_aml_contract(&self) -> Option<AccountId> {
+        self.aml_contract.clone()
+    }
+    
+    /// Procesar varios retiros en una sola llamada, pensado para relayers. Cada retiro se valida por
+    /// separado: los inválidos (nota inexistente, ya gastada, demasiado pronto, etc.) se omiten y el
+    /// resto continúa. Devuelve un resultado por retiro, en el mismo orden. La comisión de relayer de
+    /// cada retiro se paga al llamador cuando la transferencia al destinatario se completa; el
+    /// llamador no puede ser a la vez destinatario.
+    /// Admite hasta 5 retiros y requiere 30 Tgas por cada uno.
+    pub fn batch_withdraw(&mut self, withdrawals: Vec<WithdrawRequest>) -> Vec<BatchResult> {
+        self.assert_not_migrating();
+        assert!(self.aml_contract.is_none(), "Batch withdrawals are not available while AML screening is enabled");
+        assert!(!withdrawals.is_empty() && withdrawals.len() <= MAX_BATCH_WITHDRAWALS,
+                "Batch must have between 1 and 5 withdrawals");
+        let required_gas = Gas::from_gas(MIN_WITHDRAW_GAS.as_gas() * withdrawals.len() as u64);
+        assert!(env::prepaid_gas().saturating_sub(env::used_gas()) >= required_gas,
+                "Not enough gas attached, batch withdraw requires 30 Tgas per withdrawal");
+        assert!(env::attached_deposit().is_zero(), "Withdrawals must not attach NEAR");
+        let relayer = env::predecessor_account_id();
+        assert_ne!(relayer, env::current_account_id(), "The mixer contract cannot be the relayer");
+        
+        withdrawals
+            .into_iter()
+            .enumerate()
+            .map(|(index, request)| {
+                let (status, detail) = match self.batch_withdraw_one(&relayer, request) {
+                    Ok(withdrawal_hash) => (BatchItemStatus::Queued, withdrawal_hash),
+                    Err(reason) => (BatchItemStatus::Skipped, reason),
+                };
+                BatchResult { index: index as u32, status, detail: Some(detail) }
+            })
+            .collect()
+    }
+    
+    /// Callback de `withdraw` y `batch_withdraw`. Si la transferencia al destinatario falló, los fondos vuelven al
+    /// contrato y se restaura el depósito para que pueda retirarse de nuevo; si tuvo éxito, se
+    /// acredita la comisión en `accumulated_fees`. Devuelve si el retiro se completó.
+    #[private]
+    pub fn withdraw_resolve(
+        &mut self,
+        recipient: AccountId,
+        commitment_hash: String,
+        withdrawal_hash: String,
+        deposit: DepositInfo,
+        fee: U128,
+        relayer_fee: Option<(AccountId, U128)>,
+    ) -> bool {
+        let relayer_amount = relayer_fee.as_ref().map_or(0, |(_, amount)| amount.0);
+        self.in_flight_fees = self.in_flight_fees.saturating_sub(NearToken::from_yoctonear(fee.0 + relayer_amount));
+        if near_sdk::is_promise_success() {
+            self.remove_depositor(&commitment_hash);
+            self.accumulated_fees = self.accumulated_fees.saturating_add(near_token(fee));
+            // La comisión del relayer se paga solo si el retiro se completó; si su transferencia
+            // falla, queda guardada para `retry_failed_payout`
+            if let Some((relayer, amount)) = relayer_fee.filter(|(_, amount)| amount.0 > 0) {
+                Promise::new(relayer.clone())
+                    .transfer(near_token(amount))
+                    .then(
+                        Self::ext(env::current_account_id())
+                            .with_static_gas(WITHDRAW_RESOLVE_GAS)
+                            .payouts_resolve(vec![(relayer, amount)], U128(0)),
+                    );
+            }
+            return true;
+        }
+        
+        self.restore_note(&commitment_hash, &withdrawal_hash, &deposit);
         
-        // 9. Transferir comisión
-        if fee > 0 {
-            Promise::new(self.owner.clone()).transfer(NearToken::from_yoctonear(fee));
+        self.log(&format!("Withdrawal to {} failed, deposit restored", recipient));
+        events::withdraw_failed(&self.event_namespace, &withdrawal_hash, &recipient);
+        false
+    }
+    
+    /// Callback de `withdraw` cuando el monto se adjuntó a una llamada al método del destinatario. Su
+    /// resultado es el de toda la cadena que devuelva ese método, así que un fallo no prueba que los
+    /// fondos hayan vuelto: la nota queda gastada y el monto se guarda como pago fallido del
+    /// destinatario, que `retry_failed_payout` solo paga si el contrato es solvente. Devuelve si la
+    /// llamada tuvo éxito.
+    #[private]
+    pub fn recipient_call_resolve(&mut self, recipient: AccountId, commitment_hash: String, fee: U128, amount: U128) -> bool {
+        self.in_flight_fees = self.in_flight_fees.saturating_sub(near_token(fee));
+        self.remove_depositor(&commitment_hash);
+        self.accumulated_fees = self.accumulated_fees.saturating_add(near_token(fee));
+        if near_sdk::is_promise_success() {
+            return true;
+        }
+        self.record_failed_payout(recipient, near_token(amount));
+        false
+    }
+    
+    /// Retirar una nota repartiendo el monto neto entre varios destinatarios distintos.
+    /// Los montos de `payouts` deben sumar exactamente la denominación menos la comisión; la exención
+    /// de comisión no aplica y las notas vinculadas a un destinatario no se pueden repartir.
+    /// Requiere el mismo gas mínimo que `withdraw`.
+    pub fn withdraw_split(&mut self, secret: String, payouts: Vec<(AccountId, U128)>) -> Promise {
+        self.assert_not_migrating();
+        assert!(self.aml_contract.is_none(), "Split withdrawals are not available while AML screening is enabled");
+        assert!(env::prepaid_gas().saturating_sub(env::used_gas()) >= MIN_WITHDRAW_GAS,
+                "Not enough gas attached, withdraw requires at least 30 Tgas");
+        assert!(env::attached_deposit().is_zero(), "Withdrawals must not attach NEAR");
+        assert!(!payouts.is_empty() && payouts.len() <= MAX_SPLIT_PAYOUTS,
+                "Split withdrawal must have between 1 and 5 payouts");
+        for (i, (recipient, amount)) in payouts.iter().enumerate() {
+            assert_ne!(recipient, &env::current_account_id(), "The mixer contract cannot be the recipient");
+            assert!(self.is_destination_allowed(recipient), "Recipient is not an approved withdrawal destination");
+            assert!(amount.0 > 0, "Payout amounts must be positive");
+            assert!(payouts[..i].iter().all(|(other, _)| other != recipient),
+                    "Duplicate recipient in split withdrawal");
         }
         
-        // 10. Transferir fondos al destinatario
-        Promise::new(recipient.clone()).transfer(NearToken::from_yoctonear(withdrawal_amount));
+        let (commitment_hash, withdrawal_hash, deposit, fee) = self.spend_note(&secret, None, false);
+        // La nota no se restaura aunque falle un pago, así que su depositante ya no hace falta
+        self.remove_depositor(&commitment_hash);
+        let net_amount = deposit.denomination.as_yoctonear() - fee;
+        self.check_min_withdrawal(&deposit, net_amount).unwrap_or_else(|err| env::panic_str(&err));
+        let payouts_total: u128 = payouts.iter().map(|(_, amount)| amount.0).sum();
+        assert_eq!(payouts_total, net_amount, "Payouts must add up to the amount after fees");
+        self.reserve_in_flight_fees(fee);
         
-        env::log_str(&format!("Withdrawal of {} NEAR processed to {}", 
-            NearToken::from_yoctonear(withdrawal_amount).as_near(), recipient));
+        self.log(&format!("Split withdrawal of {} NEAR processed to {} recipients",
+            NearToken::from_yoctonear(net_amount).as_near(), payouts.len()));
+        let event_payouts: Vec<(AccountId, u128)> =
+            payouts.iter().map(|(recipient, amount)| (recipient.clone(), amount.0)).collect();
+        events::split_withdraw(&self.event_namespace, &withdrawal_hash, &event_payouts, fee);
+        
+        let transfers = payouts
+            .iter()
+            .map(|(recipient, amount)| Promise::new(recipient.clone()).transfer(near_token(*amount)))
+            .reduce(|all, transfer| all.and(transfer))
+            .unwrap();
+        transfers.then(
+            Self::ext(env::current_account_id())
+                .with_static_gas(WITHDRAW_RESOLVE_GAS)
+                .payouts_resolve(payouts, U128(fee)),
+        )
+    }
+    
+    /// Callback de `withdraw_split` y `retry_failed_payout`. La nota ya no se puede restaurar porque
+    /// parte del monto pudo haberse pagado, así que cada pago fallido queda guardado para reintentarlo
+    /// con `retry_failed_payout`. Devuelve si todos los pagos se completaron.
+    #[private]
+    pub fn payouts_resolve(&mut self, payouts: Vec<(AccountId, U128)>, fee: U128) -> bool {
+        self.in_flight_fees = self.in_flight_fees.saturating_sub(near_token(fee));
+        self.accumulated_fees = self.accumulated_fees.saturating_add(near_token(fee));
+        
+        let mut all_succeeded = true;
+        for (index, (recipient, amount)) in payouts.into_iter().enumerate() {
+            if matches!(env::promise_result(index as u64), near_sdk::PromiseResult::Successful(_)) {
+                continue;
+            }
+            all_succeeded = false;
+            self.record_failed_payout(recipient, near_token(amount));
+        }
+        all_succeeded
+    }
+    
+    /// Reintentar la transferencia de un pago fallido a su destinatario. Cualquiera puede llamarlo,
+    /// los fondos solo pueden ir a la cuenta original. Falla si el contrato no es solvente: un pago
+    /// fallido por el método de un destinatario puede no haber devuelto los fondos.
+    pub fn retry_failed_payout(&mut self, recipient: AccountId) -> Promise {
+        assert!(self.is_solvent(), "Failed payouts cannot be retried while the contract is insolvent");
+        let amount = self.failed_payouts.remove(&recipient).expect("No failed payout for this recipient");
+        self.failed_payouts_total = self.failed_payouts_total.saturating_sub(amount);
+        Promise::new(recipient.clone()).transfer(amount).then(
+            Self::ext(env::current_account_id())
+                .with_static_gas(WITHDRAW_RESOLVE_GAS)
+                .payouts_resolve(vec![(recipient, yocto(amount))], U128(0)),
+        )
+    }
+    
+    pub fn get_failed_payout(&self, recipient: AccountId) -> Option<U128> {
+        self.failed_payouts.get(&recipient).map(yocto)
+    }
+    
+    /// Transferir a la tesorería parte de las comisiones acumuladas (solo owner).
+    /// Nunca toca los fondos que respaldan depósitos activos.
+    pub fn withdraw_fees(&mut self, amount: U128) -> Promise {
+        self.assert_owner();
+        self.transfer_fees_to_treasury(near_token(amount))
+    }
+    
+    /// Transferir a la tesorería todas las comisiones acumuladas y dejar el contador en cero (solo owner)
+    pub fn sweep_all_fees(&mut self) -> Promise {
+        self.assert_owner();
+        self.transfer_fees_to_treasury(self.accumulated_fees)
+    }
+    
+    /// Cambiar la cuenta que recibe las comisiones (solo owner)
+    pub fn set_treasury(&mut self, treasury: AccountId) {
+        self.assert_owner();
+        events::config_change(&self.event_namespace, "treasury", json!(self.treasury), json!(treasury));
+        self.treasury = treasury;
+    }
+    
+    pub fn get_treasury(&self) -> AccountId {
+        self.treasury.clone()
+    }
+    
+    /// Definir la ganancia neta mínima para que `should_sweep_fees` recomiende retirar (solo owner)
+    pub fn set_fee_sweep_threshold(&mut self, threshold: U128) {
+        self.assert_owner();
+        events::config_change(&self.event_namespace, "fee_sweep_threshold", json!(yocto(self.fee_sweep_threshold)), json!(threshold));
+        self.fee_sweep_threshold = near_token(threshold);
+    }
+    
+    pub fn get_sweepable_fees(&self) -> U128 {
+        yocto(self.accumulated_fees)
     }
-}
\ No newline at end of file
+    
+    pub fn get_fee_sweep_threshold(&self) -> U128 {
+        yocto(self.fee_sweep_threshold)
+    }
+    
+    /// Definir a partir de qué monto de comisiones acumuladas `withdraw` las envía solo a la
+    /// tesorería, o `None` para desactivarlo (solo owner)
+    pub fn set_auto_sweep_threshold(&mut self, threshold: Option<U128>) {
+        self.assert_owner();
+        events::config_change(&self.event_namespace, "auto_sweep_threshold", json!(self.auto_sweep_threshold.map(yocto)), json!(threshold));
+        self.auto_sweep_threshold = threshold.map(near_token);
+    }
+    
+    pub fn get_auto_sweep_threshold(&self) -> Option<U128> {
+        self.auto_sweep_threshold.map(yocto)
+    }
+    
+    /// Indica si conviene retirar comisiones: lo acumulado debe superar el costo de gas estimado
+    /// y la ganancia neta debe alcanzar el umbral configurado. Es solo orientativo.
+    pub fn should_sweep_fees(&self, gas_cost_estimate: U128) -> bool {
+        let net = self.accumulated_fees.saturating_sub(near_token(gas_cost_estimate));
+        !net.is_zero() && net >= self.fee_sweep_threshold
+    }
+    
+    /// Monto en yoctoNEAR que el contrato debe a los depósitos activos
+    pub fn get_obligations(&self) -> U128 {
+        yocto(self.total_obligations())
+    }
+    
+    /// Saldo, obligaciones y comisiones acumuladas en el bloque actual, para que los usuarios
+    /// registren que en ese momento las reservas cubrían lo adeudado sin depender de un panel
+    pub fn get_reserves_attestation(&self) -> ReservesAttestation {
+        ReservesAttestation {
+            account_balance: yocto(env::account_balance()),
+            total_obligations: yocto(self.total_obligations()),
+            accumulated_fees: yocto(self.accumulated_fees),
+            block_height: env::block_height(),
+            block_timestamp: env::block_timestamp(),
+        }
+    }
+    
+    /// Obligaciones de cada denominación fija (notas activas por denominación), en orden ascendente,
+    /// para conciliar el saldo pool por pool. No incluye el pool flexible ni los pagos fallidos, que
+    /// sí suman en `get_obligations`.
+    pub fn get_obligations_by_denomination(&self) -> Vec<(NearToken, U128)> {
+        DENOMINATIONS
+            .iter()
+            .map(|&denomination| {
+                let count = self.deposit_counts.get(&denomination).unwrap_or(0);
+                (denomination, U128(denomination.as_yoctonear() * u128::from(count)))
+            })
+            .collect()
+    }
+    
+    /// Saldo que no respalda nada: NEAR enviado directamente a la cuenta del contrato fuera de
+    /// `deposit`. Es el saldo menos las obligaciones, las comisiones acumuladas, el fondo de subsidio
+    /// de relayers, el de decomisos, la reserva, la parte disponible de los saldos de almacenamiento
+    /// de las cuentas registradas y el costo del almacenamiento que ocupa el contrato.
+    pub fn get_surplus(&self) -> U128 {
+        yocto(self.surplus())
+    }
+    
+    /// Parte del saldo bloqueada por el staking del almacenamiento del contrato, que no se puede
+    /// gastar aunque figure en `account_balance`
+    pub fn get_storage_staked(&self) -> U128 {
+        yocto(self.storage_staked())
+    }
+    
+    /// Si el saldo cubre todo lo que el contrato debe conservar, incluido el staking de almacenamiento
+    pub fn is_solvent(&self) -> bool {
+        env::account_balance() >= self.reserved_balance()
+    }
+    
+    /// Transferir el excedente a `to` (solo owner). Nunca toca fondos de depósitos, comisiones ni
+    /// saldos de almacenamiento; falla si no hay excedente.
+    pub fn rescue_surplus(&mut self, to: AccountId) -> Promise {
+        self.assert_owner();
+        let surplus = self.surplus();
+        assert!(!surplus.is_zero(), "No surplus to rescue");
+        self.log(&format!("Rescued {} yoctoNEAR of surplus to {}", surplus.as_yoctonear(), to));
+        Promise::new(to).transfer(surplus)
+    }
+    
+    /// Sumar el depósito adjunto a la reserva del contrato. Queda fuera de las comisiones, de las
+    /// obligaciones y del excedente, y solo el owner la retira con `withdraw_reserve`.
+    #[payable]
+    pub fn add_reserve(&mut self) {
+        let amount = env::attached_deposit();
+        assert!(!amount.is_zero(), "Reserve amount must be positive");
+        self.reserve_buffer = self.reserve_buffer.saturating_add(amount);
+        self.log(&format!("Added {} yoctoNEAR to the reserve", amount.as_yoctonear()));
+    }
+    
+    /// Transferir `amount` de la reserva a `to` (solo owner)
+    pub fn withdraw_reserve(&mut self, amount: U128, to: AccountId) -> Promise {
+        self.assert_owner();
+        let amount = near_token(amount);
+        assert!(!amount.is_zero(), "Reserve amount must be positive");
+        assert!(amount <= self.reserve_buffer, "Amount exceeds the reserve buffer");
+        self.reserve_buffer = self.reserve_buffer.saturating_sub(amount);
+        self.log(&format!("Withdrew {} yoctoNEAR of reserve to {}", amount.as_yoctonear(), to));
+        Promise::new(to).transfer(amount)
+    }
+    
+    pub fn get_reserve_buffer(&self) -> U128 {
+        yocto(self.reserve_buffer)
+    }
+    
+    /// Programar un cambio de comisión (solo owner). Nunca puede superar el tope configurado y
+    /// solo entra en vigor tras `FEE_CHANGE_DELAY`, para que el owner no pueda subir la comisión
+    /// justo antes de un retiro. Un nuevo cambio reemplaza al que estuviera pendiente.
+    pub fn set_fee_basis_points(&mut self, fee_basis_points: u16) {
+        self.assert_owner();
+        assert!(fee_basis_points <= self.max_fee_basis_points, "Fee cannot exceed the fee cap");
+        self.apply_pending_fee();
+        let effective_at = env::block_timestamp() + FEE_CHANGE_DELAY * 1_000_000_000;
+        events::config_change(&self.event_namespace, "fee_basis_points", json!(self.fee_basis_points), json!(fee_basis_points));
+        // Un cambio pendiente que se reemplaza nunca llegó a aplicarse, así que sale del historial
+        if self.pending_fee.is_some() {
+            self.fee_history.pop();
+        }
+        self.fee_history.push((effective_at, fee_basis_points));
+        if self.fee_history.len() > MAX_FEE_HISTORY {
+            self.fee_history.remove(0);
+        }
+        self.pending_fee = Some((fee_basis_points, effective_at));
+        self.log(&format!("Fee change to {} basis points scheduled for {}", fee_basis_points, effective_at));
+    }
+    
+    /// Cambios de la comisión global, del más viejo al más nuevo, como (timestamp desde el que
+    /// aplica, comisión). Incluye la comisión inicial y un cambio programado aún no vigente; guarda
+    /// como máximo `MAX_FEE_HISTORY` entradas.
+    pub fn get_fee_history(&self) -> Vec<(Timestamp, u16)> {
+        self.fee_history.clone()
+    }
+    
+    /// Cambio de comisión aún no vigente: (nueva comisión, timestamp desde el que aplica)
+    pub fn get_pending_fee(&self) -> Option<(u16, Timestamp)> {
+        self.pending_fee.filter(|(_, effective_at)| env::block_timestamp() < *effective_at)
+    }
+    
+    /// Definir cuándo pagan la comisión los nuevos depósitos en pools fijos (solo owner). Cada nota
+    /// guarda el modo con el que se depositó, así que el cambio no afecta a las notas existentes.
+    pub fn set_fee_mode(&mut self, fee_mode: FeeMode) {
+        self.assert_owner();
+        events::config_change(&self.event_namespace, "fee_mode", json!(self.fee_mode), json!(fee_mode));
+        self.fee_mode = fee_mode;
+    }
+    
+    pub fn get_fee_mode(&self) -> FeeMode {
+        self.fee_mode
+    }
+    
+    /// Cambiar cómo se redondean las comisiones que no dan un número entero de yoctoNEAR (solo owner).
+    /// Aplica a la comisión de retiro, a la de depósito y a la penalización del retiro anticipado.
+    pub fn set_fee_rounding(&mut self, fee_rounding: FeeRounding) {
+        self.assert_owner();
+        events::config_change(&self.event_namespace, "fee_rounding", json!(self.fee_rounding), json!(fee_rounding));
+        self.fee_rounding = fee_rounding;
+    }
+    
+    /// Configuración vigente en una sola llamada
+    pub fn get_config(&self) -> ContractConfig {
+        ContractConfig {
+            owner: self.owner.clone(),
+            treasury: self.treasury.clone(),
+            fee_basis_points: self.effective_fee_basis_points(),
+            max_fee_basis_points: self.max_fee_basis_points,
+            fee_mode: self.fee_mode,
+            fee_rounding: self.fee_rounding,
+            max_note_value: self.max_note_value.map(yocto),
+            min_delay_seconds: MIN_DELAY,
+            deposits_paused: self.deposits_paused,
+            emergency_exit: self.is_emergency_exit_active(),
+            event_namespace: self.event_namespace.clone(),
+        }
+    }
+    
+    /// Estado operativo en una sola llamada: solvencia, pausas, notas activas, ocupación del árbol y
+    /// comisiones acumuladas. Solo lee contadores, así que su costo no depende de cuántas notas haya.
+    pub fn get_health(&self) -> HealthStatus {
+        let fill = u128::from(self.tree.next_index()) * 10000 / u128::from(self.tree.capacity());
+        HealthStatus {
+            is_solvent: self.is_solvent(),
+            pause_state: self.get_pause_state(),
+            migration_in_progress: self.migration_in_progress,
+            insolvency_halt: self.insolvency_halt,
+            total_deposits: self.get_total_deposits(),
+            tree_fill_basis_points: fill as u16,
+            accumulated_fees: yocto(self.accumulated_fees),
+        }
+    }
+    
+    /// Ajustar el tope de comisión (solo owner), acotado por el límite absoluto.
+    /// No se puede bajar por debajo de la comisión vigente.
+    pub fn set_max_fee_basis_points(&mut self, max_fee_basis_points: u16) {
+        self.assert_owner();
+        assert!(max_fee_basis_points <= HARD_MAX_FEE_BASIS_POINTS, "Fee cap cannot exceed 20%");
+        self.apply_pending_fee();
+        let scheduled_fee = self.pending_fee.map_or(0, |(fee, _)| fee);
+        let highest_denomination_fee = DENOMINATIONS
+            .iter()
+            .filter_map(|denom| self.denomination_fees.get(denom))
+            .map(|fee| fee.highest())
+            .max()
+            .unwrap_or(0);
+        assert!(max_fee_basis_points >= self.fee_basis_points.max(scheduled_fee).max(highest_denomination_fee),
+            "Fee cap cannot be below the current fee");
+        events::config_change(&self.event_namespace, "max_fee_basis_points", json!(self.max_fee_basis_points), json!(max_fee_basis_points));
+        self.max_fee_basis_points = max_fee_basis_points;
+    }
+    
+    /// Ajustar el tope de la comisión de relayer (solo owner), en puntos básicos de la denominación.
+    /// Protege al destinatario de un relayer que se quede con casi toda la nota.
+    pub fn set_max_relayer_fee_basis_points(&mut self, max_relayer_fee_basis_points: u16) {
+        self.assert_owner();
+        assert!(max_relayer_fee_basis_points <= 10000, "Relayer fee cap cannot exceed 100%");
+        events::config_change(&self.event_namespace, "max_relayer_fee_basis_points", json!(self.max_relayer_fee_basis_points),
+            json!(max_relayer_fee_basis_points));
+        self.max_relayer_fee_basis_points = max_relayer_fee_basis_points;
+    }
+    
+    pub fn get_max_relayer_fee_basis_points(&self) -> u16 {
+        self.max_relayer_fee_basis_points
+    }
+    
+    /// Cotización para un relayer que evalúa un retiro de `denomination`: (comisión de relayer máxima
+    /// que `batch_withdraw` acepta, si supera `estimated_gas_cost`). La máxima es el tope de comisión
+    /// de relayer, sin pasar de lo que queda tras la comisión de protocolo que `batch_withdraw`
+    /// cobraría ahora a una nota con la espera cumplida.
+    pub fn get_relay_quote(&self, denomination: NearToken, estimated_gas_cost: U128) -> (U128, bool) {
+        assert!(self.matches_denomination(denomination).is_some(), "Not an accepted denomination");
+        let fee = self.quote_fee(denomination, FeeMode::AtWithdraw, self.matured_timestamp(), None);
+        let after_fee = denomination.as_yoctonear() - fee;
+        let max_relayer_fee = self.max_relayer_fee(denomination).min(after_fee);
+        (U128(max_relayer_fee), max_relayer_fee > estimated_gas_cost.0)
+    }
+    
+    /// Programar una comisión específica para una denominación fija, o quitarla con `None` (solo owner).
+    /// Igual que `set_fee_basis_points`, entra en vigor tras `FEE_CHANGE_DELAY`.
+    pub fn set_denomination_fee(&mut self, denomination: NearToken, fee_basis_points: Option<u16>) {
+        self.assert_owner();
+        assert!(self.matches_denomination(denomination).is_some(), "Not an accepted denomination");
+        if let Some(fee) = fee_basis_points {
+            assert!(fee <= self.max_fee_basis_points, "Fee cannot exceed the fee cap");
+        }
+        let mut denomination_fee = self.denomination_fees.get(&denomination).unwrap_or_default();
+        denomination_fee.fee_basis_points = denomination_fee.effective();
+        let effective_at = env::block_timestamp() + FEE_CHANGE_DELAY * 1_000_000_000;
+        events::config_change(&self.event_namespace, &format!("denomination_fee:{}", denomination.as_yoctonear()),
+            json!(denomination_fee.fee_basis_points), json!(fee_basis_points));
+        denomination_fee.pending = Some((fee_basis_points, effective_at));
+        self.denomination_fees.insert(&denomination, &denomination_fee);
+    }
+    
+    /// Comisión específica vigente de una denominación, si tiene
+    pub fn get_denomination_fee(&self, denomination: NearToken) -> Option<u16> {
+        self.denomination_fees.get(&denomination).and_then(|fee| fee.effective())
+    }
+    
+    /// Comisión exacta que `withdraw` cobraría ahora por una nota de `denomination` retirada a
+    /// `recipient`, calculada con la misma función que el retiro: comisión específica de la
+    /// denominación, cambios programados ya vigentes, exenciones, modo de comisión de la nota,
+    /// comisión de pool escaso, penalización por retiro anticipado y salida de emergencia.
+    /// `fee_mode` y `deposited_at` son los de la nota; sin ellos se cotiza una nota con comisión al
+    /// retirar que ya cumplió la espera. Es la cotización que deben mostrar los clientes.
+    pub fn get_effective_fee(
+        &self,
+        denomination: NearToken,
+        recipient: AccountId,
+        fee_mode: Option<FeeMode>,
+        deposited_at: Option<Timestamp>,
+    ) -> U128 {
+        let fee_mode = fee_mode.unwrap_or(FeeMode::AtWithdraw);
+        let deposited_at = deposited_at.unwrap_or_else(|| self.matured_timestamp());
+        U128(self.quote_fee(denomination, fee_mode, deposited_at, Some(&recipient)))
+    }
+    
+    /// Menor denominación que `deposit` acepta ahora cuyo neto tras la comisión de retiro vigente es
+    /// al menos `desired_net`, para quien necesita recibir un monto exacto; `None` si ninguna alcanza.
+    /// Cotiza una nota del modo de comisión actual retirada tras la espera, así que en modo de
+    /// comisión al depositar solo descuenta la comisión de pool escaso. No contempla exenciones.
+    pub fn required_denomination_for_net(&self, desired_net: NearToken) -> Option<NearToken> {
+        DENOMINATIONS.iter().copied().find(|&denomination| {
+            let fee = self.quote_fee(denomination, self.fee_mode, self.matured_timestamp(), None);
+            self.accepts_denomination(denomination) && denomination.as_yoctonear() - fee >= desired_net.as_yoctonear()
+        })
+    }
+    
+    /// Marcar un destinatario como exento de comisión (solo owner)
+    pub fn add_fee_exempt_recipient(&mut self, recipient: AccountId) {
+        self.assert_owner();
+        let was_exempt = !self.fee_exempt_recipients.insert(&recipient);
+        events::config_change(&self.event_namespace, &format!("fee_exempt:{}", recipient), json!(was_exempt), json!(true));
+    }
+    
+    /// Quitar la exención de comisión de un destinatario (solo owner)
+    pub fn remove_fee_exempt_recipient(&mut self, recipient: AccountId) {
+        self.assert_owner();
+        let was_exempt = self.fee_exempt_recipients.remove(&recipient);
+        events::config_change(&self.event_namespace, &format!("fee_exempt:{}", recipient), json!(was_exempt), json!(false));
+    }
+    
+    pub fn is_fee_exempt(&self, recipient: AccountId) -> bool {
+        self.fee_exempt_recipients.contains(&recipient)
+    }
+    
+    /// Activar o desactivar la lista de depositantes autorizados (solo owner). Desactivada, cualquier
+    /// cuenta puede depositar; los retiros siguen siendo anónimos en ambos casos.
+    pub fn set_deposit_allowlist_enabled(&mut self, enabled: bool) {
+        self.assert_owner();
+        events::config_change(&self.event_namespace, "deposit_allowlist_enabled", json!(self.deposit_allowlist_enabled), json!(enabled));
+        self.deposit_allowlist_enabled = enabled;
+    }
+    
+    pub fn get_deposit_allowlist_enabled(&self) -> bool {
+        self.deposit_allowlist_enabled
+    }
+    
+    /// Autorizar a una cuenta a depositar mientras la lista está activa (solo owner)
+    pub fn add_allowed_depositor(&mut self, account_id: AccountId) {
+        self.assert_owner();
+        let was_allowed = !self.allowed_depositors.insert(&account_id);
+        events::config_change(&self.event_namespace, &format!("allowed_depositor:{}", account_id), json!(was_allowed), json!(true));
+    }
+    
+    /// Quitar la autorización de depósito de una cuenta (solo owner)
+    pub fn remove_allowed_depositor(&mut self, account_id: AccountId) {
+        self.assert_owner();
+        let was_allowed = self.allowed_depositors.remove(&account_id);
+        events::config_change(&self.event_namespace, &format!("allowed_depositor:{}", account_id), json!(was_allowed), json!(false));
+    }
+    
+    pub fn is_allowed_depositor(&self, account_id: AccountId) -> bool {
+        self.allowed_depositors.contains(&account_id)
+    }
+    
+    /// Definir el recargo que cada depósito adjunta además de la denominación (y de su comisión en
+    /// modo al depositar) para el fondo de subsidio de relayers (solo owner). 0 lo desactiva.
+    pub fn set_deposit_surcharge(&mut self, surcharge: U128) {
+        self.assert_owner();
+        events::config_change(&self.event_namespace, "deposit_surcharge", json!(yocto(self.deposit_surcharge)), json!(surcharge));
+        self.deposit_surcharge = near_token(surcharge);
+    }
+    
+    pub fn get_deposit_surcharge(&self) -> U128 {
+        yocto(self.deposit_surcharge)
+    }
+    
+    pub fn get_relayer_subsidy_pool(&self) -> U128 {
+        yocto(self.relayer_subsidy_pool)
+    }
+    
+    /// Autorizar a un relayer a reclamar reembolsos del fondo de subsidio (solo owner)
+    pub fn add_approved_relayer(&mut self, relayer: AccountId) {
+        self.assert_owner();
+        let was_approved = !self.approved_relayers.insert(&relayer);
+        events::config_change(&self.event_namespace, &format!("approved_relayer:{}", relayer), json!(was_approved), json!(true));
+    }
+    
+    /// Quitar la autorización de un relayer (solo owner)
+    pub fn remove_approved_relayer(&mut self, relayer: AccountId) {
+        self.assert_owner();
+        let was_approved = self.approved_relayers.remove(&relayer);
+        events::config_change(&self.event_namespace, &format!("approved_relayer:{}", relayer), json!(was_approved), json!(false));
+    }
+    
+    pub fn is_approved_relayer(&self, relayer: AccountId) -> bool {
+        self.approved_relayers.contains(&relayer)
+    }
+    
+    /// Activar o desactivar la lista global de relayers para `batch_withdraw` (solo owner). Activa,
+    /// solo los relayers aprobados envían retiros de las denominaciones sin lista propia.
+    pub fn set_relayer_allowlist_enabled(&mut self, enabled: bool) {
+        self.assert_owner();
+        events::config_change(&self.event_namespace, "relayer_allowlist_enabled", json!(self.relayer_allowlist_enabled), json!(enabled));
+        self.relayer_allowlist_enabled = enabled;
+    }
+    
+    pub fn get_relayer_allowlist_enabled(&self) -> bool {
+        self.relayer_allowlist_enabled
+    }
+    
+    /// Autorizar a un relayer a enviar retiros de una denominación fija (solo owner). Con al menos
+    /// un relayer en su lista, la denominación solo acepta los de esa lista, p. ej. relayers con
+    /// garantía para los pools de más valor.
+    pub fn add_denomination_relayer(&mut self, denomination: NearToken, relayer: AccountId) {
+        self.assert_owner();
+        assert!(self.matches_denomination(denomination).is_some(), "Not an accepted denomination");
+        let mut relayers = self.denomination_relayers.get(&denomination).unwrap_or_else(|| {
+            UnorderedSet::new([b"b".as_slice(), &denomination.as_yoctonear().to_le_bytes()].concat())
+        });
+        let was_allowed = !relayers.insert(&relayer);
+        self.denomination_relayers.insert(&denomination, &relayers);
+        events::config_change(&self.event_namespace,
+            &format!("denomination_relayer:{}:{}", denomination.as_yoctonear(), relayer), json!(was_allowed), json!(true));
+    }
+    
+    /// Quitar un relayer de la lista de una denominación (solo owner). Sin relayers, la denominación
+    /// vuelve a la lista global.
+    pub fn remove_denomination_relayer(&mut self, denomination: NearToken, relayer: AccountId) {
+        self.assert_owner();
+        let was_allowed = match self.denomination_relayers.get(&denomination) {
+            Some(mut relayers) => {
+                let was_allowed = relayers.remove(&relayer);
+                if relayers.is_empty() {
+                    self.denomination_relayers.remove(&denomination);
+                } else {
+                    self.denomination_relayers.insert(&denomination, &relayers);
+                }
+                was_allowed
+            }
+            None => false,
+        };
+        events::config_change(&self.event_namespace,
+            &format!("denomination_relayer:{}:{}", denomination.as_yoctonear(), relayer), json!(was_allowed), json!(false));
+    }
+    
+    /// Relayers de la lista propia de una denominación, paginados desde `from_index` con a lo sumo
+    /// `MAX_PAGE_LIMIT` por llamada; vacía si usa la global
+    pub fn get_denomination_relayers(&self, denomination: NearToken, from_index: u64, limit: u64) -> Vec<AccountId> {
+        self.denomination_relayers.get(&denomination).map_or_else(Vec::new, |relayers| {
+            relayers.iter().skip(from_index as usize).take(limit.min(MAX_PAGE_LIMIT) as usize).collect()
+        })
+    }
+    
+    /// Si `relayer` puede enviar retiros de `denomination` en `batch_withdraw`: según la lista de
+    /// la denominación si tiene, si no según la global cuando está activa
+    pub fn is_relayer_allowed(&self, denomination: NearToken, relayer: AccountId) -> bool {
+        match self.denomination_relayers.get(&denomination) {
+            Some(relayers) => relayers.contains(&relayer),
+            None => !self.relayer_allowlist_enabled || self.approved_relayers.contains(&relayer),
+        }
+    }
+    
+    /// Reclamar un reembolso de gas del fondo de subsidio; solo relayers aprobados. Si la
+    /// transferencia falla, el monto queda guardado para `retry_failed_payout`.
+    pub fn claim_relayer_subsidy(&mut self, amount: U128) -> Promise {
+        let relayer = env::predecessor_account_id();
+        assert!(self.approved_relayers.contains(&relayer), "Only approved relayers can claim subsidies");
+        let amount = near_token(amount);
+        assert!(!amount.is_zero(), "Claim amount must be positive");
+        assert!(amount <= self.relayer_subsidy_pool, "Claim exceeds the relayer subsidy pool");
+        self.relayer_subsidy_pool = self.relayer_subsidy_pool.saturating_sub(amount);
+        
+        self.log(&format!("Relayer {} claimed {} yoctoNEAR of subsidy", relayer, amount.as_yoctonear()));
+        Promise::new(relayer.clone()).transfer(amount).then(
+            Self::ext(env::current_account_id())
+                .with_static_gas(WITHDRAW_RESOLVE_GAS)
+                .payouts_resolve(vec![(relayer, yocto(amount))], U128(0)),
+        )
+    }
+    
+    /// Activar o desactivar los mensajes de log legibles (solo owner). Desactivados ahorran gas en
+    /// producción; los eventos NEP-297 se emiten siempre.
+    pub fn set_verbose_logging(&mut self, enabled: bool) {
+        self.assert_owner();
+        events::config_change(&self.event_namespace, "verbose_logging", json!(self.verbose_logging), json!(enabled));
+        self.verbose_logging = enabled;
+    }
+    
+    pub fn get_verbose_logging(&self) -> bool {
+        self.verbose_logging
+    }
+    
+    /// Habilitar o deshabilitar el pool flexible de montos arbitrarios (solo owner).
+    /// Deshabilitarlo solo bloquea depósitos nuevos; las notas existentes se pueden retirar.
+    pub fn set_flexible_pool_enabled(&mut self, enabled: bool) {
+        self.assert_owner();
+        events::config_change(&self.event_namespace, "flexible_pool_enabled", json!(self.flexible_pool_enabled), json!(enabled));
+        self.flexible_pool_enabled = enabled;
+    }
+    
+    /// Definir el monto mínimo aceptado por el pool flexible (solo owner)
+    pub fn set_flexible_pool_min(&mut self, min: U128) {
+        self.assert_owner();
+        assert!(min.0 > 0, "Flexible pool minimum must be positive");
+        events::config_change(&self.event_namespace, "flexible_pool_min", json!(yocto(self.flexible_pool_min)), json!(min));
+        self.flexible_pool_min = near_token(min);
+    }
+    
+    /// Estado del pool flexible: (habilitado, mínimo, depósitos activos, monto total en yoctoNEAR)
+    pub fn get_flexible_pool_info(&self) -> (bool, U128, u64, U128) {
+        (
+            self.flexible_pool_enabled,
+            yocto(self.flexible_pool_min),
+            self.flexible_deposit_count,
+            yocto(self.flexible_pool_total),
+        )
+    }
+    
+    /// Marcar o desmarcar una denominación fija como obsoleta (solo owner). Una denominación obsoleta
+    /// no acepta depósitos nuevos; sus notas se pueden retirar o migrar con `migrate_note`. Al volver
+    /// a habilitarla, acepta depósitos tras la espera de activación vigente.
+    pub fn set_denomination_deprecated(&mut self, denomination: NearToken, deprecated: bool) {
+        self.assert_owner();
+        assert!(self.matches_denomination(denomination).is_some(), "Not an accepted denomination");
+        let was_deprecated = if deprecated {
+            !self.deprecated_denominations.insert(&denomination)
+        } else {
+            self.deprecated_denominations.remove(&denomination)
+        };
+        if was_deprecated && !deprecated && self.denomination_activation_delay > 0 {
+            let active_at = env::block_timestamp() + self.denomination_activation_delay * 1_000_000_000;
+            self.denomination_active_at.insert(&denomination, &active_at);
+        }
+        events::config_change(&self.event_namespace, &format!("denomination_deprecated:{}", denomination.as_yoctonear()),
+            json!(was_deprecated), json!(deprecated));
+    }
+    
+    /// Definir la espera en segundos entre que una denominación obsoleta vuelve a habilitarse y que
+    /// acepta depósitos (solo owner). Solo afecta a las reactivaciones posteriores. 0 la desactiva.
+    pub fn set_denomination_activation_delay(&mut self, delay_seconds: u64) {
+        self.assert_owner();
+        events::config_change(&self.event_namespace, "denomination_activation_delay",
+            json!(self.denomination_activation_delay), json!(delay_seconds));
+        self.denomination_activation_delay = delay_seconds;
+    }
+    
+    pub fn get_denomination_activation_delay(&self) -> u64 {
+        self.denomination_activation_delay
+    }
+    
+    /// Si `amount` es exactamente una de las denominaciones fijas; un yoctoNEAR de diferencia ya no lo es
+    pub fn is_fixed_denomination(&self, amount: U128) -> bool {
+        self.matches_denomination(near_token(amount)).is_some()
+    }
+    
+    /// Definir la etiqueta con la que los frontends muestran una denominación fija, o `None` para
+    /// volver a la etiqueta numérica (solo owner)
+    pub fn set_denomination_label(&mut self, denomination: NearToken, label: Option<String>) {
+        self.assert_owner();
+        assert!(self.matches_denomination(denomination).is_some(), "Not an accepted denomination");
+        assert!(label.as_ref().is_none_or(|label| !label.is_empty()), "Denomination label cannot be empty");
+        events::config_change(&self.event_namespace, &format!("denomination_label:{}", denomination.as_yoctonear()),
+            json!(self.denomination_labels.get(&denomination)), json!(label));
+        match label {
+            Some(label) => self.denomination_labels.insert(&denomination, &label),
+            None => self.denomination_labels.remove(&denomination),
+        };
+    }
+    
+    /// Etiqueta para mostrar de una denominación; sin etiqueta definida, su valor en NEAR
+    pub fn get_denomination_label(&self, denomination: NearToken) -> String {
+        self.denomination_labels
+            .get(&denomination)
+            .unwrap_or_else(|| format!("{} NEAR", denomination.as_near()))
+    }
+    
+    pub fn is_denomination_deprecated(&self, denomination: NearToken) -> bool {
+        self.deprecated_denominations.contains(&denomination)
+    }
+    
+    /// Congelar o descongelar una denominación fija (solo owner), p. ej. si un pool queda comprometido,
+    /// sin pausar las demás. Un pool congelado no acepta depósitos; sus notas se pueden retirar salvo
+    /// que `freeze_blocks_withdrawals` esté activo.
+    pub fn set_denomination_frozen(&mut self, denomination: NearToken, frozen: bool) {
+        self.assert_owner();
+        assert!(self.matches_denomination(denomination).is_some(), "Not an accepted denomination");
+        let was_frozen = if frozen {
+            !self.frozen_denominations.insert(&denomination)
+        } else {
+            self.frozen_denominations.remove(&denomination)
+        };
+        events::config_change(&self.event_namespace, &format!("denomination_frozen:{}", denomination.as_yoctonear()),
+            json!(was_frozen), json!(frozen));
+    }
+    
+    /// Definir si congelar una denominación también bloquea los retiros de sus notas (solo owner)
+    pub fn set_freeze_blocks_withdrawals(&mut self, blocks_withdrawals: bool) {
+        self.assert_owner();
+        events::config_change(&self.event_namespace, "freeze_blocks_withdrawals", json!(self.freeze_blocks_withdrawals), json!(blocks_withdrawals));
+        self.freeze_blocks_withdrawals = blocks_withdrawals;
+    }
+    
+    pub fn get_freeze_blocks_withdrawals(&self) -> bool {
+        self.freeze_blocks_withdrawals
+    }
+    
+    /// Denominaciones congeladas, en orden ascendente
+    pub fn get_frozen_denominations(&self) -> Vec<NearToken> {
+        DENOMINATIONS
+            .iter()
+            .filter(|denomination| self.frozen_denominations.contains(denomination))
+            .copied()
+            .collect()
+    }
+    
+    /// Definir el piso de anonimato (solo owner): un pool fijo con menos notas que `min_pool_size`
+    /// no permite retiros, salvo al depositante original de cada nota. 0 lo desactiva.
+    pub fn set_min_pool_size_for_withdrawal(&mut self, min_pool_size: u64) {
+        self.assert_owner();
+        events::config_change(&self.event_namespace, "min_pool_size_for_withdrawal", json!(self.min_pool_size_for_withdrawal), json!(min_pool_size));
+        self.min_pool_size_for_withdrawal = min_pool_size;
+    }
+    
+    pub fn get_min_pool_size_for_withdrawal(&self) -> u64 {
+        self.min_pool_size_for_withdrawal
+    }
+    
+    /// Definir el valor máximo de una nota, o `None` para quitar el tope (solo owner).
+    /// Limita lo que se pierde si se filtra un secreto; no afecta a los depósitos existentes.
+    pub fn set_max_note_value(&mut self, max_note_value: Option<U128>) {
+        self.assert_owner();
+        assert!(max_note_value.is_none_or(|max| max.0 > 0), "Maximum note value must be positive");
+        events::config_change(&self.event_namespace, "max_note_value", json!(self.max_note_value.map(yocto)), json!(max_note_value));
+        self.max_note_value = max_note_value.map(near_token);
+    }
+    
+    pub fn get_max_note_value(&self) -> Option<U128> {
+        self.max_note_value.map(yocto)
+    }
+    
+    /// Definir el tope global de valor bloqueado en notas activas, o `None` para quitarlo (solo owner).
+    /// Un depósito que lo superaría se rechaza; bajarlo no afecta a las notas existentes.
+    pub fn set_max_tvl_global(&mut self, max_tvl: Option<U128>) {
+        self.assert_owner();
+        events::config_change(&self.event_namespace, "max_tvl_global", json!(self.max_tvl_global.map(yocto)), json!(max_tvl));
+        self.max_tvl_global = max_tvl.map(near_token);
+    }
+    
+    pub fn get_max_tvl_global(&self) -> Option<U128> {
+        self.max_tvl_global.map(yocto)
+    }
+    
+    /// Definir el tope de valor bloqueado de una denominación fija, o `None` para quitarlo (solo owner)
+    pub fn set_max_tvl_for_denomination(&mut self, denomination: NearToken, max_tvl: Option<U128>) {
+        self.assert_owner();
+        assert!(self.matches_denomination(denomination).is_some(), "Not an accepted denomination");
+        events::config_change(&self.event_namespace, &format!("max_tvl:{}", denomination.as_yoctonear()),
+            json!(self.max_tvl_per_denomination.get(&denomination).map(yocto)), json!(max_tvl));
+        match max_tvl {
+            Some(max_tvl) => self.max_tvl_per_denomination.insert(&denomination, &near_token(max_tvl)),
+            None => self.max_tvl_per_denomination.remove(&denomination),
+        };
+    }
+    
+    pub fn get_max_tvl_for_denomination(&self, denomination: NearToken) -> Option<U128> {
+        self.max_tvl_per_denomination.get(&denomination).map(yocto)
+    }
+    
+    /// Cuánto valor más se puede depositar antes de alcanzar un tope: sin denominación, el global;
+    /// con una denominación fija, el menor entre el global y el suyo. `None` si no hay tope.
+    pub fn get_tvl_headroom(&self, denomination: Option<NearToken>) -> Option<U128> {
+        let global = self.max_tvl_global
+            .map(|max| max.saturating_sub(self.total_value_locked()));
+        let per_denomination = denomination.and_then(|denomination| {
+            let max = self.max_tvl_per_denomination.get(&denomination)?;
+            Some(max.saturating_sub(self.denomination_value_locked(denomination)))
+        });
+        match (global, per_denomination) {
+            (Some(global), Some(per_denomination)) => Some(global.min(per_denomination)),
+            (headroom, None) | (None, headroom) => headroom,
+        }
+        .map(yocto)
+    }
+    
+    /// Definir cuántas notas nuevas se insertan como máximo en el árbol por bloque (solo owner); las
+    /// siguientes fallan con un error claro para reintentar en el bloque siguiente. 0 lo desactiva.
+    pub fn set_max_inserts_per_block(&mut self, max_inserts: u32) {
+        self.assert_owner();
+        events::config_change(&self.event_namespace, "max_inserts_per_block", json!(self.max_inserts_per_block), json!(max_inserts));
+        self.max_inserts_per_block = max_inserts;
+    }
+    
+    pub fn get_max_inserts_per_block(&self) -> u32 {
+        self.max_inserts_per_block
+    }
+    
+    /// Definir el monto neto mínimo de un retiro del pool flexible (solo owner)
+    pub fn set_min_withdrawal_amount(&mut self, min_withdrawal_amount: U128) {
+        self.assert_owner();
+        events::config_change(&self.event_namespace, "min_withdrawal_amount", json!(yocto(self.min_withdrawal_amount)), json!(min_withdrawal_amount));
+        self.min_withdrawal_amount = near_token(min_withdrawal_amount);
+    }
+    
+    pub fn get_min_withdrawal_amount(&self) -> U128 {
+        yocto(self.min_withdrawal_amount)
+    }
+    
+    /// Definir la penalización del retiro anticipado (solo owner), acotada por el límite absoluto de
+    /// comisión; 0 exige siempre esperar `MIN_DELAY`
+    pub fn set_early_withdrawal_penalty_basis_points(&mut self, penalty_basis_points: u16) {
+        self.assert_owner();
+        assert!(penalty_basis_points <= HARD_MAX_FEE_BASIS_POINTS, "Early withdrawal penalty cannot exceed 20%");
+        events::config_change(&self.event_namespace, "early_withdrawal_penalty_basis_points",
+            json!(self.early_withdrawal_penalty_basis_points), json!(penalty_basis_points));
+        self.early_withdrawal_penalty_basis_points = penalty_basis_points;
+    }
+    
+    pub fn get_early_withdrawal_penalty_basis_points(&self) -> u16 {
+        self.early_withdrawal_penalty_basis_points
+    }
+    
+    /// Aprobar una cuenta como destino de retiros (solo owner)
+    pub fn add_withdrawal_destination(&mut self, account_id: AccountId) {
+        self.assert_owner();
+        let was_approved = !self.withdrawal_allowlist.insert(&account_id);
+        events::config_change(&self.event_namespace, &format!("withdrawal_destination:{}", account_id), json!(was_approved), json!(true));
+    }
+    
+    /// Quitar una cuenta de los destinos de retiro aprobados (solo owner)
+    pub fn remove_withdrawal_destination(&mut self, account_id: AccountId) {
+        self.assert_owner();
+        let was_approved = self.withdrawal_allowlist.remove(&account_id);
+        events::config_change(&self.event_namespace, &format!("withdrawal_destination:{}", account_id), json!(was_approved), json!(false));
+    }
+    
+    pub fn is_withdrawal_destination_approved(&self, account_id: AccountId) -> bool {
+        self.withdrawal_allowlist.contains(&account_id)
+    }
+    
+    /// Activar o desactivar la lista de destinos de retiro (solo owner). Activa, `withdraw`,
+    /// `withdraw_split` y `batch_withdraw` solo pagan a cuentas aprobadas; los pagos fallidos ya
+    /// guardados se pueden reintentar igual. Desactivada por defecto.
+    pub fn set_withdrawal_allowlist_enabled(&mut self, enabled: bool) {
+        self.assert_owner();
+        events::config_change(&self.event_namespace, "withdrawal_allowlist_enabled", json!(self.withdrawal_allowlist_enabled), json!(enabled));
+        self.withdrawal_allowlist_enabled = enabled;
+    }
+    
+    pub fn get_withdrawal_allowlist_enabled(&self) -> bool {
+        self.withdrawal_allowlist_enabled
+    }
+    
+    /// Definir la comisión de retiro de un pool con poco anonimato (solo owner): mientras el pool fijo
+    /// de la nota tenga menos de `pool_floor` notas, `withdraw` cobra `fee_basis_points`, para
+    /// incentivar esperar a un conjunto de anonimato mayor. Con 0 basis points queda desactivada.
+    pub fn set_low_anonymity_fee(&mut self, fee_basis_points: u16, pool_floor: u64) {
+        self.assert_owner();
+        assert!(fee_basis_points <= HARD_MAX_FEE_BASIS_POINTS, "Low anonymity fee cannot exceed 20%");
+        events::config_change(&self.event_namespace, "low_anonymity_fee",
+            json!([self.low_anonymity_fee_basis_points, self.low_anonymity_pool_floor]),
+            json!([fee_basis_points, pool_floor]));
+        self.low_anonymity_fee_basis_points = fee_basis_points;
+        self.low_anonymity_pool_floor = pool_floor;
+    }
+    
+    /// (basis points, piso de notas) de la comisión de pool con poco anonimato
+    pub fn get_low_anonymity_fee(&self) -> (u16, u64) {
+        (self.low_anonymity_fee_basis_points, self.low_anonymity_pool_floor)
+    }
+    
+    /// Timestamp del último depósito en una denominación fija, o `None` si nunca recibió uno. Un pool
+    /// sin actividad reciente ofrece poco anonimato.
+    pub fn get_last_activity(&self, denomination: NearToken) -> Option<Timestamp> {
+        self.last_deposit_timestamp.get(&denomination)
+    }
+    
+    /// Datos para la cuenta regresiva de un depósito: (timestamp del depósito, timestamp desde el que
+    /// se puede retirar, si ya se puede retirar ahora). `None` si el commitment no existe.
+    pub fn get_unlock_info(&self, commitment_hash: String) -> Option<(Timestamp, Timestamp, bool)> {
+        self.get_deposit(&commitment_hash)
+            .or_else(|| self.get_deposit(&format!("{}{}", RAW_COMMITMENT_PREFIX, commitment_hash)))
+            .map(|deposit| {
+                let unlock_timestamp = deposit.timestamp + MIN_DELAY * 1_000_000_000;
+                (deposit.timestamp, unlock_timestamp, env::block_timestamp() >= unlock_timestamp)
+            })
+    }
+    
+    /// Recibo de una nota activa: commitment, denominación, timestamp, posición en el árbol, versión
+    /// del esquema y momento de desbloqueo. `None` si el commitment no existe o ya se retiró.
+    pub fn get_deposit_receipt(&self, commitment_hash: String) -> Option<DepositReceipt> {
+        let hex = commitment_hex(&commitment_hash).to_string();
+        let deposit = self.get_deposit(&hex)
+            .or_else(|| self.get_deposit(&format!("{}{}", RAW_COMMITMENT_PREFIX, hex)))?;
+        Some(DepositReceipt {
+            leaf_index: self.leaf_indices.get(&hex),
+            commitment_hash: hex,
+            denomination: yocto(deposit.denomination),
+            timestamp: deposit.timestamp,
+            scheme_version: deposit.scheme_version,
+            hash_scheme: deposit.hash_scheme,
+            unlock_timestamp: deposit.timestamp + MIN_DELAY * 1_000_000_000,
+        })
+    }
+    
+    /// Nota cifrada que el depositante guardó con su depósito, como bytes opacos, o `None` si la nota
+    /// no existe o no tiene. No forma parte del recibo ni de ninguna estadística.
+    pub fn get_encrypted_note(&self, commitment_hash: String) -> Option<Base64VecU8> {
+        let hex = commitment_hex(&commitment_hash).to_string();
+        self.get_deposit(&hex)
+            .or_else(|| self.get_deposit(&format!("{}{}", RAW_COMMITMENT_PREFIX, hex)))?
+            .encrypted_note
+            .map(Base64VecU8::from)
+    }
+    
+    /// Recibos de varios commitments en una sola llamada, en el mismo orden que la entrada: `None` en
+    /// la posición de cada commitment que no existe. Acepta hasta `MAX_RECEIPT_BATCH` commitments.
+    pub fn get_deposit_receipts(&self, commitment_hashes: Vec<String>) -> Vec<Option<DepositReceipt>> {
+        assert!(commitment_hashes.len() <= MAX_RECEIPT_BATCH, "Receipt batch cannot exceed 100 commitments");
+        commitment_hashes
+            .into_iter()
+            .map(|commitment_hash| self.get_deposit_receipt(commitment_hash))
+            .collect()
+    }
+    
+    /// Depósitos con `from_ts <= timestamp <= to_ts`, como filas (índice, timestamp, denominación).
+    /// Cada llamada recorre como máximo `MAX_HISTORY_SCAN` entradas del historial a partir de
+    /// `from_index` y devuelve como máximo `limit` filas, recortado a `MAX_PAGE_LIMIT`. Para continuar,
+    /// volver a llamar con `from_index + MAX_HISTORY_SCAN`, o, si la página se llenó (tantas filas
+    /// como el menor entre `limit` y `MAX_PAGE_LIMIT`), con el último índice devuelto + 1.
+    pub fn get_deposits_in_range(
+        &self,
+        from_ts: Timestamp,
+        to_ts: Timestamp,
+        from_index: u64,
+        limit: u64,
+    ) -> Vec<(u64, Timestamp, NearToken)> {
+        let end = self.deposit_history.len().min(from_index.saturating_add(MAX_HISTORY_SCAN));
+        (from_index..end)
+            .filter_map(|index| {
+                let record = self.deposit_history.get(index)?;
+                (record.timestamp >= from_ts && record.timestamp <= to_ts)
+                    .then_some((index, record.timestamp, record.denomination))
+            })
+            .take(limit.min(MAX_PAGE_LIMIT) as usize)
+            .collect()
+    }
+    
+    pub fn get_deposit_history_length(&self) -> u64 {
+        self.deposit_history.len()
+    }
+    
+    /// Comprueba pares (commitment, hash de retiro) de notas conocidas por quien llama (solo owner) y
+    /// devuelve los commitments inconsistentes: con depósito activo y hash de retiro ya gastado. El
+    /// hash de retiro sale del secreto, así que el contrato no puede recorrer sus notas por su
+    /// cuenta. Revelar un par enlaza esa nota con su retiro: usar solo notas propias o de prueba.
+    /// No modifica el estado, pero es un método de cambio: la comprobación de owner necesita una
+    /// llamada firmada, que una vista no tiene.
+    pub fn check_note_consistency(&mut self, notes: Vec<(String, String)>) -> Vec<String> {
+        self.assert_owner();
+        notes
+            .into_iter()
+            .filter(|(commitment_hash, withdrawal_hash)| {
+                let hex = commitment_hex(commitment_hash).to_string();
+                let live = self.get_deposit(&hex)
+                    .or_else(|| self.get_deposit(&format!("{}{}", RAW_COMMITMENT_PREFIX, hex)))
+                    .is_some();
+                live && self.spent_outputs.contains(withdrawal_hash)
+            })
+            .map(|(commitment_hash, _)| commitment_hash)
+            .collect()
+    }
+    
+    /// Compara `deposit_counts` con las notas activas de cada denominación fija (solo owner). Como
+    /// `LookupMap` no es iterable, las notas se cuentan recorriendo `deposit_history`, así que solo
+    /// ve notas depositadas desde que existe el historial. Devuelve las denominaciones que no
+    /// coinciden como (denominación, contador registrado, notas activas); con `correct` el contador
+    /// se reemplaza por el valor contado. Cada llamada recorre a lo sumo `limit` entradas (entre 1 y
+    /// `MAX_PAGE_LIMIT`) desde donde quedó la anterior y devuelve `None` mientras falte
+    /// historial; la llamada que lo termina devuelve el resultado y es la única que corrige. Si entre
+    /// llamadas hubo depósitos o retiros, la auditoría vuelve a empezar desde el principio.
+    pub fn audit_counts(&mut self, limit: u64, correct: bool) -> Option<Vec<(NearToken, u64, u64)>> {
+        self.assert_owner();
+        let history_len = self.deposit_history.len();
+        let spent_len = self.spent_outputs.len();
+        let mut audit = self
+            .count_audit
+            .take()
+            .filter(|audit| audit.history_len == history_len && audit.spent_len == spent_len)
+            .unwrap_or_else(|| CountAudit { next_index: 0, history_len, spent_len, counts: vec![0; DENOMINATIONS.len()] });
+        let end = history_len.min(audit.next_index.saturating_add(limit.clamp(1, MAX_PAGE_LIMIT)));
+        for index in audit.next_index..end {
+            let Some(record) = self.deposit_history.get(index) else {
+                continue;
+            };
+            // Un commitment retirado puede volver a depositarse: solo cuenta su última entrada, la
+            // de su hoja en el árbol (cada entrada del historial se insertó como la hoja del mismo índice)
+            if self.leaf_indices.get(&record.commitment_hash) != Some(index) {
+                continue;
+            }
+            let raw = format!("{}{}", RAW_COMMITMENT_PREFIX, record.commitment_hash);
+            let Some(deposit) = self.get_deposit(&record.commitment_hash).or_else(|| self.get_deposit(&raw)) else {
+                continue;
+            };
+            let position = self
+                .matches_denomination(deposit.denomination)
+                .and_then(|denomination| DENOMINATIONS.iter().position(|&denom| denom == denomination));
+            if let Some(position) = position {
+                audit.counts[position] += 1;
+            }
+        }
+        audit.next_index = end;
+        if end < history_len {
+            self.count_audit = Some(audit);
+            return None;
+        }
+        
+        let mismatches: Vec<(NearToken, u64, u64)> = DENOMINATIONS
+            .iter()
+            .zip(audit.counts)
+            .map(|(&denom, count)| (denom, self.deposit_counts.get(&denom).unwrap_or(0), count))
+            .filter(|(_, recorded, count)| recorded != count)
+            .collect();
+        if correct {
+            for &(denom, recorded, count) in &mismatches {
+                self.log(&format!("Corrected deposit count for {} NEAR from {} to {}", denom.as_near(), recorded, count));
+                self.deposit_counts.insert(&denom, &count);
+            }
+        }
+        Some(mismatches)
+    }
+    
+    /// Estadísticas de los pools de denominación fija (el pool flexible se consulta aparte):
+    /// (depósitos activos, monto total en yoctoNEAR, filas por denominación).
+    /// En un contrato recién inicializado devuelve (0, "0", ...) con una fila en cero por denominación.
+    pub fn get_pool_stats(&self) -> (u64, U128, Vec<(NearToken, u64)>) {
+        let total_amount = self.total_obligations()
+            .saturating_sub(self.flexible_pool_total)
+            .saturating_sub(self.failed_payouts_total);
+        (self.get_total_deposits(), yocto(total_amount), self.get_all_denomination_stats())
+    }
+    
+    /// Depósitos activos en todas las denominaciones fijas; coincide con el total de `get_pool_stats`
+    pub fn get_total_deposits(&self) -> u64 {
+        DENOMINATIONS.iter().map(|denom| self.deposit_counts.get(denom).unwrap_or(0)).sum()
+    }
+    
+    /// Una fila (denominación, depósitos activos) por cada denominación aceptada,
+    /// incluidas las que aún no tienen depósitos, en orden ascendente de denominación
+    pub fn get_all_denomination_stats(&self) -> Vec<(NearToken, u64)> {
+        DENOMINATIONS
+            .iter()
+            .map(|denom| (*denom, self.deposit_counts.get(denom).unwrap_or(0)))
+            .collect()
+    }
+    
+    /// Una fila por denominación fija, en orden ascendente, con su estado y configuración en una sola
+    /// llamada, pensada para paneles de operación
+    pub fn get_denomination_overview(&self) -> Vec<DenominationOverview> {
+        DENOMINATIONS
+            .iter()
+            .map(|&denomination| {
+                let active_notes = self.deposit_counts.get(&denomination).unwrap_or(0);
+                DenominationOverview {
+                    denomination: yocto(denomination),
+                    active_notes,
+                    total_amount: U128(denomination.as_yoctonear() * u128::from(active_notes)),
+                    fee_basis_points: self
+                        .get_denomination_fee(denomination)
+                        .unwrap_or_else(|| self.effective_fee_basis_points()),
+                    withdrawal_fee: U128(self.quote_fee(denomination, self.fee_mode, self.matured_timestamp(), None)),
+                    min_delay_seconds: MIN_DELAY,
+                    deprecated: self.deprecated_denominations.contains(&denomination),
+                    accepts_deposits: self.accepts_denomination(denomination),
+                    active_at: self.denomination_active_at.get(&denomination),
+                    last_deposit_timestamp: self.last_deposit_timestamp.get(&denomination),
+                    max_tvl: self.max_tvl_per_denomination.get(&denomination).map(yocto),
+                }
+            })
+            .collect()
+    }
+    
+    /// Comisión vigente en este momento
+    pub fn get_fee_basis_points(&self) -> u16 {
+        self.effective_fee_basis_points()
+    }
+    
+    pub fn get_max_fee_basis_points(&self) -> u16 {
+        self.max_fee_basis_points
+    }
+    
+    /// Mensaje que la clave de relay de una nota firma para autorizar un retiro en `batch_withdraw`.
+    /// No usa el secreto, así que se puede pedir al contrato o calcular localmente.
+    pub fn compute_relay_message(
+        &self,
+        withdrawal_hash: String,
+        recipient: AccountId,
+        relayer: AccountId,
+        relayer_fee: U128,
+    ) -> String {
+        relay_message(&withdrawal_hash, &recipient, &relayer, relayer_fee.0)
+    }
+    
+    /// Calcula la vinculación secreto-destinatario a registrar en `deposit`.
+    /// Igual que `compute_commitment`, conviene calcularla localmente.
+    pub fn compute_recipient_commitment(&self, secret: String, recipient: AccountId) -> String {
+        recipient_commitment_for(&secret, &recipient)
+    }
+    
+    /// Ocupación del árbol de Merkle: (próximo índice de hoja, capacidad, altura). Los depósitos se
+    /// rechazan cuando el próximo índice alcanza la capacidad.
+    pub fn get_tree_info(&self) -> (u64, u64, u8) {
+        (self.tree.next_index(), self.tree.capacity(), self.tree.height())
+    }
+    
+    /// Hash de retiro (nullifier) que `withdraw` marcaría como usado para `secret`, calculado con el
+    /// esquema de la nota si existe, o con el esquema actual si no. En el esquema actual el preimage
+    /// es "withdraw:v3:{cuenta del contrato}:{secreto}"; en el 2, "withdraw:{cuenta del
+    /// contrato}:{secreto}", y en el 1, "withdraw:{secreto}". Es una vista:
+    /// el secreto no queda en la cadena, pero sí lo ve el nodo RPC que la responde.
+    pub fn compute_nullifier_hash(&self, secret: String) -> String {
+        match self.find_note(&secret) {
+            Some((_, deposit, note_secret)) => withdrawal_hash_for_scheme(&note_secret, deposit.scheme_version, deposit.hash_scheme),
+            None => withdrawal_hash_for_scheme(&secret, CURRENT_SCHEME_VERSION, HashScheme::Sha256),
+        }
+    }
+    
+    /// Si enviar el secreto en un retiro hacia `recipient` es seguro frente a front-running: la nota
+    /// existe y está vinculada a ese destinatario, o exige retiros firmados con su clave de relay.
+    /// Una nota sin vinculación devuelve `false`, porque quien vea el secreto puede retirarla hacia
+    /// otra cuenta. Igual que `compute_nullifier_hash`, el nodo RPC que responde ve el secreto.
+    pub fn is_withdrawal_safe(&self, secret: String, recipient: AccountId) -> bool {
+        self.find_note(&secret).is_some_and(|(_, deposit, note_secret)| {
+            deposit.relay_key.is_some()
+                || deposit.recipient_commitment == Some(recipient_commitment_for(&note_secret, &recipient))
+        })
+    }
+    
+    /// Estado del árbol de Merkle para reconstruirlo fuera de la cadena: con los subárboles completos,
+    /// el próximo índice y los valores vacíos de `get_zero_value`, cada hoja nueva se inserta como
+    /// lo hace el contrato. Solo copia campos ya guardados.
+    pub fn get_tree_snapshot(&self) -> TreeSnapshot {
+        TreeSnapshot {
+            filled_subtrees: self.tree.filled_subtrees().to_vec(),
+            next_index: self.tree.next_index(),
+            current_root: self.tree.root().to_string(),
+            height: self.tree.height(),
+        }
+    }
+    
+    /// Raíz actual del árbol de Merkle de commitments
+    pub fn get_merkle_root(&self) -> String {
+        self.tree.root().to_string()
+    }
+    
+    /// Si `root` es la raíz actual o una de las `root_history_size` más recientes. Una prueba hecha
+    /// contra una raíz que ya salió del historial debe rehacerse con una raíz más nueva.
+    pub fn is_known_root(&self, root: String) -> bool {
+        self.tree.is_known_root(&root)
+    }
+    
+    /// Raíz que tendría el árbol si `new_leaf` se insertara ahora en la siguiente posición, sin
+    /// modificar estado. Permite preparar una prueba contra la raíz posterior al propio depósito; solo
+    /// coincide si ningún otro depósito entra antes. La hoja es el commitment en hex, también para
+    /// los depósitos con `deposit_raw`.
+    pub fn peek_next_root(&self, new_leaf: String) -> String {
+        self.tree.peek_root(&new_leaf)
+    }
+    
+    pub fn get_root_history_size(&self) -> u32 {
+        self.tree.root_history_size()
+    }
+    
+    /// Valor de un subárbol vacío en `level` (0 = hoja), derivado de la semilla pública "near-mixer"
+    /// para que cualquiera pueda comprobar el estado vacío del árbol
+    pub fn get_zero_value(&self, level: u8) -> String {
+        assert!(level <= merkle::TREE_HEIGHT, "Level exceeds the tree height");
+        merkle::zeros().swap_remove(usize::from(level))
+    }
+    
+    /// Verifica un camino de Merkle construido por el cliente usando el mismo hasher del contrato.
+    /// No modifica estado; sirve para validar pruebas locales antes de intentar un retiro.
+    pub fn verify_merkle_path(&self, leaf: String, path_elements: Vec<String>, path_indices: Vec<u8>, root: String) -> bool {
+        merkle::compute_root(&leaf, &path_elements, &path_indices).is_some_and(|computed| computed == root)
+    }
+    
+    /// Calcula el commitment que corresponde a un secreto en este despliegue. Con `denomination`, el
+    /// commitment queda ligado a ese pool fijo y el mismo secreto se puede depositar en otro; con
+    /// `salt`, también entre varias notas del mismo pool, retirándolas con el secreto "{sal}:{secreto}".
+    /// Rechaza los secretos más cortos que `min_secret_bytes`, que no se podrían retirar; mide, como
+    /// el retiro, el secreto que se pasará a `withdraw` (con la sal y sin el prefijo del pool).
+    /// Es una vista de conveniencia: lo ideal es calcularlo localmente para no enviar el secreto a un nodo RPC.
+    pub fn compute_commitment(&self, secret: String, denomination: Option<NearToken>, salt: Option<String>) -> String {
+        let secret = match salt {
+            Some(salt) => format!("{}:{}", salt, secret),
+            None => secret,
+        };
+        assert!(secret.len() >= self.min_secret_bytes.0 as usize, "Secret is shorter than the minimum secret length");
+        match denomination {
+            Some(denomination) => {
+                let denomination = self.matches_denomination(denomination).expect("Invalid denomination");
+                commitment_for(&pool_secret(&secret, denomination))
+            }
+            None => commitment_for(&secret),
+        }
+    }
+    
+    /// Definir el largo mínimo en bytes del secreto (solo owner). El contrato solo ve el secreto al
+    /// retirar, así que lo exige entonces, y solo a las notas depositadas desde este momento: las
+    /// anteriores se retiran igual. 0 lo desactiva.
+    pub fn set_min_secret_bytes(&mut self, min_secret_bytes: u32) {
+        self.assert_owner();
+        events::config_change(&self.event_namespace, "min_secret_bytes", json!(self.min_secret_bytes.0), json!(min_secret_bytes));
+        self.min_secret_bytes = (min_secret_bytes, env::block_timestamp());
+    }
+    
+    /// (largo mínimo del secreto en bytes, timestamp desde el que rige)
+    pub fn get_min_secret_bytes(&self) -> (u32, Timestamp) {
+        self.min_secret_bytes
+    }
+}
+
+impl UtxoMixer {
+    /// Estado inicial del contrato, compartido por `new` y las rutas de migración
+    fn initial_state(
+        owner: AccountId,
+        fee_basis_points: u16,
+        max_fee_basis_points: Option<u16>,
+        root_history_size: Option<u32>,
+    ) -> Self {
+        let max_fee_basis_points = max_fee_basis_points.unwrap_or(DEFAULT_MAX_FEE_BASIS_POINTS);
+        assert!(max_fee_basis_points <= HARD_MAX_FEE_BASIS_POINTS, "Fee cap cannot exceed 20%");
+        assert!(fee_basis_points <= max_fee_basis_points, "Fee cannot exceed the fee cap");
+        
+        Self {
+            deposits: LookupMap::new(b"d"),
+            spent_outputs: UnorderedSet::new(b"s"),
+            treasury: owner.clone(),
+            owner,
+            fee_basis_points,
+            deposit_counts: LookupMap::new(b"c"),
+            max_fee_basis_points,
+            pending_fee: None,
+            denomination_fees: LookupMap::new(b"x"),
+            fee_exempt_recipients: UnorderedSet::new(b"e"),
+            accumulated_fees: NearToken::from_yoctonear(0),
+            fee_sweep_threshold: NearToken::from_yoctonear(0),
+            flexible_deposits: LookupMap::new(b"f"),
+            flexible_pool_enabled: false,
+            flexible_pool_min: NearToken::from_near(1),
+            flexible_deposit_count: 0,
+            flexible_pool_total: NearToken::from_yoctonear(0),
+            deposit_history: Vector::new(b"h"),
+            failed_payouts: LookupMap::new(b"p"),
+            failed_payouts_total: NearToken::from_yoctonear(0),
+            verbose_logging: true,
+            max_note_value: None,
+            fee_mode: FeeMode::AtWithdraw,
+            min_withdrawal_amount: NearToken::from_yoctonear(0),
+            tree: merkle::MerkleTree::new(merkle::TREE_HEIGHT,
+                root_history_size.unwrap_or(merkle::DEFAULT_ROOT_HISTORY_SIZE), b"q"),
+            deprecated_denominations: UnorderedSet::new(b"v"),
+            depositors: LookupMap::new(b"o"),
+            min_pool_size_for_withdrawal: 0,
+            storage_accounts: LookupMap::new(b"a"),
+            fee_history: vec![(env::block_timestamp(), fee_basis_points)],
+            raw_deposits: LookupMap::new(b"r"),
+            hex_deposits_enabled: true,
+            allowed_depositors: UnorderedSet::new(b"w"),
+            deposit_allowlist_enabled: false,
+            migration_in_progress: false,
+            max_relayer_fee_basis_points: DEFAULT_MAX_RELAYER_FEE_BASIS_POINTS,
+            last_deposit_timestamp: LookupMap::new(b"t"),
+            versioned_deposits: LookupMap::new(b"n"),
+            storage_balances_total: NearToken::from_yoctonear(0),
+            in_flight_fees: NearToken::from_yoctonear(0),
+            early_withdrawal_penalty_basis_points: 0,
+            accepted_hash_schemes: vec![HashScheme::Sha256],
+            registered_commitments: LookupSet::new(b"u"),
+            auto_sweep_threshold: None,
+            frozen_denominations: UnorderedSet::new(b"z"),
+            freeze_blocks_withdrawals: false,
+            aml_contract: None,
+            deposits_paused: false,
+            emergency_exit: false,
+            fee_rounding: FeeRounding::Down,
+            deposit_surcharge: NearToken::from_yoctonear(0),
+            relayer_subsidy_pool: NearToken::from_yoctonear(0),
+            approved_relayers: UnorderedSet::new(b"y"),
+            event_namespace: events::DEFAULT_EVENT_NAMESPACE.to_string(),
+            strict_field_elements: false,
+            leaf_indices: LookupMap::new(b"l"),
+            low_anonymity_fee_basis_points: 0,
+            low_anonymity_pool_floor: 0,
+            max_tvl_global: None,
+            max_tvl_per_denomination: LookupMap::new(b"k"),
+            commitment_ttl: None,
+            forfeited_pool: NearToken::from_yoctonear(0),
+            denomination_labels: LookupMap::new(b"g"),
+            max_inserts_per_block: 0,
+            block_inserts: (0, 0),
+            denomination_activation_delay: 0,
+            denomination_active_at: LookupMap::new(b"j"),
+            min_secret_bytes: (0, 0),
+            auto_pause_on_insolvency: false,
+            insolvency_halt: false,
+            denomination_relayers: LookupMap::new(b"i"),
+            relayer_allowlist_enabled: false,
+            withdrawal_allowlist: UnorderedSet::new(b"m"),
+            withdrawal_allowlist_enabled: false,
+            reserve_buffer: NearToken::from_yoctonear(0),
+            count_audit: None,
+            storage_locked_bytes: 0,
+            versioned_flexible_deposits: LookupMap::new(b"F"),
+        }
+    }
+    
+    /// Si se puede retirar a `recipient`: siempre, salvo con la lista de destinos activa
+    fn is_destination_allowed(&self, recipient: &AccountId) -> bool {
+        !self.withdrawal_allowlist_enabled || self.withdrawal_allowlist.contains(recipient)
+    }
+    
+    /// Mensaje de log legible, solo si `verbose_logging` está activo
+    fn log(&self, message: &str) {
+        if self.verbose_logging {
+            env::log_str(message);
+        }
+    }
+    
+    fn assert_owner(&self) {
+        assert_eq!(env::predecessor_account_id(), self.owner, "Only the owner can call this method");
+    }
+    
+    /// Denominación fija, tal como está definida en `DENOMINATIONS`, que coincide exactamente (al
+    /// yoctoNEAR) con `amount`. Es la única comparación de montos contra las denominaciones: los
+    /// depósitos, las validaciones y las vistas pasan por aquí.
+    fn matches_denomination(&self, amount: NearToken) -> Option<NearToken> {
+        DENOMINATIONS.iter().copied().find(|denomination| denomination.as_yoctonear() == amount.as_yoctonear())
+    }
+    
+    fn assert_not_migrating(&self) {
+        assert!(!self.migration_in_progress, "Contract is being migrated, try again later");
+    }
+    
+    fn assert_not_halted(&self) {
+        assert!(!self.insolvency_halt, "Withdrawals are halted after an insolvency was detected");
+    }
+    
+    /// Con `auto_pause_on_insolvency`, si el saldo no cubre lo reservado pausa los depósitos, detiene
+    /// los retiros y emite la alerta. Devuelve si lo hizo: quien llama debe terminar sin fallar, porque
+    /// un panic revertiría la pausa.
+    fn pause_if_insolvent(&mut self) -> bool {
+        if !self.auto_pause_on_insolvency {
+            return false;
+        }
+        // El saldo ya incluye el depósito adjunto, que aún no está reservado
+        let balance = env::account_balance().saturating_sub(env::attached_deposit());
+        let reserved = self.reserved_balance();
+        if balance >= reserved {
+            return false;
+        }
+        self.deposits_paused = true;
+        self.insolvency_halt = true;
+        self.log("Insolvency detected: deposits paused and withdrawals halted");
+        events::insolvency_detected(&self.event_namespace, balance, reserved);
+        true
+    }
+    
+    /// Devuelve el depósito adjunto a quien llama, cuando un depósito termina sin registrarse
+    fn refund_attached_deposit(&self) {
+        let attached = env::attached_deposit();
+        if !attached.is_zero() {
+            Promise::new(env::predecessor_account_id()).transfer(attached);
+        }
+    }
+    
+    /// Exige que `hash_scheme` esté aceptada y que el commitment tenga el largo de su digest y, en
+    /// modo estricto, que sea un elemento del campo de BN254
+    fn assert_hash_scheme(&self, commitment_hash: &str, hash_scheme: HashScheme) {
+        assert!(self.accepted_hash_schemes.contains(&hash_scheme), "Hash scheme is not accepted");
+        let hex = commitment_hex(commitment_hash);
+        // Solo hex en minúsculas: cada digest tiene una única clave y no se puede repetir cambiando mayúsculas
+        assert!(hex.len() == hash_scheme.commitment_hex_len()
+                && hex.bytes().all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b)),
+                "Commitment does not match the declared hash scheme");
+        // Con el mismo largo y en minúsculas, el orden de los strings es el de los números
+        assert!(!self.strict_field_elements || hex < BN254_FIELD_MODULUS_HEX,
+                "Commitment is not a valid BN254 field element");
+    }
+    
+    /// Un retiro de `batch_withdraw`; devuelve el motivo en lugar de hacer panic para que el lote continúe
+    fn batch_withdraw_one(&mut self, relayer: &AccountId, request: WithdrawRequest) -> Result<String, String> {
+        let WithdrawRequest { recipient, secret, relayer_fee, relay_signature } = request;
+        if recipient == env::current_account_id() {
+            return Err("The mixer contract cannot be the recipient".to_string());
+        }
+        // Quien envía el lote cobra la comisión de relayer; si además fuera el destinatario, el flujo
+        // de comisiones dejaría de reflejar quién recibe qué
+        if &recipient == relayer {
+            return Err("The relayer cannot be the recipient".to_string());
+        }
+        if !self.is_destination_allowed(&recipient) {
+            return Err("Recipient is not an approved withdrawal destination".to_string());
+        }
+        
+        let (commitment_hash, withdrawal_hash, deposit) = self.check_note(&secret, Some(&recipient), false)?;
+        if !self.is_relayer_allowed(deposit.denomination, relayer.clone()) {
+            return Err("Relayer is not allowed for this denomination".to_string());
+        }
+        let fee = self.fee_for(&deposit, Some(&recipient));
+        let relayer_fee = relayer_fee.map_or(0, |fee| fee.0);
+        if relayer_fee > self.max_relayer_fee(deposit.denomination) {
+            return Err("Relayer fee exceeds the maximum relayer fee".to_string());
+        }
+        let withdrawal_amount = (deposit.denomination.as_yoctonear() - fee)
+            .checked_sub(relayer_fee)
+            .ok_or_else(|| "Relayer fee exceeds the amount after fees".to_string())?;
+        self.check_min_withdrawal(&deposit, withdrawal_amount)?;
+        // 2c. Con clave de relay, el usuario firmó el destinatario, el relayer y su comisión: otro
+        // relayer, o el mismo con otra comisión, invalida la firma
+        if let Some(relay_key) = &deposit.relay_key {
+            let message = relay_message(&withdrawal_hash, &recipient, relayer, relayer_fee);
+            if !verify_relay_signature(relay_key, relay_signature.as_ref(), &message) {
+                return Err("Invalid relay signature".to_string());
+            }
+        }
+        self.consume_note(&commitment_hash, &withdrawal_hash, &deposit);
+        self.reserve_in_flight_fees(fee + relayer_fee);
+        
+        self.log(&format!("Withdrawal of {} NEAR processed to {}",
+            NearToken::from_yoctonear(withdrawal_amount).as_near(), recipient));
+        events::withdraw(&self.event_namespace, &withdrawal_hash, &recipient, withdrawal_amount, fee);
+        
+        Promise::new(recipient.clone())
+            .transfer(NearToken::from_yoctonear(withdrawal_amount))
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(WITHDRAW_RESOLVE_GAS)
+                    .withdraw_resolve(recipient, commitment_hash, withdrawal_hash.clone(), deposit, U128(fee),
+                        Some((relayer.clone(), U128(relayer_fee)))),
+            );
+        Ok(withdrawal_hash)
+    }
+    
+    /// Valida el secreto contra su nota y la consume: marca el hash de retiro como usado y elimina
+    /// el depósito. `recipient` es el destinatario único del retiro, o `None` si se reparte entre varios;
+    /// `allow_early` omite la espera mínima (retiro anticipado con penalización).
+    /// Devuelve (commitment, hash de retiro, depósito, comisión), con la comisión calculada antes de
+    /// sacar la nota de su pool.
+    fn spend_note(&mut self, secret: &str, recipient: Option<&AccountId>, allow_early: bool) -> (String, String, DepositInfo, u128) {
+        let (commitment_hash, withdrawal_hash, deposit) =
+            self.check_note(secret, recipient, allow_early).unwrap_or_else(|err| env::panic_str(&err));
+        assert!(deposit.relay_key.is_none(), "This note can only be withdrawn with a signed relay request");
+        let fee = self.fee_for(&deposit, recipient);
+        self.consume_note(&commitment_hash, &withdrawal_hash, &deposit);
+        (commitment_hash, withdrawal_hash, deposit, fee)
+    }
+    
+    /// Busca la nota de un secreto con cada esquema soportado, del más nuevo al más viejo, y cada
+    /// función de hash, en ambos formatos de commitment; la nota solo vale con el esquema y la
+    /// función con los que fue registrada. El esquema 1 es anterior a las funciones de hash y solo
+    /// usa SHA-256. Si no hay ninguna, prueba el secreto ligado a cada pool fijo con el esquema actual
+    /// (ver `pool_secret`), del pool menor al mayor. Devuelve (commitment, depósito, secreto de la
+    /// nota). Son a lo sumo veintidós búsquedas puntuales por mapa, sin recorrer ninguno: el gas no
+    /// depende de cuántas notas haya.
+    fn find_note(&self, secret: &str) -> Option<(String, DepositInfo, String)> {
+        let plain = [
+            (SCHEME_V3, HashScheme::Sha256),
+            (SCHEME_V3, HashScheme::Keccak256),
+            (SCHEME_V2, HashScheme::Sha256),
+            (SCHEME_V2, HashScheme::Keccak256),
+            (SCHEME_V1, HashScheme::Sha256),
+        ]
+        .into_iter()
+        .find_map(|(version, hash_scheme)| {
+            let hex = commitment_for_scheme(secret, version, hash_scheme);
+            let raw = format!("{}{}", RAW_COMMITMENT_PREFIX, hex);
+            [hex, raw].into_iter().find_map(|commitment_hash| {
+                self.get_deposit(&commitment_hash)
+                    .filter(|deposit| deposit.scheme_version == version && deposit.hash_scheme == hash_scheme)
+                    .map(|deposit| (commitment_hash, deposit, secret.to_string()))
+            })
+        });
+        plain.or_else(|| {
+            DENOMINATIONS.into_iter().find_map(|denomination| {
+                let note_secret = pool_secret(secret, denomination);
+                [HashScheme::Sha256, HashScheme::Keccak256].into_iter().find_map(|hash_scheme| {
+                    let hex = commitment_for_scheme(&note_secret, CURRENT_SCHEME_VERSION, hash_scheme);
+                    let raw = format!("{}{}", RAW_COMMITMENT_PREFIX, hex);
+                    [hex, raw].into_iter().find_map(|commitment_hash| {
+                        self.get_deposit(&commitment_hash)
+                            .filter(|deposit| {
+                                deposit.scheme_version == CURRENT_SCHEME_VERSION
+                                    && deposit.hash_scheme == hash_scheme
+                                    && self.matches_denomination(deposit.denomination) == Some(denomination)
+                            })
+                            .map(|deposit| (commitment_hash, deposit, note_secret.clone()))
+                    })
+                })
+            })
+        })
+    }
+    
+    /// Pasos 1-5 de `spend_note`: comprueba que la nota existe y se puede retirar, sin modificar el estado
+    fn check_note(
+        &self,
+        secret: &str,
+        recipient: Option<&AccountId>,
+        allow_early: bool,
+    ) -> Result<(String, String, DepositInfo), String> {
+        if self.insolvency_halt {
+            return Err("Withdrawals are halted after an insolvency was detected".to_string());
+        }
+        
+        // 1-2. Buscar el depósito del secreto
+        let (commitment_hash, deposit, note_secret) = self.find_note(secret).ok_or("No deposit found for this secret")?;
+        
+        // 2a. Un secreto corto se puede adivinar; solo se exige a las notas posteriores al mínimo.
+        // Se mide el secreto recibido y no el de la nota, que lleva el prefijo del pool
+        let (min_secret_bytes, since) = self.min_secret_bytes;
+        if deposit.timestamp >= since && secret.len() < min_secret_bytes as usize {
+            return Err("Secret is shorter than the minimum secret length".to_string());
+        }
+        
+        // 2b. Si el depósito está vinculado a un destinatario, el retiro debe ir a ese destinatario
+        if let Some(expected) = &deposit.recipient_commitment {
+            let recipient = recipient.ok_or("Recipient-bound notes cannot be split or migrated")?;
+            if &recipient_commitment_for(&note_secret, recipient) != expected {
+                return Err("Recipient does not match the one bound at deposit".to_string());
+            }
+        }
+        
+        // 3. Generar un hash de retiro único con el esquema de la nota
+        let withdrawal_hash = withdrawal_hash_for_scheme(&note_secret, deposit.scheme_version, deposit.hash_scheme);
+        
+        // 4. Verificar que este hash de retiro no se ha usado antes (prevenir doble gasto); es una
+        // lectura de una sola clave
+        if self.spent_outputs.contains(&withdrawal_hash) {
+            return Err("This secret has already been used".to_string());
+        }
+        
+        // 5. Verificar que ha pasado suficiente tiempo, salvo retiro anticipado con penalización o
+        // salida de emergencia
+        if !allow_early && env::block_timestamp() - deposit.timestamp < MIN_DELAY * 1_000_000_000 {
+            if !self.is_emergency_exit_active() {
+                return Err("Withdrawal too early".to_string());
+            }
+            self.log("Emergency exit: time-lock waived");
+        }
+        
+        // 5b. Piso de anonimato: retirar de un pool fijo casi vacío enlaza depósito y retiro, salvo
+        // que quien retira sea el propio depositante. El tamaño sale del contador, no de contar notas
+        if self.matches_denomination(deposit.denomination).is_some() {
+            let pool_size = self.deposit_counts.get(&deposit.denomination).unwrap_or(0);
+            let is_depositor = self.depositors.get(&commitment_hash) == Some(env::predecessor_account_id());
+            if pool_size < self.min_pool_size_for_withdrawal && !is_depositor {
+                return Err("Pool is too small to withdraw privately".to_string());
+            }
+        }
+        
+        // 5c. Un pool congelado solo bloquea los retiros si el owner lo configuró así
+        if self.freeze_blocks_withdrawals && self.frozen_denominations.contains(&deposit.denomination) {
+            return Err("This denomination is frozen".to_string());
+        }
+        
+        Ok((commitment_hash, withdrawal_hash, deposit))
+    }
+    
+    /// Pasos 6-7 de `spend_note`: consume una nota ya validada. Marcar el hash de retiro y eliminar
+    /// el depósito ocurren juntos, antes de crear cualquier promesa, así que ningún callback ve una
+    /// nota activa y a la vez gastada.
+    fn consume_note(&mut self, commitment_hash: &String, withdrawal_hash: &String, deposit: &DepositInfo) {
+        // 6. Marcar como usado; el commitment queda registrado aunque la nota sea anterior al registro
+        assert!(self.spent_outputs.insert(withdrawal_hash), "This secret has already been used");
+        self.registered_commitments.insert(&commitment_hex(commitment_hash).to_string());
+        
+        // 7. Eliminar el depósito y descontarlo de las estadísticas del pool
+        self.remove_deposit(commitment_hash, deposit);
+    }
+    
+    /// Inverso de `consume_note` para un retiro fallido: libera el hash de retiro y vuelve a guardar
+    /// el depósito. Solo vale para una nota en vuelo; un callback repetido o fuera de orden que
+    /// encuentre la nota ya activa falla en lugar de duplicarla en los contadores.
+    fn restore_note(&mut self, commitment_hash: &String, withdrawal_hash: &String, deposit: &DepositInfo) {
+        assert!(self.get_deposit(commitment_hash).is_none(), "Note is not pending withdrawal");
+        self.spent_outputs.remove(withdrawal_hash);
+        self.store_deposit(commitment_hash, deposit);
+    }
+    
+    /// Guarda `amount` como pago fallido de `recipient`, a la espera de `retry_failed_payout`
+    fn record_failed_payout(&mut self, recipient: AccountId, amount: NearToken) {
+        let pending = self.failed_payouts.get(&recipient).unwrap_or(NearToken::from_yoctonear(0));
+        self.failed_payouts.insert(&recipient, &pending.saturating_add(amount));
+        self.failed_payouts_total = self.failed_payouts_total.saturating_add(amount);
+        self.log(&format!("Payout of {} yoctoNEAR to {} failed, kept for retry", amount.as_yoctonear(), recipient));
+        events::payout_failed(&self.event_namespace, &recipient, amount);
+    }
+    
+    /// Comisión normal en yoctoNEAR para una nota de `denomination`; los destinatarios exentos no
+    /// pagan. Sin destinatario único (retiro repartido) la exención no aplica.
+    fn base_fee(&self, denomination: NearToken, recipient: Option<&AccountId>) -> u128 {
+        if recipient.is_some_and(|recipient| self.fee_exempt_recipients.contains(recipient)) {
+            return 0;
+        }
+        let fee_basis_points = self
+            .denomination_fees
+            .get(&denomination)
+            .and_then(|fee| fee.effective())
+            .unwrap_or_else(|| self.effective_fee_basis_points());
+        self.fee_rounding.apply(denomination.as_yoctonear(), fee_basis_points)
+    }
+    
+    /// Si `deposit` acepta ahora notas de una denominación fija: no obsoleta, no congelada, ya activa
+    /// y dentro del valor máximo de nota
+    fn accepts_denomination(&self, denomination: NearToken) -> bool {
+        !self.deprecated_denominations.contains(&denomination)
+            && !self.frozen_denominations.contains(&denomination)
+            && self.is_denomination_active(denomination)
+            && self.max_note_value.is_none_or(|max| denomination <= max)
+    }
+    
+    /// Comisión de relayer máxima para una nota de `denomination`, según `max_relayer_fee_basis_points`
+    fn max_relayer_fee(&self, denomination: NearToken) -> u128 {
+        denomination.as_yoctonear() * u128::from(self.max_relayer_fee_basis_points) / 10000
+    }
+    
+    /// Si ya pasó la espera de activación de una denominación; sin reactivación pendiente, siempre
+    fn is_denomination_active(&self, denomination: NearToken) -> bool {
+        self.denomination_active_at
+            .get(&denomination)
+            .is_none_or(|active_at| env::block_timestamp() >= active_at)
+    }
+    
+    /// Si un retiro de `denomination` paga la comisión de pool con poco anonimato. Se llama con la
+    /// nota todavía en su pool, así que el contador la incluye.
+    fn is_low_anonymity_pool(&self, denomination: NearToken) -> bool {
+        self.low_anonymity_fee_basis_points > 0
+            && self.matches_denomination(denomination).is_some()
+            && self.deposit_counts.get(&denomination).unwrap_or(0) < self.low_anonymity_pool_floor
+    }
+    
+    /// Los retiros del pool flexible deben pagar al menos `min_withdrawal_amount`; los pools fijos
+    /// no se controlan porque sus montos ya son grandes
+    fn check_min_withdrawal(&self, deposit: &DepositInfo, net_amount: u128) -> Result<(), String> {
+        if self.matches_denomination(deposit.denomination).is_none() && net_amount < self.min_withdrawal_amount.as_yoctonear() {
+            return Err("Withdrawal is below the minimum withdrawal amount".to_string());
+        }
+        Ok(())
+    }
+    
+    /// Cuenta una inserción en el árbol en el bloque actual y rechaza las que excedan el máximo
+    fn count_block_insert(&mut self) {
+        let height = env::block_height();
+        let inserted = if self.block_inserts.0 == height { self.block_inserts.1 } else { 0 };
+        assert!(self.max_inserts_per_block == 0 || inserted < self.max_inserts_per_block,
+                "Too many deposits in this block, try again next block");
+        self.block_inserts = (height, inserted + 1);
+    }
+    
+    /// Registra una nota nueva, creada con `DepositInfo::new`: comprueba los topes de valor, la
+    /// inserta en el árbol, la guarda en su pool y la añade al historial
+    fn register_note(&mut self, commitment_hash: &String, deposit: DepositInfo) {
+        let amount = deposit.denomination;
+        assert!(deposit.encrypted_note.as_ref().is_none_or(|note| note.len() <= MAX_ENCRYPTED_NOTE_LEN),
+                "Encrypted note cannot exceed 512 bytes");
+        assert!(deposit.relay_key.as_ref().is_none_or(|key| key.curve_type() == CurveType::ED25519),
+                "Relay key must be an ed25519 key");
+        // Una nota con clave de relay solo sale por `batch_withdraw`, que el screening AML desactiva
+        assert!(deposit.relay_key.is_none() || self.aml_contract.is_none(),
+                "Relay-key notes are not available while AML screening is enabled");
+        if let Some(max_note_value) = self.max_note_value {
+            assert!(amount <= max_note_value, "Deposit exceeds the maximum note value");
+        }
+        // Toda nota nueva cuenta para los topes de valor, venga de un depósito o de una migración
+        self.assert_tvl_headroom(amount, self.matches_denomination(amount).is_none());
+        // La nota irá al árbol: el bloque no puede haber alcanzado el máximo de inserciones
+        self.count_block_insert();
+        
+        // Verificar que este commitment no existe ya en ningún pool, en ninguno de los dos formatos, y
+        // que no se registró antes (aunque ya se haya retirado, o con otro esquema)
+        let hex = commitment_hex(commitment_hash).to_string();
+        let raw = format!("{}{}", RAW_COMMITMENT_PREFIX, hex);
+        assert!(self.get_deposit(&hex).is_none() && self.get_deposit(&raw).is_none(), "Commitment already exists");
+        assert!(!self.registered_commitments.contains(&hex), "Commitment was already used");
+        // Con el esquema 1 el commitment de "withdraw:<secreto>" es el hash de retiro de otra nota
+        assert!(!self.spent_outputs.contains(&hex), "Commitment collides with a spent withdrawal hash");
+        self.registered_commitments.insert(&hex);
+        
+        // Insertar el commitment en el árbol; falla si el árbol está lleno
+        let leaf_index = self.tree.insert(&hex);
+        self.leaf_indices.insert(&hex, &leaf_index);
+        
+        // Almacenar la información del depósito asociada al hash del commitment
+        self.store_deposit(commitment_hash, &deposit);
+        self.deposit_history.push(&DepositRecord {
+            commitment_hash: hex,
+            denomination: amount,
+            timestamp: env::block_timestamp(),
+        });
+        if self.matches_denomination(amount).is_some() {
+            self.last_deposit_timestamp.insert(&amount, &env::block_timestamp());
+        }
+        self.record_depositor(commitment_hash, &env::predecessor_account_id());
+    }
+    
+    /// Comisión a descontar al retirar `deposit` ahora, la misma en todas las rutas de retiro y en
+    /// las cotizaciones. La comisión normal depende del modo con el que se depositó la nota; en un
+    /// pool con poco anonimato se cobra la comisión de pool escaso y antes del plazo, salvo en la
+    /// salida de emergencia, la penalización, ambas sin exenciones salvo que la comisión normal sea
+    /// mayor. La nota debe seguir contada en su pool.
+    fn fee_for(&self, deposit: &DepositInfo, recipient: Option<&AccountId>) -> u128 {
+        let amount = deposit.denomination.as_yoctonear();
+        let mut fee = match deposit.fee_mode {
+            FeeMode::AtDeposit => 0,
+            FeeMode::AtWithdraw => self.base_fee(deposit.denomination, recipient),
+        };
+        if self.is_low_anonymity_pool(deposit.denomination) {
+            fee = self.fee_rounding.apply(amount, self.low_anonymity_fee_basis_points).max(fee);
+        }
+        let early = env::block_timestamp().saturating_sub(deposit.timestamp) < MIN_DELAY * 1_000_000_000;
+        if early && !self.is_emergency_exit_active() {
+            fee = self.fee_rounding.apply(amount, self.early_withdrawal_penalty_basis_points).max(fee);
+        }
+        fee
+    }
+    
+    /// `fee_for` de una nota hipotética de `denomination`, depositada en `deposited_at` con
+    /// `fee_mode`, para las vistas de cotización
+    fn quote_fee(&self, denomination: NearToken, fee_mode: FeeMode, deposited_at: Timestamp, recipient: Option<&AccountId>) -> u128 {
+        let deposit = DepositInfo { timestamp: deposited_at, ..DepositInfo::new(denomination, fee_mode, HashScheme::Sha256) };
+        self.fee_for(&deposit, recipient)
+    }
+    
+    /// Timestamp de depósito de una nota que ya cumplió la espera mínima
+    fn matured_timestamp(&self) -> Timestamp {
+        env::block_timestamp().saturating_sub(MIN_DELAY * 1_000_000_000)
+    }
+    
+    /// Separa un monto adjuntado en (valor de la nota, comisión cobrada ahora, modo de la nota).
+    /// Los montos del pool flexible son arbitrarios, así que su comisión siempre se cobra al retirar.
+    fn split_deposit_fee(&self, attached: NearToken) -> (NearToken, u128, FeeMode) {
+        if self.fee_mode == FeeMode::AtDeposit {
+            // El monto sin la comisión de cada denominación debe ser esa misma denominación
+            let fixed = DENOMINATIONS.iter().find_map(|&denomination| {
+                let fee = self.base_fee(denomination, None);
+                let net = NearToken::from_yoctonear(attached.as_yoctonear().saturating_sub(fee));
+                (self.matches_denomination(net) == Some(denomination)).then_some((denomination, fee))
+            });
+            if let Some((denomination, fee)) = fixed {
+                return (denomination, fee, FeeMode::AtDeposit);
+            }
+            assert!(self.matches_denomination(attached).is_none(), "Deposit must include the fee in at-deposit fee mode");
+        }
+        (attached, 0, FeeMode::AtWithdraw)
+    }
+    
+    /// Paso final de `withdraw`: transfiere el monto al destinatario, o lo adjunta a la llamada a su
+    /// método; la comisión reservada se acredita en el callback si la transferencia tuvo éxito
+    fn pay_out(&self, pending: PendingWithdrawal) -> Promise {
+        let PendingWithdrawal { recipient, recipient_method, recipient_msg, amount, commitment_hash, withdrawal_hash, deposit, fee } =
+            pending;
+        let payout = Promise::new(recipient.clone());
+        let resolve = Self::ext(env::current_account_id()).with_static_gas(WITHDRAW_RESOLVE_GAS);
+        match recipient_method {
+            Some(method) => payout
+                .function_call(
+                    method,
+                    recipient_msg.unwrap_or_else(|| "{}".to_string()).into_bytes(),
+                    near_token(amount),
+                    RECIPIENT_CALL_GAS,
+                )
+                .then(resolve.recipient_call_resolve(recipient, commitment_hash, fee, amount)),
+            None => payout
+                .transfer(near_token(amount))
+                .then(resolve.withdraw_resolve(recipient, commitment_hash, withdrawal_hash, deposit, fee, None)),
+        }
+    }
+    
+    /// Envía todas las comisiones acumuladas a la tesorería si superan `auto_sweep_threshold`.
+    /// `pending_payout` es lo que el retiro en curso todavía no transfirió. Si el envío dejara
+    /// depósitos sin respaldo se omite en silencio, para no hacer fallar el retiro.
+    fn auto_sweep_fees(&mut self, pending_payout: NearToken) {
+        let Some(threshold) = self.auto_sweep_threshold else {
+            return;
+        };
+        let amount = self.accumulated_fees;
+        let remaining_balance = env::account_balance().saturating_sub(pending_payout).saturating_sub(amount);
+        if amount > threshold && remaining_balance >= self.reserved_besides_fees() {
+            self.log(&format!("Forwarding {} yoctoNEAR of fees to the treasury", amount.as_yoctonear()));
+            self.transfer_fees_to_treasury(amount);
+        }
+    }
+    
+    fn transfer_fees_to_treasury(&mut self, amount: NearToken) -> Promise {
+        assert!(!amount.is_zero(), "No fees to withdraw");
+        assert!(amount <= self.accumulated_fees, "Amount exceeds accumulated fees");
+        let remaining_balance = env::account_balance().saturating_sub(amount);
+        assert!(remaining_balance >= self.reserved_besides_fees(), "Withdrawal would leave deposits unbacked");
+        
+        self.accumulated_fees = self.accumulated_fees.saturating_sub(amount);
+        Promise::new(self.treasury.clone()).transfer(amount)
+    }
+    
+    /// Comisión vigente, teniendo en cuenta un cambio programado que ya haya cumplido su plazo
+    fn effective_fee_basis_points(&self) -> u16 {
+        match self.pending_fee {
+            Some((fee, effective_at)) if env::block_timestamp() >= effective_at => fee,
+            _ => self.fee_basis_points,
+        }
+    }
+    
+    /// Consolida en `fee_basis_points` un cambio programado que ya esté vigente
+    fn apply_pending_fee(&mut self) {
+        if let Some((fee, effective_at)) = self.pending_fee {
+            if env::block_timestamp() >= effective_at {
+                self.fee_basis_points = fee;
+                self.pending_fee = None;
+            }
+        }
+    }
+    
+    fn reserve_in_flight_fees(&mut self, amount: u128) {
+        self.in_flight_fees = self.in_flight_fees.saturating_add(NearToken::from_yoctonear(amount));
+    }
+    
+    /// Parte del saldo bloqueada por el staking del almacenamiento que ocupa el contrato
+    fn storage_staked(&self) -> NearToken {
+        env::storage_byte_cost().saturating_mul(u128::from(env::storage_usage()))
+    }
+    
+    /// Saldo que el contrato debe conservar: obligaciones, comisiones acumuladas y reservadas,
+    /// fondos de subsidio y de decomisos, la reserva, la parte disponible de los saldos de
+    /// almacenamiento y el staking de almacenamiento. La parte bloqueada de cada saldo paga bytes que
+    /// ya cuentan en el staking, así que sumarla también los contaría dos veces.
+    fn reserved_balance(&self) -> NearToken {
+        self.total_obligations()
+            .saturating_add(self.accumulated_fees)
+            .saturating_add(self.in_flight_fees)
+            .saturating_add(self.relayer_subsidy_pool)
+            .saturating_add(self.forfeited_pool)
+            .saturating_add(self.reserve_buffer)
+            .saturating_add(self.storage_available_total())
+            .saturating_add(self.storage_staked())
+    }
+    
+    /// Lo que el contrato debe conservar además de las comisiones acumuladas, que son lo único que
+    /// un envío a la tesorería puede gastar
+    fn reserved_besides_fees(&self) -> NearToken {
+        self.reserved_balance().saturating_sub(self.accumulated_fees)
+    }
+    
+    fn surplus(&self) -> NearToken {
+        env::account_balance().saturating_sub(self.reserved_balance())
+    }
+    
+    /// Valor de las notas activas de una denominación fija
+    fn denomination_value_locked(&self, denomination: NearToken) -> NearToken {
+        NearToken::from_yoctonear(denomination.as_yoctonear() * u128::from(self.deposit_counts.get(&denomination).unwrap_or(0)))
+    }
+    
+    /// Valor de todas las notas activas, fijas y flexibles; a diferencia de las obligaciones, sin
+    /// los pagos fallidos
+    fn total_value_locked(&self) -> NearToken {
+        DENOMINATIONS
+            .iter()
+            .fold(self.flexible_pool_total, |total, &denomination| {
+                total.saturating_add(self.denomination_value_locked(denomination))
+            })
+    }
+    
+    /// Rechaza un depósito que superaría el tope global o el de su denominación
+    fn assert_tvl_headroom(&self, amount: NearToken, flexible: bool) {
+        if let Some(max) = self.max_tvl_global {
+            assert!(self.total_value_locked().saturating_add(amount) <= max,
+                    "Deposit would exceed the total value locked cap");
+        }
+        if let Some(max) = (!flexible).then(|| self.max_tvl_per_denomination.get(&amount)).flatten() {
+            assert!(self.denomination_value_locked(amount).saturating_add(amount) <= max,
+                    "Deposit would exceed the value locked cap of this denomination");
+        }
+    }
+    
+    /// Suma de todos los depósitos activos, incluido el pool flexible, y de los pagos fallidos pendientes
+    fn total_obligations(&self) -> NearToken {
+        let total: u128 = DENOMINATIONS
+            .iter()
+            .map(|denom| denom.as_yoctonear() * u128::from(self.deposit_counts.get(denom).unwrap_or(0)))
+            .sum();
+        NearToken::from_yoctonear(total)
+            .saturating_add(self.flexible_pool_total)
+            .saturating_add(self.failed_payouts_total)
+    }
+    
+    /// Busca un depósito en los pools fijos y en el flexible
+    fn get_deposit(&self, commitment_hash: &String) -> Option<DepositInfo> {
+        if let Some(raw) = raw_commitment_bytes(commitment_hash) {
+            return self.raw_deposits.get(&raw).map(VersionedDepositInfo::into_current);
+        }
+        self.versioned_deposits
+            .get(commitment_hash)
+            .or_else(|| self.versioned_flexible_deposits.get(commitment_hash))
+            .map(VersionedDepositInfo::into_current)
+            .or_else(|| self.deposits.get(commitment_hash))
+            .or_else(|| self.flexible_deposits.get(commitment_hash))
+    }
+    
+    /// Guarda un depósito en su pool y actualiza los contadores correspondientes. Siempre se escribe
+    /// en el formato versionado actual, en el mapa de su pool.
+    fn store_deposit(&mut self, commitment_hash: &String, deposit: &DepositInfo) {
+        let fixed = self.matches_denomination(deposit.denomination).is_some();
+        if fixed {
+            let current_count = self.deposit_counts.get(&deposit.denomination).unwrap_or(0);
+            self.deposit_counts.insert(&deposit.denomination, &(current_count + 1));
+        } else {
+            self.flexible_deposit_count += 1;
+            self.flexible_pool_total = self.flexible_pool_total.saturating_add(deposit.denomination);
+        }
+        let stored = VersionedDepositInfo::V3(deposit.clone());
+        match raw_commitment_bytes(commitment_hash) {
+            Some(raw) => self.raw_deposits.insert(&raw, &stored),
+            None if fixed => self.versioned_deposits.insert(commitment_hash, &stored),
+            None => self.versioned_flexible_deposits.insert(commitment_hash, &stored),
+        };
+    }
+    
+    /// Elimina un depósito de su pool y descuenta los contadores correspondientes
+    fn remove_deposit(&mut self, commitment_hash: &String, deposit: &DepositInfo) {
+        if self.matches_denomination(deposit.denomination).is_some() {
+            let current_count = self.deposit_counts.get(&deposit.denomination).unwrap_or(0);
+            self.deposit_counts.insert(&deposit.denomination, &current_count.saturating_sub(1));
+        } else {
+            self.flexible_deposit_count = self.flexible_deposit_count.saturating_sub(1);
+            self.flexible_pool_total = self.flexible_pool_total.saturating_sub(deposit.denomination);
+        }
+        match raw_commitment_bytes(commitment_hash) {
+            Some(raw) => {
+                self.raw_deposits.remove(&raw);
+            }
+            None => {
+                // La nota puede estar en el formato versionado o en un mapa heredado
+                self.versioned_deposits.remove(commitment_hash);
+                self.versioned_flexible_deposits.remove(commitment_hash);
+                self.deposits.remove(commitment_hash);
+                self.flexible_deposits.remove(commitment_hash);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,169 @@
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::LookupMap;
+use near_sdk::env;
+use sha2::{Digest, Sha256};
+
+/// Altura del árbol de commitments: admite 2^20 hojas
+pub(crate) const TREE_HEIGHT: u8 = 20;
+
+/// Raíces recientes que se recuerdan por defecto, para que una prueba hecha contra una raíz
+/// anterior siga valiendo aunque entren depósitos nuevos
+pub(crate) const DEFAULT_ROOT_HISTORY_SIZE: u32 = 30;
+/// Límites del tamaño del historial de raíces configurable en `new`
+pub(crate) const MIN_ROOT_HISTORY_SIZE: u32 = 2;
+pub(crate) const MAX_ROOT_HISTORY_SIZE: u32 = 256;
+
+/// Semilla pública de la que se derivan los valores vacíos del árbol ("nothing up my sleeve")
+pub(crate) const ZERO_VALUE_SEED: &str = "near-mixer";
+
+/// Hash de un nodo interno del árbol: SHA-256 de la concatenación de los hijos en hex
+pub(crate) fn hash_pair(left: &str, right: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(left.as_bytes());
+    hasher.update(right.as_bytes());
+    format!("{:x}", hasher.finalize())
+}
+
+/// Recalcula la raíz a partir de una hoja y su camino. `path_indices[i]` indica si el nodo
+/// actual es el hijo izquierdo (0) o derecho (1) en el nivel `i`.
+/// Devuelve `None` si el camino está mal formado.
+pub(crate) fn compute_root(leaf: &str, path_elements: &[String], path_indices: &[u8]) -> Option<String> {
+    if path_elements.len() != path_indices.len() {
+        return None;
+    }
+    let mut current = leaf.to_string();
+    for (sibling, index) in path_elements.iter().zip(path_indices) {
+        current = match index {
+            0 => hash_pair(&current, sibling),
+            1 => hash_pair(sibling, &current),
+            _ => return None,
+        };
+    }
+    Some(current)
+}
+
+/// Valores vacíos del árbol por nivel, de las hojas (nivel 0) a la raíz (nivel `TREE_HEIGHT`).
+/// La hoja vacía es keccak256(`ZERO_VALUE_SEED`) en hex; cada nivel siguiente es el hash del par
+/// formado por dos subárboles vacíos del nivel anterior. Los hashes del árbol son SHA-256 sobre
+/// hex, sin reducción a un campo, así que la semilla se usa tal cual.
+pub(crate) fn zeros() -> Vec<String> {
+    zeros_for_height(TREE_HEIGHT)
+}
+
+fn zeros_for_height(height: u8) -> Vec<String> {
+    let leaf: String = env::keccak256(ZERO_VALUE_SEED.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    let mut levels = vec![leaf];
+    for level in 0..usize::from(height) {
+        let below = &levels[level];
+        levels.push(hash_pair(below, below));
+    }
+    levels
+}
+
+/// Sube desde la hoja en `leaf_index` hasta la raíz, actualizando los subárboles completos por
+/// nivel, y devuelve la raíz resultante
+fn hash_up(height: u8, leaf_index: u64, leaf: &str, filled_subtrees: &mut [String]) -> String {
+    let zeros = zeros_for_height(height);
+    let mut index = leaf_index;
+    let mut current = leaf.to_string();
+    for (filled_subtree, zero) in filled_subtrees.iter_mut().zip(&zeros) {
+        current = if index.is_multiple_of(2) {
+            *filled_subtree = current.clone();
+            hash_pair(&current, zero)
+        } else {
+            hash_pair(filled_subtree, &current)
+        };
+        index /= 2;
+    }
+    current
+}
+
+/// Árbol de Merkle incremental de altura fija con los commitments como hojas. Solo guarda el
+/// subárbol completo más reciente de cada nivel, así que insertar cuesta `height` hashes. Las
+/// últimas `root_history_size` raíces quedan en un buffer circular.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub(crate) struct MerkleTree {
+    height: u8,
+    next_index: u64,
+    filled_subtrees: Vec<String>,
+    root: String,
+    // Posición del buffer -> raíz; la raíz actual está en `current_root_index`
+    roots: LookupMap<u32, String>,
+    current_root_index: u32,
+    root_history_size: u32,
+}
+
+impl MerkleTree {
+    pub(crate) fn new(height: u8, root_history_size: u32, roots_prefix: &[u8]) -> Self {
+        assert!((MIN_ROOT_HISTORY_SIZE..=MAX_ROOT_HISTORY_SIZE).contains(&root_history_size),
+                "Root history size must be between 2 and 256");
+        let zeros = zeros_for_height(height);
+        let root = zeros[usize::from(height)].clone();
+        let mut roots = LookupMap::new(roots_prefix);
+        roots.insert(&0, &root);
+        Self {
+            height,
+            next_index: 0,
+            root,
+            filled_subtrees: zeros[..usize::from(height)].to_vec(),
+            roots,
+            current_root_index: 0,
+            root_history_size,
+        }
+    }
+
+    /// Inserta una hoja y devuelve su índice
+    pub(crate) fn insert(&mut self, leaf: &str) -> u64 {
+        assert!(self.next_index < self.capacity(), "Merkle tree is full");
+        let leaf_index = self.next_index;
+        let current = hash_up(self.height, leaf_index, leaf, &mut self.filled_subtrees);
+        self.current_root_index = (self.current_root_index + 1) % self.root_history_size;
+        self.roots.insert(&self.current_root_index, &current);
+        self.root = current;
+        self.next_index += 1;
+        leaf_index
+    }
+    
+    /// Si `root` es la raíz actual o una de las anteriores que siguen en el historial. Recorre solo
+    /// las `root_history_size` posiciones del buffer, de la más nueva a la más vieja.
+    pub(crate) fn is_known_root(&self, root: &str) -> bool {
+        (0..self.root_history_size)
+            .map(|offset| (self.current_root_index + self.root_history_size - offset) % self.root_history_size)
+            .map_while(|index| self.roots.get(&index))
+            .any(|known| known == root)
+    }
+    
+    /// Raíz que quedaría si `leaf` se insertara ahora en `next_index`, sin modificar el árbol
+    pub(crate) fn peek_root(&self, leaf: &str) -> String {
+        assert!(self.next_index < self.capacity(), "Merkle tree is full");
+        hash_up(self.height, self.next_index, leaf, &mut self.filled_subtrees.clone())
+    }
+    
+    pub(crate) fn root_history_size(&self) -> u32 {
+        self.root_history_size
+    }
+
+    pub(crate) fn next_index(&self) -> u64 {
+        self.next_index
+    }
+
+    pub(crate) fn capacity(&self) -> u64 {
+        1u64 << self.height
+    }
+
+    pub(crate) fn height(&self) -> u8 {
+        self.height
+    }
+
+    pub(crate) fn root(&self) -> &str {
+        &self.root
+    }
+
+    /// Subárbol completo más reciente de cada nivel, de las hojas hacia arriba
+    pub(crate) fn filled_subtrees(&self) -> &[String] {
+        &self.filled_subtrees
+    }
+}
@@ -0,0 +1,47 @@
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::{LookupMap, UnorderedSet};
+use near_sdk::{env, AccountId, NearToken};
+
+use crate::DepositInfo;
+
+/// Estado del contrato tal como lo guardaba la primera versión desplegada.
+/// Sus colecciones usan los mismos prefijos que la versión actual, así que sus datos se conservan.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub(crate) struct LegacyUtxoMixer {
+    pub(crate) deposits: LookupMap<String, DepositInfo>,
+    pub(crate) spent_outputs: UnorderedSet<String>,
+    pub(crate) owner: AccountId,
+    pub(crate) fee_basis_points: u16,
+    pub(crate) deposit_counts: LookupMap<NearToken, u64>,
+}
+
+/// Lee un campo del estado serializado; si falla, el panic indica qué campo no coincide
+fn read_field<T: BorshDeserialize>(reader: &mut &[u8], field: &str) -> T {
+    T::deserialize(reader).unwrap_or_else(|err| {
+        env::panic_str(&format!(
+            "Failed to migrate state: field `{}` could not be deserialized ({}). \
+             The stored layout does not match the legacy layout; use `recover_state` instead",
+            field, err
+        ))
+    })
+}
+
+/// Deserializa el estado heredado campo por campo
+pub(crate) fn read_legacy_state(state: &[u8]) -> LegacyUtxoMixer {
+    let mut reader = state;
+    let legacy = LegacyUtxoMixer {
+        deposits: read_field(&mut reader, "deposits"),
+        spent_outputs: read_field(&mut reader, "spent_outputs"),
+        owner: read_field(&mut reader, "owner"),
+        fee_basis_points: read_field(&mut reader, "fee_basis_points"),
+        deposit_counts: read_field(&mut reader, "deposit_counts"),
+    };
+    if !reader.is_empty() {
+        env::panic_str(&format!(
+            "Failed to migrate state: {} unexpected bytes after field `deposit_counts`. \
+             The stored layout does not match the legacy layout; use `recover_state` instead",
+            reader.len()
+        ));
+    }
+    legacy
+}
@@ -0,0 +1,179 @@
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::U128;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{env, near_bindgen, AccountId, NearToken, Promise};
+
+use crate::{near_token, yocto, UtxoMixer, UtxoMixerExt};
+
+// Bytes que cubre el mínimo de registro: la propia entrada de la cuenta en `storage_accounts`
+const STORAGE_REGISTRATION_BYTES: u64 = 125;
+
+/// Saldo de almacenamiento de una cuenta registrada (NEP-145)
+#[derive(BorshDeserialize, BorshSerialize)]
+pub(crate) struct AccountStorage {
+    balance: NearToken,
+    // Bytes ocupados por entradas de la cuenta, además del registro
+    used_bytes: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct StorageBalance {
+    pub total: U128,
+    pub available: U128,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct StorageBalanceBounds {
+    pub min: U128,
+    pub max: Option<U128>,
+}
+
+fn registration_cost() -> NearToken {
+    env::storage_byte_cost().saturating_mul(u128::from(STORAGE_REGISTRATION_BYTES))
+}
+
+impl AccountStorage {
+    fn locked(&self) -> NearToken {
+        registration_cost().saturating_add(env::storage_byte_cost().saturating_mul(u128::from(self.used_bytes)))
+    }
+
+    fn available(&self) -> NearToken {
+        self.balance.saturating_sub(self.locked())
+    }
+
+    fn to_json(&self) -> StorageBalance {
+        StorageBalance { total: yocto(self.balance), available: yocto(self.available()) }
+    }
+}
+
+// Gestión de almacenamiento NEP-145. Las cuentas registradas pagan con su saldo las entradas que el
+// contrato guarda a su nombre (hoy, el registro de depositante de cada nota); sin registro, el
+// depósito funciona igual pero esas entradas no se guardan.
+#[near_bindgen]
+impl UtxoMixer {
+    /// Registrar una cuenta o aumentar su saldo de almacenamiento. Con `registration_only`, solo se
+    /// cobra el mínimo de registro y se devuelve el resto.
+    #[payable]
+    pub fn storage_deposit(&mut self, account_id: Option<AccountId>, registration_only: Option<bool>) -> StorageBalance {
+        let amount = env::attached_deposit();
+        let account_id = account_id.unwrap_or_else(env::predecessor_account_id);
+        let registration_only = registration_only.unwrap_or(false);
+        
+        let storage = match self.storage_accounts.get(&account_id) {
+            Some(storage) if registration_only => {
+                if !amount.is_zero() {
+                    Promise::new(env::predecessor_account_id()).transfer(amount);
+                }
+                storage
+            }
+            Some(mut storage) => {
+                storage.balance = storage.balance.saturating_add(amount);
+                self.storage_balances_total = self.storage_balances_total.saturating_add(amount);
+                storage
+            }
+            None => {
+                let min = registration_cost();
+                assert!(amount >= min, "The attached deposit is less than the minimum storage balance");
+                let balance = if registration_only { min } else { amount };
+                let refund = amount.saturating_sub(balance);
+                if !refund.is_zero() {
+                    Promise::new(env::predecessor_account_id()).transfer(refund);
+                }
+                self.storage_balances_total = self.storage_balances_total.saturating_add(balance);
+                self.storage_locked_bytes += STORAGE_REGISTRATION_BYTES;
+                AccountStorage { balance, used_bytes: 0 }
+            }
+        };
+        self.storage_accounts.insert(&account_id, &storage);
+        storage.to_json()
+    }
+    
+    /// Retirar saldo de almacenamiento no usado; sin `amount`, todo lo disponible. Requiere 1 yoctoNEAR.
+    #[payable]
+    pub fn storage_withdraw(&mut self, amount: Option<U128>) -> StorageBalance {
+        assert_eq!(env::attached_deposit(), NearToken::from_yoctonear(1), "Requires attached deposit of exactly 1 yoctoNEAR");
+        let account_id = env::predecessor_account_id();
+        let mut storage = self.storage_accounts.get(&account_id).expect("The account is not registered");
+        let available = storage.available();
+        let amount = amount.map_or(available, near_token);
+        assert!(amount <= available, "The amount is greater than the available storage balance");
+        
+        storage.balance = storage.balance.saturating_sub(amount);
+        self.storage_balances_total = self.storage_balances_total.saturating_sub(amount);
+        self.storage_accounts.insert(&account_id, &storage);
+        if !amount.is_zero() {
+            Promise::new(account_id).transfer(amount.saturating_add(NearToken::from_yoctonear(1)));
+        }
+        storage.to_json()
+    }
+    
+    /// Dar de baja la cuenta y devolver todo su saldo. Solo es posible sin entradas a su nombre;
+    /// `force` no se admite porque borraría registros de depositante de notas activas.
+    #[payable]
+    pub fn storage_unregister(&mut self, force: Option<bool>) -> bool {
+        assert_eq!(env::attached_deposit(), NearToken::from_yoctonear(1), "Requires attached deposit of exactly 1 yoctoNEAR");
+        assert!(!force.unwrap_or(false), "Forced unregistration is not supported");
+        let account_id = env::predecessor_account_id();
+        let Some(storage) = self.storage_accounts.get(&account_id) else {
+            return false;
+        };
+        assert_eq!(storage.used_bytes, 0, "Cannot unregister while storage is in use");
+        self.storage_accounts.remove(&account_id);
+        self.storage_balances_total = self.storage_balances_total.saturating_sub(storage.balance);
+        self.storage_locked_bytes = self.storage_locked_bytes.saturating_sub(STORAGE_REGISTRATION_BYTES);
+        Promise::new(account_id).transfer(storage.balance.saturating_add(NearToken::from_yoctonear(1)));
+        true
+    }
+    
+    pub fn storage_balance_bounds(&self) -> StorageBalanceBounds {
+        StorageBalanceBounds { min: yocto(registration_cost()), max: None }
+    }
+    
+    pub fn storage_balance_of(&self, account_id: AccountId) -> Option<StorageBalance> {
+        self.storage_accounts.get(&account_id).map(|storage| storage.to_json())
+    }
+}
+
+impl UtxoMixer {
+    /// Guarda el depositante de una nota si está registrado y su saldo disponible cubre la entrada;
+    /// los bytes usados se cobran a su saldo
+    pub(crate) fn record_depositor(&mut self, commitment_hash: &String, depositor: &AccountId) {
+        let Some(mut storage) = self.storage_accounts.get(depositor) else {
+            return;
+        };
+        let usage_before = env::storage_usage();
+        self.depositors.insert(commitment_hash, depositor);
+        let used = env::storage_usage().saturating_sub(usage_before);
+        if env::storage_byte_cost().saturating_mul(u128::from(used)) > storage.available() {
+            self.depositors.remove(commitment_hash);
+            return;
+        }
+        storage.used_bytes += used;
+        self.storage_locked_bytes += used;
+        self.storage_accounts.insert(depositor, &storage);
+    }
+    
+    /// Elimina el registro de depositante de una nota y libera los bytes en el saldo del depositante
+    pub(crate) fn remove_depositor(&mut self, commitment_hash: &String) {
+        let usage_before = env::storage_usage();
+        let Some(depositor) = self.depositors.remove(commitment_hash) else {
+            return;
+        };
+        let freed = usage_before.saturating_sub(env::storage_usage());
+        if let Some(mut storage) = self.storage_accounts.get(&depositor) {
+            let freed = freed.min(storage.used_bytes);
+            storage.used_bytes -= freed;
+            self.storage_locked_bytes = self.storage_locked_bytes.saturating_sub(freed);
+            self.storage_accounts.insert(&depositor, &storage);
+        }
+    }
+    
+    /// Parte disponible de los saldos de almacenamiento de todas las cuentas registradas: la que
+    /// no paga bytes ya guardados
+    pub(crate) fn storage_available_total(&self) -> NearToken {
+        self.storage_balances_total
+            .saturating_sub(env::storage_byte_cost().saturating_mul(u128::from(self.storage_locked_bytes)))
+    }
+}
@@ -0,0 +1,3315 @@
+use super::*;
+use near_sdk::mock::MockAction;
+use near_sdk::test_utils::{accounts, get_created_receipts, get_logs, VMContextBuilder};
+use near_sdk::{testing_env, PromiseResult, RuntimeFeesConfig};
+
+fn context(predecessor: AccountId) -> VMContextBuilder {
+    let mut builder = VMContextBuilder::new();
+    builder
+        .current_account_id("mixer.testnet".parse().unwrap())
+        .predecessor_account_id(predecessor);
+    builder
+}
+
+fn setup(fee_basis_points: u16) -> (VMContextBuilder, UtxoMixer) {
+    let ctx = context(accounts(0));
+    testing_env!(ctx.build());
+    let contract = UtxoMixer::new(accounts(0), fee_basis_points, None, None, None);
+    (ctx, contract)
+}
+
+fn deposit_as(ctx: &mut VMContextBuilder, contract: &mut UtxoMixer, depositor: AccountId, secret: &str, amount: NearToken) {
+    testing_env!(ctx.predecessor_account_id(depositor).attached_deposit(amount).build());
+    contract.deposit(commitment_for(secret), None, None, None, None);
+}
+
+fn withdraw_after_delay(ctx: &mut VMContextBuilder, contract: &mut UtxoMixer, recipient: AccountId, secret: &str) {
+    testing_env!(ctx
+        .attached_deposit(NearToken::from_yoctonear(0))
+        .block_timestamp(env::block_timestamp() + MIN_DELAY * 1_000_000_000)
+        .build());
+    contract.withdraw(recipient, secret.to_string(), None, None, None);
+}
+
+fn withdrawal_hash_for(secret: &str) -> String {
+    withdrawal_hash_for_scheme(secret, CURRENT_SCHEME_VERSION, HashScheme::Sha256)
+}
+
+/// Nota del esquema actual, como la pasaría `withdraw` a su callback
+fn note(denomination: NearToken) -> DepositInfo {
+    DepositInfo {
+        denomination,
+        timestamp: 0,
+        recipient_commitment: None,
+        scheme_version: CURRENT_SCHEME_VERSION,
+        fee_mode: FeeMode::AtWithdraw,
+        hash_scheme: HashScheme::Sha256,
+        encrypted_note: None,
+        relay_key: None,
+    }
+}
+
+/// Ejecuta `withdraw_resolve` como lo haría el runtime tras la transferencia al destinatario
+fn resolve_withdrawal(
+    ctx: &mut VMContextBuilder,
+    contract: &mut UtxoMixer,
+    recipient: AccountId,
+    secret: &str,
+    deposit: DepositInfo,
+    fee: NearToken,
+    result: PromiseResult,
+) -> bool {
+    testing_env!(
+        ctx.predecessor_account_id(env::current_account_id()).build(),
+        near_sdk::test_vm_config(),
+        RuntimeFeesConfig::test(),
+        Default::default(),
+        vec![result],
+    );
+    contract.withdraw_resolve(recipient, commitment_for(secret), withdrawal_hash_for(secret), deposit, yocto(fee), None)
+}
+
+/// Transferencias creadas en la última llamada, como (receptor, monto)
+fn transfers() -> Vec<(AccountId, NearToken)> {
+    get_created_receipts()
+        .into_iter()
+        .flat_map(|receipt| {
+            let receiver = receipt.receiver_id.clone();
+            receipt.actions.into_iter().filter_map(move |action| match action {
+                MockAction::Transfer { deposit, .. } => Some((receiver.clone(), deposit)),
+                _ => None,
+            })
+        })
+        .collect()
+}
+
+#[test]
+fn commitment_is_domain_separated_by_contract_account() {
+    let secret = "my_secret_key_123";
+
+    let mut ctx = context(accounts(0));
+    testing_env!(ctx.current_account_id("mixer-a.testnet".parse().unwrap()).build());
+    let contract_a = UtxoMixer::new(accounts(0), 100, None, None, None);
+    let commitment_a = contract_a.compute_commitment(secret.to_string(), None, None);
+
+    testing_env!(ctx.current_account_id("mixer-b.testnet".parse().unwrap()).build());
+    let contract_b = UtxoMixer::new(accounts(0), 100, None, None, None);
+    let commitment_b = contract_b.compute_commitment(secret.to_string(), None, None);
+
+    assert_ne!(commitment_a, commitment_b);
+    // El hash "plano" del secreto ya no es un commitment válido en ningún despliegue
+    let plain = format!("{:x}", Sha256::digest(secret.as_bytes()));
+    assert_ne!(commitment_a, plain);
+    assert_ne!(commitment_b, plain);
+}
+
+#[test]
+#[should_panic(expected = "No deposit found for this secret")]
+fn commitment_from_another_deployment_cannot_be_withdrawn() {
+    let secret = "my_secret_key_123";
+
+    let mut ctx = context(accounts(1));
+    testing_env!(ctx.current_account_id("mixer-a.testnet".parse().unwrap()).build());
+    let foreign_commitment = commitment_for(secret);
+
+    testing_env!(ctx
+        .current_account_id("mixer-b.testnet".parse().unwrap())
+        .attached_deposit(NearToken::from_near(1))
+        .build());
+    let mut contract = UtxoMixer::new(accounts(0), 100, None, None, None);
+    contract.deposit(foreign_commitment, None, None, None, None);
+
+    testing_env!(ctx
+        .attached_deposit(NearToken::from_yoctonear(0))
+        .block_timestamp(MIN_DELAY * 1_000_000_000)
+        .build());
+    contract.withdraw(accounts(2), secret.to_string(), None, None, None);
+}
+
+#[test]
+fn fee_cap_defaults_to_five_percent() {
+    testing_env!(context(accounts(0)).build());
+    let contract = UtxoMixer::new(accounts(0), 500, None, None, None);
+    assert_eq!(contract.get_max_fee_basis_points(), 500);
+}
+
+#[test]
+#[should_panic(expected = "Fee cannot exceed the fee cap")]
+fn new_rejects_fee_above_cap() {
+    testing_env!(context(accounts(0)).build());
+    UtxoMixer::new(accounts(0), 501, None, None, None);
+}
+
+#[test]
+#[should_panic(expected = "Fee cap cannot exceed 20%")]
+fn new_rejects_cap_above_hard_limit() {
+    testing_env!(context(accounts(0)).build());
+    UtxoMixer::new(accounts(0), 100, Some(2001), None, None);
+}
+
+/// Avanza el reloj lo suficiente para que un cambio de comisión programado entre en vigor
+fn pass_fee_change_delay(ctx: &mut VMContextBuilder) {
+    testing_env!(ctx.block_timestamp(env::block_timestamp() + FEE_CHANGE_DELAY * 1_000_000_000).build());
+}
+
+#[test]
+fn fee_setter_validates_against_configured_cap() {
+    let (mut ctx, mut contract) = setup(100);
+    contract.set_max_fee_basis_points(2000);
+    contract.set_fee_basis_points(2000);
+    pass_fee_change_delay(&mut ctx);
+    assert_eq!(contract.get_fee_basis_points(), 2000);
+
+    contract.set_fee_basis_points(100);
+    pass_fee_change_delay(&mut ctx);
+    contract.set_max_fee_basis_points(300);
+    assert_eq!(contract.get_max_fee_basis_points(), 300);
+    contract.set_fee_basis_points(300);
+    pass_fee_change_delay(&mut ctx);
+    assert_eq!(contract.get_fee_basis_points(), 300);
+}
+
+#[test]
+#[should_panic(expected = "Fee cannot exceed the fee cap")]
+fn fee_setter_rejects_fee_one_above_cap() {
+    testing_env!(context(accounts(0)).build());
+    let mut contract = UtxoMixer::new(accounts(0), 100, Some(1000), None, None);
+    contract.set_fee_basis_points(1001);
+}
+
+#[test]
+#[should_panic(expected = "Fee cap cannot be below the current fee")]
+fn fee_cap_cannot_drop_below_current_fee() {
+    testing_env!(context(accounts(0)).build());
+    let mut contract = UtxoMixer::new(accounts(0), 300, None, None, None);
+    contract.set_max_fee_basis_points(299);
+}
+
+#[test]
+#[should_panic(expected = "Only the owner can call this method")]
+fn fee_cap_setter_is_owner_only() {
+    testing_env!(context(accounts(1)).build());
+    let mut contract = UtxoMixer::new(accounts(0), 100, None, None, None);
+    contract.set_max_fee_basis_points(1000);
+}
+
+#[test]
+fn pool_stats_on_fresh_contract_are_zero() {
+    let (_, contract) = setup(100);
+    let (total_deposits, total_amount, by_denomination) = contract.get_pool_stats();
+    assert_eq!(total_deposits, 0);
+    assert_eq!(total_amount, U128(0));
+    // Misma forma que get_all_denomination_stats: una fila en cero por denominación
+    assert_eq!(by_denomination, contract.get_all_denomination_stats());
+    assert_eq!(by_denomination.len(), DENOMINATIONS.len());
+    assert!(by_denomination.iter().all(|(_, count)| *count == 0));
+}
+
+#[test]
+fn pool_stats_track_active_deposits() {
+    let (mut ctx, mut contract) = setup(100);
+    deposit_as(&mut ctx, &mut contract, accounts(1), "secret-a", NearToken::from_near(1));
+    deposit_as(&mut ctx, &mut contract, accounts(1), "secret-b", NearToken::from_near(10));
+
+    let (total_deposits, total_amount, by_denomination) = contract.get_pool_stats();
+    assert_eq!(total_deposits, 2);
+    assert_eq!(total_amount, yocto(NearToken::from_near(11)));
+    assert_eq!(by_denomination[0], (NearToken::from_near(1), 1));
+    assert_eq!(by_denomination[1], (NearToken::from_near(10), 1));
+
+    withdraw_after_delay(&mut ctx, &mut contract, accounts(2), "secret-a");
+    let (total_deposits, total_amount, _) = contract.get_pool_stats();
+    assert_eq!(total_deposits, 1);
+    assert_eq!(total_amount, yocto(NearToken::from_near(10)));
+}
+
+#[test]
+fn verify_merkle_path_accepts_correct_path() {
+    let (_, contract) = setup(100);
+    let leaves: Vec<String> = ["a", "b", "c", "d"].iter().map(|secret| commitment_for(secret)).collect();
+    let left = merkle::hash_pair(&leaves[0], &leaves[1]);
+    let right = merkle::hash_pair(&leaves[2], &leaves[3]);
+    let root = merkle::hash_pair(&left, &right);
+
+    // Hoja "c": hijo izquierdo en el nivel 0, subárbol derecho en el nivel 1
+    let path = vec![leaves[3].clone(), left.clone()];
+    assert!(contract.verify_merkle_path(leaves[2].clone(), path, vec![0, 1], root.clone()));
+
+    let path = vec![leaves[0].clone(), right];
+    assert!(contract.verify_merkle_path(leaves[1].clone(), path, vec![1, 0], root));
+}
+
+#[test]
+fn verify_merkle_path_rejects_tampered_path() {
+    let (_, contract) = setup(100);
+    let leaves: Vec<String> = ["a", "b", "c", "d"].iter().map(|secret| commitment_for(secret)).collect();
+    let left = merkle::hash_pair(&leaves[0], &leaves[1]);
+    let right = merkle::hash_pair(&leaves[2], &leaves[3]);
+    let root = merkle::hash_pair(&left, &right);
+
+    // Hermano alterado
+    let tampered = vec![commitment_for("x"), left.clone()];
+    assert!(!contract.verify_merkle_path(leaves[2].clone(), tampered, vec![0, 1], root.clone()));
+    // Índices invertidos
+    let path = vec![leaves[3].clone(), left.clone()];
+    assert!(!contract.verify_merkle_path(leaves[2].clone(), path.clone(), vec![1, 1], root.clone()));
+    // Índice inválido y longitudes distintas
+    assert!(!contract.verify_merkle_path(leaves[2].clone(), path.clone(), vec![0, 2], root.clone()));
+    assert!(!contract.verify_merkle_path(leaves[2].clone(), path, vec![0], root));
+}
+
+#[test]
+fn withdrawal_charges_fee_to_owner() {
+    let (mut ctx, mut contract) = setup(100);
+    deposit_as(&mut ctx, &mut contract, accounts(1), "secret", NearToken::from_near(10));
+    withdraw_after_delay(&mut ctx, &mut contract, accounts(2), "secret");
+    assert_eq!(transfers(), vec![(accounts(2), NearToken::from_millinear(9_900))]);
+
+    let deposit = note(NearToken::from_near(10));
+    let fee = NearToken::from_millinear(100);
+    assert!(resolve_withdrawal(&mut ctx, &mut contract, accounts(2), "secret", deposit, fee, PromiseResult::Successful(vec![])));
+    assert!(transfers().is_empty());
+    assert_eq!(contract.get_sweepable_fees(), yocto(fee));
+}
+
+#[test]
+fn zero_fee_withdrawal_pays_full_denomination_without_owner_transfer() {
+    let (mut ctx, mut contract) = setup(0);
+    deposit_as(&mut ctx, &mut contract, accounts(1), "secret", NearToken::from_near(10));
+    withdraw_after_delay(&mut ctx, &mut contract, accounts(2), "secret");
+    // Una sola transferencia, por la denominación completa, y ninguna de valor cero al owner
+    assert_eq!(transfers(), vec![(accounts(2), NearToken::from_near(10))]);
+
+    let deposit = note(NearToken::from_near(10));
+    let fee = NearToken::from_yoctonear(0);
+    assert!(resolve_withdrawal(&mut ctx, &mut contract, accounts(2), "secret", deposit, fee, PromiseResult::Successful(vec![])));
+    assert!(transfers().is_empty());
+    assert_eq!(contract.get_sweepable_fees(), U128(0));
+}
+
+#[test]
+fn fee_exempt_recipient_receives_full_denomination() {
+    let (mut ctx, mut contract) = setup(100);
+    contract.add_fee_exempt_recipient(accounts(2));
+    assert!(contract.is_fee_exempt(accounts(2)));
+    assert!(!contract.is_fee_exempt(accounts(3)));
+
+    deposit_as(&mut ctx, &mut contract, accounts(1), "secret", NearToken::from_near(10));
+    withdraw_after_delay(&mut ctx, &mut contract, accounts(2), "secret");
+    assert_eq!(transfers(), vec![(accounts(2), NearToken::from_near(10))]);
+}
+
+#[test]
+fn fee_exemption_can_be_revoked() {
+    let (_, mut contract) = setup(100);
+    contract.add_fee_exempt_recipient(accounts(2));
+    contract.remove_fee_exempt_recipient(accounts(2));
+    assert!(!contract.is_fee_exempt(accounts(2)));
+}
+
+#[test]
+#[should_panic(expected = "Only the owner can call this method")]
+fn fee_exemption_is_owner_only() {
+    let (mut ctx, mut contract) = setup(100);
+    testing_env!(ctx.predecessor_account_id(accounts(1)).build());
+    contract.add_fee_exempt_recipient(accounts(1));
+}
+
+#[test]
+fn recipient_bound_deposit_withdraws_to_bound_recipient() {
+    let (mut ctx, mut contract) = setup(0);
+    testing_env!(ctx.predecessor_account_id(accounts(1)).attached_deposit(NearToken::from_near(1)).build());
+    let binding = contract.compute_recipient_commitment("secret".to_string(), accounts(2));
+    contract.deposit(commitment_for("secret"), Some(binding), None, None, None);
+
+    withdraw_after_delay(&mut ctx, &mut contract, accounts(2), "secret");
+    assert_eq!(transfers(), vec![(accounts(2), NearToken::from_near(1))]);
+}
+
+#[test]
+fn withdrawal_is_safe_only_for_recipient_bound_notes() {
+    let (mut ctx, mut contract) = setup(100);
+    deposit_as(&mut ctx, &mut contract, accounts(1), "unbound", NearToken::from_near(1));
+    let binding = contract.compute_recipient_commitment("bound".to_string(), accounts(2));
+    contract.deposit(commitment_for("bound"), Some(binding), None, None, None);
+    
+    assert!(!contract.is_withdrawal_safe("unbound".to_string(), accounts(2)));
+    assert!(contract.is_withdrawal_safe("bound".to_string(), accounts(2)));
+    // Hacia otra cuenta el retiro fallaría, y una nota inexistente no se puede retirar
+    assert!(!contract.is_withdrawal_safe("bound".to_string(), accounts(3)));
+    assert!(!contract.is_withdrawal_safe("missing".to_string(), accounts(2)));
+}
+
+#[test]
+#[should_panic(expected = "Recipient does not match the one bound at deposit")]
+fn front_runner_cannot_redirect_bound_withdrawal() {
+    let (mut ctx, mut contract) = setup(0);
+    testing_env!(ctx.predecessor_account_id(accounts(1)).attached_deposit(NearToken::from_near(1)).build());
+    contract.deposit(commitment_for("secret"), Some(recipient_commitment_for("secret", &accounts(2))), None, None, None);
+
+    // Un observador copia el secreto de la transacción pendiente y cambia el destinatario
+    testing_env!(ctx.predecessor_account_id(accounts(3)).build());
+    withdraw_after_delay(&mut ctx, &mut contract, accounts(3), "secret");
+}
+
+#[test]
+#[should_panic(expected = "Not enough gas attached, deposit requires at least 10 Tgas")]
+fn deposit_rejects_gas_below_minimum() {
+    let (mut ctx, mut contract) = setup(100);
+    ctx.prepaid_gas(MIN_DEPOSIT_GAS.saturating_sub(Gas::from_gas(1)));
+    deposit_as(&mut ctx, &mut contract, accounts(1), "secret", NearToken::from_near(1));
+}
+
+#[test]
+fn deposit_with_minimum_gas_is_accepted() {
+    let (mut ctx, mut contract) = setup(100);
+    ctx.prepaid_gas(MIN_DEPOSIT_GAS.saturating_add(Gas::from_tgas(1)));
+    deposit_as(&mut ctx, &mut contract, accounts(1), "secret", NearToken::from_near(1));
+    assert_eq!(contract.get_total_deposits(), 1);
+}
+
+#[test]
+#[should_panic(expected = "Not enough gas attached, withdraw requires at least 30 Tgas")]
+fn withdraw_rejects_gas_below_callback_reserve() {
+    let (mut ctx, mut contract) = setup(100);
+    deposit_as(&mut ctx, &mut contract, accounts(1), "secret", NearToken::from_near(1));
+    ctx.prepaid_gas(MIN_WITHDRAW_GAS.saturating_sub(Gas::from_gas(1)));
+    withdraw_after_delay(&mut ctx, &mut contract, accounts(2), "secret");
+}
+
+#[test]
+fn withdraw_with_borderline_gas_completes_callback() {
+    let (mut ctx, mut contract) = setup(100);
+    deposit_as(&mut ctx, &mut contract, accounts(1), "secret", NearToken::from_near(1));
+    ctx.prepaid_gas(MIN_WITHDRAW_GAS.saturating_add(Gas::from_tgas(1)));
+    withdraw_after_delay(&mut ctx, &mut contract, accounts(2), "secret");
+
+    let callback = get_created_receipts()
+        .into_iter()
+        .flat_map(|receipt| receipt.actions)
+        .find_map(|action| match action {
+            MockAction::FunctionCallWeight { method_name, prepaid_gas, .. } => Some((method_name, prepaid_gas)),
+            _ => None,
+        })
+        .expect("withdraw must schedule its resolve callback");
+    assert_eq!(callback, (b"withdraw_resolve".to_vec(), WITHDRAW_RESOLVE_GAS));
+
+    let deposit = note(NearToken::from_near(1));
+    let fee = NearToken::from_millinear(10);
+    assert!(resolve_withdrawal(&mut ctx, &mut contract, accounts(2), "secret", deposit, fee, PromiseResult::Successful(vec![])));
+}
+
+#[test]
+fn failed_transfer_restores_deposit() {
+    let (mut ctx, mut contract) = setup(100);
+    deposit_as(&mut ctx, &mut contract, accounts(1), "secret", NearToken::from_near(1));
+    withdraw_after_delay(&mut ctx, &mut contract, accounts(2), "secret");
+    assert_eq!(contract.get_pool_stats().0, 0);
+
+    let deposit = note(NearToken::from_near(1));
+    let fee = NearToken::from_millinear(10);
+    assert!(!resolve_withdrawal(&mut ctx, &mut contract, accounts(2), "secret", deposit, fee, PromiseResult::Failed));
+    assert!(transfers().is_empty());
+    assert_eq!(get_logs()[0], format!("Withdrawal to {} failed, deposit restored", accounts(2)));
+    assert_eq!(contract.get_pool_stats().0, 1);
+
+    // El depósito restaurado puede retirarse de nuevo
+    testing_env!(ctx.predecessor_account_id(accounts(1)).build());
+    withdraw_after_delay(&mut ctx, &mut contract, accounts(3), "secret");
+}
+
+/// Acredita comisiones como lo haría una serie de retiros resueltos con éxito
+fn accumulate_fees(ctx: &mut VMContextBuilder, contract: &mut UtxoMixer, amount: NearToken) {
+    let deposit = note(NearToken::from_near(1));
+    resolve_withdrawal(ctx, contract, accounts(2), "unused", deposit, amount, PromiseResult::Successful(vec![]));
+    testing_env!(ctx.predecessor_account_id(accounts(0)).build());
+}
+
+#[test]
+fn should_sweep_fees_requires_fees_above_gas_cost() {
+    let (mut ctx, mut contract) = setup(100);
+    assert!(!contract.should_sweep_fees(yocto(NearToken::from_yoctonear(0))));
+
+    accumulate_fees(&mut ctx, &mut contract, NearToken::from_millinear(10));
+    assert!(contract.should_sweep_fees(yocto(NearToken::from_millinear(9))));
+    assert!(!contract.should_sweep_fees(yocto(NearToken::from_millinear(10))));
+    assert!(!contract.should_sweep_fees(yocto(NearToken::from_millinear(11))));
+}
+
+#[test]
+fn should_sweep_fees_respects_threshold_boundary() {
+    let (mut ctx, mut contract) = setup(100);
+    accumulate_fees(&mut ctx, &mut contract, NearToken::from_millinear(100));
+    contract.set_fee_sweep_threshold(yocto(NearToken::from_millinear(90)));
+    assert_eq!(contract.get_fee_sweep_threshold(), yocto(NearToken::from_millinear(90)));
+
+    // Ganancia neta exactamente en el umbral, justo por encima y justo por debajo
+    assert!(contract.should_sweep_fees(yocto(NearToken::from_millinear(10))));
+    assert!(contract.should_sweep_fees(yocto(NearToken::from_millinear(9))));
+    assert!(!contract.should_sweep_fees(yocto(NearToken::from_yoctonear(NearToken::from_millinear(10).as_yoctonear() + 1))));
+}
+
+#[test]
+fn withdraw_fees_transfers_to_owner() {
+    let (mut ctx, mut contract) = setup(100);
+    accumulate_fees(&mut ctx, &mut contract, NearToken::from_millinear(100));
+    contract.withdraw_fees(yocto(NearToken::from_millinear(40)));
+    assert_eq!(transfers(), vec![(accounts(0), NearToken::from_millinear(40))]);
+    assert_eq!(contract.get_sweepable_fees(), yocto(NearToken::from_millinear(60)));
+}
+
+#[test]
+#[should_panic(expected = "Amount exceeds accumulated fees")]
+fn withdraw_fees_cannot_exceed_accumulated() {
+    let (mut ctx, mut contract) = setup(100);
+    accumulate_fees(&mut ctx, &mut contract, NearToken::from_millinear(100));
+    contract.withdraw_fees(yocto(NearToken::from_millinear(101)));
+}
+
+#[test]
+#[should_panic(expected = "Withdrawal would leave deposits unbacked")]
+fn withdraw_fees_never_touches_deposit_backing() {
+    let (mut ctx, mut contract) = setup(100);
+    deposit_as(&mut ctx, &mut contract, accounts(1), "secret", NearToken::from_near(10));
+    accumulate_fees(&mut ctx, &mut contract, NearToken::from_near(1));
+    // El balance solo cubre los depósitos activos
+    testing_env!(ctx.attached_deposit(NearToken::from_yoctonear(0)).account_balance(NearToken::from_near(10)).build());
+    contract.withdraw_fees(yocto(NearToken::from_near(1)));
+}
+
+#[test]
+#[should_panic(expected = "Deposit must be one of the accepted denominations")]
+fn flexible_amount_rejected_while_pool_disabled() {
+    let (mut ctx, mut contract) = setup(100);
+    deposit_as(&mut ctx, &mut contract, accounts(1), "secret", NearToken::from_millinear(3_700));
+}
+
+#[test]
+#[should_panic(expected = "Deposit is below the flexible pool minimum")]
+fn flexible_amount_below_minimum_rejected() {
+    let (mut ctx, mut contract) = setup(100);
+    contract.set_flexible_pool_enabled(true);
+    contract.set_flexible_pool_min(yocto(NearToken::from_near(2)));
+    deposit_as(&mut ctx, &mut contract, accounts(1), "secret", NearToken::from_millinear(1_999));
+}
+
+#[test]
+fn flexible_pool_deposit_and_withdraw_arbitrary_amount() {
+    let (mut ctx, mut contract) = setup(100);
+    contract.set_flexible_pool_enabled(true);
+    let amount = NearToken::from_millinear(3_700);
+
+    deposit_as(&mut ctx, &mut contract, accounts(1), "secret", amount);
+    assert!(get_logs()[0].contains("flexible pool"));
+    // Las notas flexibles tienen su propio mapa, separado del de los pools fijos
+    assert!(contract.versioned_flexible_deposits.get(&commitment_for("secret")).is_some());
+    assert!(contract.versioned_deposits.get(&commitment_for("secret")).is_none());
+    assert_eq!(contract.get_flexible_pool_info(), (true, yocto(NearToken::from_near(1)), 1, yocto(amount)));
+    // Los pools fijos no cambian; las obligaciones sí incluyen el pool flexible
+    assert_eq!(contract.get_pool_stats().0, 0);
+    assert_eq!(contract.get_obligations(), yocto(amount));
+
+    withdraw_after_delay(&mut ctx, &mut contract, accounts(2), "secret");
+    assert_eq!(transfers(), vec![(accounts(2), NearToken::from_millinear(3_663))]);
+    assert_eq!(contract.get_flexible_pool_info().2, 0);
+    assert_eq!(contract.get_obligations(), U128(0));
+}
+
+#[test]
+fn rekey_moves_flexible_notes_out_of_the_fixed_map() {
+    let (mut ctx, mut contract) = setup(100);
+    contract.set_flexible_pool_enabled(true);
+    let amount = NearToken::from_millinear(3_700);
+    deposit_as(&mut ctx, &mut contract, accounts(1), "secret", amount);
+    
+    // Una nota flexible guardada en el mapa de los pools fijos, como hacían versiones anteriores
+    let commitment = commitment_for("secret");
+    let stored = contract.versioned_flexible_deposits.remove(&commitment).unwrap();
+    contract.versioned_deposits.insert(&commitment, &stored);
+    
+    testing_env!(ctx.predecessor_account_id(accounts(0)).attached_deposit(NearToken::from_yoctonear(0)).build());
+    assert_eq!(contract.rekey_deposits(vec![commitment.clone()], false), 1);
+    assert!(contract.versioned_deposits.get(&commitment).is_none());
+    assert!(contract.versioned_flexible_deposits.get(&commitment).is_some());
+    assert_eq!(contract.get_flexible_pool_info().2, 1);
+    assert_eq!(contract.rekey_deposits(vec![commitment], false), 0);
+}
+
+#[test]
+#[should_panic(expected = "Commitment already exists")]
+fn commitment_is_unique_across_fixed_and_flexible_pools() {
+    let (mut ctx, mut contract) = setup(100);
+    contract.set_flexible_pool_enabled(true);
+    deposit_as(&mut ctx, &mut contract, accounts(1), "secret", NearToken::from_near(1));
+    deposit_as(&mut ctx, &mut contract, accounts(1), "secret", NearToken::from_millinear(3_700));
+}
+
+#[test]
+fn self_recipient_rejected_before_nullifier_is_consumed() {
+    let (mut ctx, mut contract) = setup(100);
+    deposit_as(&mut ctx, &mut contract, accounts(1), "secret", NearToken::from_near(1));
+
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        withdraw_after_delay(&mut ctx, &mut contract, env::current_account_id(), "secret");
+    }));
+    assert!(result.is_err());
+    assert!(!contract.spent_outputs.contains(&withdrawal_hash_for("secret")));
+    assert_eq!(contract.get_pool_stats().0, 1);
+
+    // La nota sigue siendo retirable hacia un destinatario válido
+    withdraw_after_delay(&mut ctx, &mut contract, accounts(2), "secret");
+}
+
+#[test]
+#[should_panic(expected = "The mixer contract cannot be the recipient")]
+fn withdraw_to_contract_account_panics() {
+    let (mut ctx, mut contract) = setup(100);
+    deposit_as(&mut ctx, &mut contract, accounts(1), "secret", NearToken::from_near(1));
+    withdraw_after_delay(&mut ctx, &mut contract, env::current_account_id(), "secret");
+}
+
+#[test]
+fn deposits_in_range_filter_by_time_window() {
+    let (mut ctx, mut contract) = setup(100);
+    for (i, amount) in [1, 10, 1, 100].iter().enumerate() {
+        ctx.block_timestamp(1_000 * (i as u64 + 1));
+        deposit_as(&mut ctx, &mut contract, accounts(1), &format!("secret-{}", i), NearToken::from_near(*amount));
+    }
+    assert_eq!(contract.get_deposit_history_length(), 4);
+
+    let rows = contract.get_deposits_in_range(2_000, 3_000, 0, 10);
+    assert_eq!(rows, vec![(1, 2_000, NearToken::from_near(10)), (2, 3_000, NearToken::from_near(1))]);
+
+    // Paginación por índice y por límite
+    assert_eq!(contract.get_deposits_in_range(0, u64::MAX, 2, 10).len(), 2);
+    assert_eq!(contract.get_deposits_in_range(0, u64::MAX, 0, 1), vec![(0, 1_000, NearToken::from_near(1))]);
+    assert!(contract.get_deposits_in_range(5_000, 6_000, 0, 10).is_empty());
+}
+
+#[test]
+fn deposits_in_range_scan_is_bounded() {
+    let (mut ctx, mut contract) = setup(100);
+    for i in 0..(MAX_HISTORY_SCAN + 5) {
+        if i == MAX_HISTORY_SCAN {
+            ctx.block_timestamp(1_000);
+        }
+        deposit_as(&mut ctx, &mut contract, accounts(1), &format!("secret-{}", i), NearToken::from_near(1));
+    }
+    // Las coincidencias que están más allá de `MAX_HISTORY_SCAN` entradas requieren otra llamada
+    assert!(contract.get_deposits_in_range(1_000, u64::MAX, 0, u64::MAX).is_empty());
+    let rest = contract.get_deposits_in_range(1_000, u64::MAX, MAX_HISTORY_SCAN, u64::MAX);
+    assert_eq!(rest.len(), 5);
+}
+
+#[test]
+fn fee_change_only_applies_after_delay() {
+    let (mut ctx, mut contract) = setup(100);
+    ctx.block_timestamp(1_000);
+    testing_env!(ctx.build());
+    contract.set_fee_basis_points(500);
+    let effective_at = 1_000 + FEE_CHANGE_DELAY * 1_000_000_000;
+    assert_eq!(contract.get_pending_fee(), Some((500, effective_at)));
+    assert_eq!(contract.get_fee_basis_points(), 100);
+
+    // Un retiro antes del plazo paga la comisión anterior
+    deposit_as(&mut ctx, &mut contract, accounts(1), "early", NearToken::from_near(10));
+    withdraw_after_delay(&mut ctx, &mut contract, accounts(2), "early");
+    assert!(env::block_timestamp() < effective_at);
+    assert_eq!(transfers(), vec![(accounts(2), NearToken::from_millinear(9_900))]);
+
+    // Pasado el plazo aplica la nueva comisión y deja de estar pendiente
+    deposit_as(&mut ctx, &mut contract, accounts(1), "late", NearToken::from_near(10));
+    testing_env!(ctx.block_timestamp(effective_at).build());
+    assert_eq!(contract.get_pending_fee(), None);
+    assert_eq!(contract.get_fee_basis_points(), 500);
+    withdraw_after_delay(&mut ctx, &mut contract, accounts(2), "late");
+    assert_eq!(transfers(), vec![(accounts(2), NearToken::from_millinear(9_500))]);
+}
+
+#[test]
+#[should_panic(expected = "Fee cap cannot be below the current fee")]
+fn fee_cap_cannot_drop_below_pending_fee() {
+    let (_, mut contract) = setup(100);
+    contract.set_fee_basis_points(400);
+    contract.set_max_fee_basis_points(300);
+}
+
+#[test]
+fn sweep_all_fees_moves_full_balance_to_treasury() {
+    let (mut ctx, mut contract) = setup(100);
+    contract.set_treasury(accounts(4));
+    assert_eq!(contract.get_treasury(), accounts(4));
+    accumulate_fees(&mut ctx, &mut contract, NearToken::from_millinear(70));
+    accumulate_fees(&mut ctx, &mut contract, NearToken::from_millinear(30));
+
+    contract.sweep_all_fees();
+    assert_eq!(transfers(), vec![(accounts(4), NearToken::from_millinear(100))]);
+    assert!(contract.get_sweepable_fees() == U128(0));
+}
+
+#[test]
+#[should_panic(expected = "No fees to withdraw")]
+fn sweep_all_fees_with_empty_balance_panics() {
+    let (_, mut contract) = setup(100);
+    contract.sweep_all_fees();
+}
+
+#[test]
+#[should_panic(expected = "Withdrawal would leave deposits unbacked")]
+fn sweep_all_fees_respects_solvency() {
+    let (mut ctx, mut contract) = setup(100);
+    deposit_as(&mut ctx, &mut contract, accounts(1), "secret", NearToken::from_near(10));
+    accumulate_fees(&mut ctx, &mut contract, NearToken::from_near(1));
+    testing_env!(ctx.attached_deposit(NearToken::from_yoctonear(0)).account_balance(NearToken::from_near(10)).build());
+    contract.sweep_all_fees();
+}
+
+#[test]
+#[should_panic(expected = "Withdrawal would leave deposits unbacked")]
+fn fee_sweep_never_spends_storage_balances() {
+    let (mut ctx, mut contract) = setup(100);
+    deposit_as(&mut ctx, &mut contract, accounts(1), "secret", NearToken::from_near(10));
+    register_storage(&mut ctx, &mut contract, accounts(3), NearToken::from_millinear(100));
+    accumulate_fees(&mut ctx, &mut contract, NearToken::from_near(1));
+    // Al saldo le falta un yoctoNEAR para cubrir todo, así que enviar las comisiones tocaría el saldo
+    // de almacenamiento de la cuenta registrada
+    testing_env!(ctx.attached_deposit(NearToken::from_yoctonear(0)).build());
+    let short = contract.reserved_balance().saturating_sub(NearToken::from_yoctonear(1));
+    testing_env!(ctx.account_balance(short).build());
+    contract.sweep_all_fees();
+}
+
+#[test]
+fn notes_from_older_scheme_withdraw_with_their_own_logic() {
+    let (mut ctx, mut contract) = setup(0);
+    // Nota registrada antes de la actualización, con el esquema 1 (SHA-256 del secreto sin dominio)
+    let legacy = DepositInfo { scheme_version: SCHEME_V1, ..note(NearToken::from_near(1)) };
+    let legacy_commitment = format!("{:x}", Sha256::digest("old-secret".as_bytes()));
+    contract.store_deposit(&legacy_commitment, &legacy);
+
+    // Después de la actualización los depósitos nuevos usan el esquema actual
+    deposit_as(&mut ctx, &mut contract, accounts(1), "new-secret", NearToken::from_near(1));
+    assert_eq!(contract.get_deposit(&commitment_for("new-secret")).unwrap().scheme_version, CURRENT_SCHEME_VERSION);
+
+    withdraw_after_delay(&mut ctx, &mut contract, accounts(2), "old-secret");
+    assert!(contract.spent_outputs.contains(&format!("{:x}", Sha256::digest("withdraw:old-secret".as_bytes()))));
+    assert_eq!(transfers(), vec![(accounts(2), NearToken::from_near(1))]);
+
+    withdraw_after_delay(&mut ctx, &mut contract, accounts(3), "new-secret");
+    assert!(contract.spent_outputs.contains(&withdrawal_hash_for("new-secret")));
+    assert_eq!(contract.get_pool_stats().0, 0);
+}
+
+#[test]
+#[should_panic(expected = "No deposit found for this secret")]
+fn note_is_only_valid_under_its_recorded_scheme() {
+    let (mut ctx, mut contract) = setup(0);
+    // Un commitment del esquema 1 marcado como esquema 2 no debe aceptarse con la lógica de ninguno
+    let legacy_commitment = format!("{:x}", Sha256::digest("old-secret".as_bytes()));
+    contract.store_deposit(&legacy_commitment, &note(NearToken::from_near(1)));
+    withdraw_after_delay(&mut ctx, &mut contract, accounts(2), "old-secret");
+}
+
+#[test]
+fn total_deposits_matches_pool_stats() {
+    let (mut ctx, mut contract) = setup(100);
+    assert_eq!(contract.get_total_deposits(), 0);
+    deposit_as(&mut ctx, &mut contract, accounts(1), "a", NearToken::from_near(1));
+    deposit_as(&mut ctx, &mut contract, accounts(1), "b", NearToken::from_near(10));
+    deposit_as(&mut ctx, &mut contract, accounts(1), "c", NearToken::from_near(100));
+    assert_eq!(contract.get_total_deposits(), 3);
+    assert_eq!(contract.get_total_deposits(), contract.get_pool_stats().0);
+
+    withdraw_after_delay(&mut ctx, &mut contract, accounts(2), "b");
+    assert_eq!(contract.get_total_deposits(), 2);
+    assert_eq!(contract.get_total_deposits(), contract.get_pool_stats().0);
+}
+
+fn split_after_delay(ctx: &mut VMContextBuilder, contract: &mut UtxoMixer, secret: &str, payouts: Vec<(AccountId, NearToken)>) {
+    testing_env!(ctx
+        .attached_deposit(NearToken::from_yoctonear(0))
+        .block_timestamp(env::block_timestamp() + MIN_DELAY * 1_000_000_000)
+        .build());
+    let payouts = payouts.into_iter().map(|(recipient, amount)| (recipient, yocto(amount))).collect();
+    contract.withdraw_split(secret.to_string(), payouts);
+}
+
+#[test]
+fn split_withdrawal_pays_each_recipient() {
+    let (mut ctx, mut contract) = setup(100);
+    deposit_as(&mut ctx, &mut contract, accounts(1), "secret", NearToken::from_near(10));
+    let payouts = vec![(accounts(2), NearToken::from_near(4)), (accounts(3), NearToken::from_millinear(5_900))];
+    split_after_delay(&mut ctx, &mut contract, "secret", payouts.clone());
+    assert_eq!(transfers(), payouts);
+    assert_eq!(contract.get_total_deposits(), 0);
+}
+
+#[test]
+#[should_panic(expected = "Duplicate recipient in split withdrawal")]
+fn split_withdrawal_rejects_duplicate_recipients() {
+    let (mut ctx, mut contract) = setup(100);
+    deposit_as(&mut ctx, &mut contract, accounts(1), "secret", NearToken::from_near(10));
+    let payouts = vec![
+        (accounts(2), NearToken::from_near(4)),
+        (accounts(3), NearToken::from_near(1)),
+        (accounts(2), NearToken::from_millinear(4_900)),
+    ];
+    split_after_delay(&mut ctx, &mut contract, "secret", payouts);
+}
+
+#[test]
+#[should_panic(expected = "Payouts must add up to the amount after fees")]
+fn split_withdrawal_must_distribute_exact_net_amount() {
+    let (mut ctx, mut contract) = setup(100);
+    deposit_as(&mut ctx, &mut contract, accounts(1), "secret", NearToken::from_near(10));
+    split_after_delay(&mut ctx, &mut contract, "secret", vec![(accounts(2), NearToken::from_near(10))]);
+}
+
+#[test]
+#[should_panic(expected = "Recipient-bound notes cannot be split")]
+fn recipient_bound_note_cannot_be_split() {
+    let (mut ctx, mut contract) = setup(0);
+    testing_env!(ctx.predecessor_account_id(accounts(1)).attached_deposit(NearToken::from_near(1)).build());
+    contract.deposit(commitment_for("secret"), Some(recipient_commitment_for("secret", &accounts(2))), None, None, None);
+    split_after_delay(&mut ctx, &mut contract, "secret", vec![(accounts(2), NearToken::from_near(1))]);
+}
+
+#[test]
+fn failed_split_payout_is_kept_for_retry() {
+    let (mut ctx, mut contract) = setup(0);
+    let payouts = vec![
+        (accounts(2), yocto(NearToken::from_near(4))),
+        (accounts(3), yocto(NearToken::from_near(6))),
+    ];
+    testing_env!(
+        ctx.predecessor_account_id(env::current_account_id()).build(),
+        near_sdk::test_vm_config(),
+        RuntimeFeesConfig::test(),
+        Default::default(),
+        vec![PromiseResult::Successful(vec![]), PromiseResult::Failed],
+    );
+    assert!(!contract.payouts_resolve(payouts, U128(0)));
+    assert_eq!(contract.get_failed_payout(accounts(2)), None);
+    assert_eq!(contract.get_failed_payout(accounts(3)), Some(yocto(NearToken::from_near(6))));
+    assert_eq!(contract.get_obligations(), yocto(NearToken::from_near(6)));
+
+    testing_env!(ctx.predecessor_account_id(accounts(4)).build());
+    contract.retry_failed_payout(accounts(3));
+    assert_eq!(transfers(), vec![(accounts(3), NearToken::from_near(6))]);
+    assert_eq!(contract.get_failed_payout(accounts(3)), None);
+}
+
+#[test]
+fn deposit_and_withdraw_emit_nep297_events() {
+    let (mut ctx, mut contract) = setup(100);
+    deposit_as(&mut ctx, &mut contract, accounts(1), "secret", NearToken::from_near(1));
+    let logs = get_logs();
+    assert_eq!(logs.len(), 2);
+    let event: near_sdk::serde_json::Value = near_sdk::serde_json::from_str(logs[1].strip_prefix("EVENT_JSON:").unwrap()).unwrap();
+    assert_eq!(event["standard"], "near-mixer");
+    assert_eq!(event["event"], "deposit");
+    assert_eq!(event["data"][0]["commitment_hash"], commitment_for("secret"));
+    assert_eq!(event["data"][0]["pool"], "fixed");
+
+    withdraw_after_delay(&mut ctx, &mut contract, accounts(2), "secret");
+    let withdraw_event = get_logs().into_iter().find(|log| log.starts_with("EVENT_JSON:")).unwrap();
+    assert!(withdraw_event.contains(r#""event":"withdraw""#));
+    assert!(withdraw_event.contains(&withdrawal_hash_for("secret")));
+}
+
+#[test]
+fn events_carry_the_configured_namespace() {
+    let mut ctx = context(accounts(0));
+    testing_env!(ctx.build());
+    let mut contract = UtxoMixer::new(accounts(0), 100, None, None, Some("near-mixer-usdc".to_string()));
+    assert_eq!(contract.get_config().event_namespace, "near-mixer-usdc");
+    
+    let standards = || -> Vec<String> {
+        get_logs().iter().filter_map(|log| log.strip_prefix("EVENT_JSON:"))
+            .map(|event| near_sdk::serde_json::from_str::<near_sdk::serde_json::Value>(event).unwrap()["standard"].to_string())
+            .collect()
+    };
+    contract.set_verbose_logging(false);
+    assert_eq!(standards(), vec![r#""near-mixer-usdc""#]);
+    deposit_as(&mut ctx, &mut contract, accounts(1), "secret", NearToken::from_near(1));
+    assert_eq!(standards(), vec![r#""near-mixer-usdc""#]);
+    withdraw_after_delay(&mut ctx, &mut contract, accounts(2), "secret");
+    assert_eq!(standards(), vec![r#""near-mixer-usdc""#]);
+    
+    // Sin namespace se usa el de siempre
+    assert_eq!(setup(100).1.get_config().event_namespace, "near-mixer");
+}
+
+#[test]
+fn disabling_verbose_logging_keeps_only_events() {
+    let (mut ctx, mut contract) = setup(100);
+    assert!(contract.get_verbose_logging());
+    contract.set_verbose_logging(false);
+    assert!(!contract.get_verbose_logging());
+
+    deposit_as(&mut ctx, &mut contract, accounts(1), "secret", NearToken::from_near(1));
+    let logs = get_logs();
+    assert_eq!(logs.len(), 1);
+    assert!(logs.iter().all(|log| log.starts_with("EVENT_JSON:")));
+
+    withdraw_after_delay(&mut ctx, &mut contract, accounts(2), "secret");
+    let logs = get_logs();
+    assert_eq!(logs.len(), 1);
+    assert!(logs.iter().all(|log| log.starts_with("EVENT_JSON:")));
+}
+
+#[test]
+fn denomination_fee_override_applies_after_delay() {
+    let (mut ctx, mut contract) = setup(100);
+    contract.set_denomination_fee(NearToken::from_near(100), Some(50));
+    assert_eq!(contract.get_denomination_fee(NearToken::from_near(100)), None);
+    assert_eq!(contract.get_effective_fee(NearToken::from_near(100), accounts(2), None, None), yocto(NearToken::from_near(1)));
+
+    pass_fee_change_delay(&mut ctx);
+    assert_eq!(contract.get_denomination_fee(NearToken::from_near(100)), Some(50));
+    assert_eq!(contract.get_effective_fee(NearToken::from_near(100), accounts(2), None, None), yocto(NearToken::from_millinear(500)));
+    // Las demás denominaciones siguen con la comisión global
+    assert_eq!(contract.get_effective_fee(NearToken::from_near(10), accounts(2), None, None), yocto(NearToken::from_millinear(100)));
+}
+
+#[test]
+fn effective_fee_combines_exemption_and_denomination_override() {
+    let (mut ctx, mut contract) = setup(100);
+    contract.set_denomination_fee(NearToken::from_near(10), Some(300));
+    contract.add_fee_exempt_recipient(accounts(3));
+    pass_fee_change_delay(&mut ctx);
+
+    // La exención prevalece sobre la comisión específica
+    assert_eq!(contract.get_effective_fee(NearToken::from_near(10), accounts(3), None, None), yocto(NearToken::from_yoctonear(0)));
+    assert_eq!(contract.get_effective_fee(NearToken::from_near(10), accounts(2), None, None), yocto(NearToken::from_millinear(300)));
+
+    // La cotización coincide con lo que cobra withdraw
+    deposit_as(&mut ctx, &mut contract, accounts(1), "secret", NearToken::from_near(10));
+    let quote = contract.get_effective_fee(NearToken::from_near(10), accounts(2), None, None);
+    withdraw_after_delay(&mut ctx, &mut contract, accounts(2), "secret");
+    assert_eq!(transfers(), vec![(accounts(2), NearToken::from_near(10).saturating_sub(near_token(quote)))]);
+}
+
+#[test]
+fn denomination_fee_override_can_be_removed() {
+    let (mut ctx, mut contract) = setup(100);
+    contract.set_denomination_fee(NearToken::from_near(1), Some(0));
+    pass_fee_change_delay(&mut ctx);
+    assert_eq!(contract.get_effective_fee(NearToken::from_near(1), accounts(2), None, None), yocto(NearToken::from_yoctonear(0)));
+
+    contract.set_denomination_fee(NearToken::from_near(1), None);
+    pass_fee_change_delay(&mut ctx);
+    assert_eq!(contract.get_denomination_fee(NearToken::from_near(1)), None);
+    assert_eq!(contract.get_effective_fee(NearToken::from_near(1), accounts(2), None, None), yocto(NearToken::from_millinear(10)));
+}
+
+#[test]
+#[should_panic(expected = "Fee cap cannot be below the current fee")]
+fn fee_cap_cannot_drop_below_denomination_fee() {
+    let (_, mut contract) = setup(100);
+    contract.set_denomination_fee(NearToken::from_near(1), Some(400));
+    contract.set_max_fee_basis_points(300);
+}
+
+#[test]
+fn migrate_from_legacy_state_keeps_existing_notes() {
+    let (mut ctx, _) = setup(0);
+    // Estado y depósito con el formato de la primera versión
+    let mut legacy = migration::LegacyUtxoMixer {
+        deposits: LookupMap::new(b"d"),
+        spent_outputs: UnorderedSet::new(b"s"),
+        owner: accounts(0),
+        fee_basis_points: 0,
+        deposit_counts: LookupMap::new(b"c"),
+    };
+    let legacy_commitment = format!("{:x}", Sha256::digest("old-secret".as_bytes()));
+    let legacy_deposit = (NearToken::from_near(1), 0u64);
+    env::storage_write(&[b"d".as_slice(), &borsh::to_vec(&legacy_commitment).unwrap()].concat(),
+        &borsh::to_vec(&legacy_deposit).unwrap());
+    legacy.deposit_counts.insert(&NearToken::from_near(1), &1);
+    legacy.spent_outputs.insert(&"spent".to_string());
+    env::state_write(&legacy);
+
+    let mut contract = UtxoMixer::migrate();
+    assert_eq!(contract.get_total_deposits(), 1);
+    assert!(contract.spent_outputs.contains(&"spent".to_string()));
+    assert_eq!(contract.spent_outputs.len(), 1);
+    assert_eq!(contract.get_deposit(&legacy_commitment).unwrap().scheme_version, SCHEME_V1);
+
+    withdraw_after_delay(&mut ctx, &mut contract, accounts(2), "old-secret");
+    assert_eq!(transfers(), vec![(accounts(2), NearToken::from_near(1))]);
+}
+
+#[test]
+fn migrated_note_is_spent_once_under_its_own_scheme() {
+    let (mut ctx, _) = setup(0);
+    let mut legacy = migration::LegacyUtxoMixer {
+        deposits: LookupMap::new(b"d"),
+        spent_outputs: UnorderedSet::new(b"s"),
+        owner: accounts(0),
+        fee_basis_points: 0,
+        deposit_counts: LookupMap::new(b"c"),
+    };
+    let legacy_commitment = format!("{:x}", Sha256::digest("old-secret".as_bytes()));
+    env::storage_write(&[b"d".as_slice(), &borsh::to_vec(&legacy_commitment).unwrap()].concat(),
+        &borsh::to_vec(&(NearToken::from_near(1), 0u64)).unwrap());
+    legacy.deposit_counts.insert(&NearToken::from_near(1), &1);
+    env::state_write(&legacy);
+    let mut contract = UtxoMixer::migrate();
+    
+    withdraw_after_delay(&mut ctx, &mut contract, accounts(2), "old-secret");
+    let v1_nullifier = withdrawal_hash_for_scheme("old-secret", SCHEME_V1, HashScheme::Sha256);
+    assert!(contract.spent_outputs.contains(&v1_nullifier));
+    assert!(!contract.spent_outputs.contains(&withdrawal_hash_for("old-secret")));
+    
+    // Con el esquema actual no hay nota que gastar
+    let current = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        withdraw_after_delay(&mut ctx, &mut contract, accounts(2), "old-secret")
+    }));
+    assert!(current.is_err());
+    // Y si la nota del esquema 1 reapareciera, su hash de retiro ya está gastado
+    let legacy_note = DepositInfo { scheme_version: SCHEME_V1, ..note(NearToken::from_near(1)) };
+    contract.store_deposit(&legacy_commitment, &legacy_note);
+    assert_eq!(contract.check_note("old-secret", Some(&accounts(2)), true).err().as_deref(),
+        Some("This secret has already been used"));
+}
+
+#[test]
+fn v2_and_v3_notes_of_one_secret_have_separate_nullifiers() {
+    let (mut ctx, mut contract) = setup(0);
+    // Nota del esquema 2, como la deja una migración, y nota nueva del esquema 3, ambas del mismo secreto
+    let v2_commitment = commitment_for_scheme("shared", SCHEME_V2, HashScheme::Sha256);
+    let v2_note = DepositInfo { scheme_version: SCHEME_V2, ..note(NearToken::from_near(1)) };
+    contract.store_deposit(&v2_commitment, &v2_note);
+    deposit_as(&mut ctx, &mut contract, accounts(1), "shared", NearToken::from_near(1));
+    let v2_nullifier = withdrawal_hash_for_scheme("shared", SCHEME_V2, HashScheme::Sha256);
+    let v3_nullifier = withdrawal_hash_for_scheme("shared", SCHEME_V3, HashScheme::Sha256);
+    assert_ne!(v2_nullifier, v3_nullifier);
+    
+    // Se retira primero la nota del esquema 3, y gastarla no bloquea la del 2
+    withdraw_after_delay(&mut ctx, &mut contract, accounts(2), "shared");
+    assert!(contract.spent_outputs.contains(&v3_nullifier) && !contract.spent_outputs.contains(&v2_nullifier));
+    withdraw_after_delay(&mut ctx, &mut contract, accounts(2), "shared");
+    assert_eq!(transfers(), vec![(accounts(2), NearToken::from_near(1))]);
+    assert!(contract.spent_outputs.contains(&v2_nullifier));
+    
+    // Un segundo gasto se bloquea con cualquiera de los dos esquemas
+    let again = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        withdraw_after_delay(&mut ctx, &mut contract, accounts(2), "shared")
+    }));
+    assert!(again.is_err());
+    contract.store_deposit(&v2_commitment, &v2_note);
+    assert_eq!(contract.check_note("shared", Some(&accounts(2)), true).err().as_deref(),
+        Some("This secret has already been used"));
+    contract.remove_deposit(&v2_commitment, &v2_note);
+    let v3_note = DepositInfo { scheme_version: SCHEME_V3, ..note(NearToken::from_near(1)) };
+    contract.store_deposit(&commitment_for("shared"), &v3_note);
+    assert_eq!(contract.check_note("shared", Some(&accounts(2)), true).err().as_deref(),
+        Some("This secret has already been used"));
+}
+
+#[test]
+#[should_panic(expected = "Failed to migrate state: field `owner` could not be deserialized")]
+fn migrate_from_mismatched_layout_names_broken_field() {
+    #[derive(BorshSerialize)]
+    struct MismatchedLayout {
+        deposits: LookupMap<String, DepositInfo>,
+        spent_outputs: UnorderedSet<String>,
+        owner: [u8; 3],
+    }
+    setup(0);
+    env::state_write(&MismatchedLayout {
+        deposits: LookupMap::new(b"d"),
+        spent_outputs: UnorderedSet::new(b"s"),
+        owner: [0xff; 3],
+    });
+    UtxoMixer::migrate();
+}
+
+#[test]
+#[should_panic(expected = "Contract state is already migrated")]
+fn migrate_twice_is_rejected() {
+    let (_, contract) = setup(0);
+    env::state_write(&contract);
+    UtxoMixer::migrate();
+}
+
+#[test]
+fn recover_state_keeps_deposits_under_known_prefixes() {
+    let (mut ctx, mut contract) = setup(0);
+    deposit_as(&mut ctx, &mut contract, accounts(1), "secret", NearToken::from_near(10));
+    // El estado raíz queda ilegible
+    env::storage_write(b"STATE", &[0xff; 4]);
+
+    let mut recovered = UtxoMixer::recover_state(accounts(0), 0);
+    assert_eq!(recovered.get_total_deposits(), 1);
+    withdraw_after_delay(&mut ctx, &mut recovered, accounts(2), "secret");
+    assert_eq!(transfers(), vec![(accounts(2), NearToken::from_near(10))]);
+}
+
+fn withdraw_request(recipient: AccountId, secret: &str, relayer_fee: NearToken) -> WithdrawRequest {
+    WithdrawRequest { recipient, secret: secret.to_string(), relayer_fee: Some(yocto(relayer_fee)), relay_signature: None }
+}
+
+#[test]
+fn batch_withdraw_skips_spent_notes_and_continues() {
+    let (mut ctx, mut contract) = setup(0);
+    for secret in ["a", "b", "c"] {
+        deposit_as(&mut ctx, &mut contract, accounts(1), secret, NearToken::from_near(1));
+    }
+    withdraw_after_delay(&mut ctx, &mut contract, accounts(2), "b");
+    
+    testing_env!(ctx.predecessor_account_id(accounts(5)).build());
+    let relayer_fee = NearToken::from_millinear(10);
+    let results = contract.batch_withdraw(vec![
+        withdraw_request(accounts(2), "a", relayer_fee),
+        withdraw_request(accounts(3), "b", relayer_fee),
+        withdraw_request(accounts(4), "c", relayer_fee),
+    ]);
+    
+    assert_eq!(results, vec![
+        BatchResult { index: 0, status: BatchItemStatus::Queued, detail: Some(withdrawal_hash_for("a")) },
+        BatchResult { index: 1, status: BatchItemStatus::Skipped, detail: Some("No deposit found for this secret".to_string()) },
+        BatchResult { index: 2, status: BatchItemStatus::Queued, detail: Some(withdrawal_hash_for("c")) },
+    ]);
+    assert_eq!(near_sdk::serde_json::to_value(&results[1]).unwrap(),
+        json!({"index": 1, "status": "skipped", "detail": "No deposit found for this secret"}));
+    // El relayer cobra en el callback, no junto con la transferencia al destinatario
+    assert_eq!(transfers(), vec![
+        (accounts(2), NearToken::from_millinear(990)),
+        (accounts(4), NearToken::from_millinear(990)),
+    ]);
+    assert_eq!(contract.get_total_deposits(), 0);
+}
+
+#[test]
+fn relay_signature_binds_relayer_and_fee() {
+    use ed25519_dalek::{Signer, SigningKey};
+    
+    let (mut ctx, mut contract) = setup(0);
+    let signing_key = SigningKey::from_bytes(&[7; 32]);
+    let relay_key = PublicKey::from_parts(CurveType::ED25519, signing_key.verifying_key().to_bytes().to_vec()).unwrap();
+    testing_env!(ctx.predecessor_account_id(accounts(1)).attached_deposit(NearToken::from_near(1)).build());
+    contract.deposit(commitment_for("secret"), None, None, None, Some(relay_key));
+    
+    // El usuario firma el retiro hacia accounts(2) por medio de accounts(5) con 10 milliNEAR de comisión
+    let relayer_fee = NearToken::from_millinear(10);
+    let message = contract.compute_relay_message(withdrawal_hash_for("secret"), accounts(2), accounts(5), yocto(relayer_fee));
+    let signature: Base64VecU8 = signing_key.sign(message.as_bytes()).to_bytes().to_vec().into();
+    let signed = |relayer_fee: NearToken| WithdrawRequest {
+        relay_signature: Some(signature.clone()),
+        ..withdraw_request(accounts(2), "secret", relayer_fee)
+    };
+    
+    testing_env!(ctx
+        .predecessor_account_id(accounts(5))
+        .attached_deposit(NearToken::from_yoctonear(0))
+        .block_timestamp(env::block_timestamp() + MIN_DELAY * 1_000_000_000)
+        .build());
+    let invalid = || BatchResult { index: 0, status: BatchItemStatus::Skipped, detail: Some("Invalid relay signature".to_string()) };
+    // Otra comisión, otro relayer o ninguna firma invalidan el pedido
+    assert_eq!(contract.batch_withdraw(vec![signed(NearToken::from_millinear(50))]), vec![invalid()]);
+    assert_eq!(contract.batch_withdraw(vec![withdraw_request(accounts(2), "secret", relayer_fee)]), vec![invalid()]);
+    testing_env!(ctx.predecessor_account_id(accounts(4)).build());
+    assert_eq!(contract.batch_withdraw(vec![signed(relayer_fee)]), vec![invalid()]);
+    let direct = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        contract.withdraw(accounts(2), "secret".to_string(), None, None, None);
+    }));
+    assert!(direct.is_err());
+    
+    testing_env!(ctx.predecessor_account_id(accounts(5)).build());
+    assert_eq!(contract.batch_withdraw(vec![signed(relayer_fee)]), vec![
+        BatchResult { index: 0, status: BatchItemStatus::Queued, detail: Some(withdrawal_hash_for("secret")) },
+    ]);
+    assert_eq!(transfers(), vec![(accounts(2), NearToken::from_millinear(990))]);
+}
+
+#[test]
+fn relay_quote_is_profitable_only_above_gas_cost() {
+    let (_, mut contract) = setup(100);
+    let one = NearToken::from_near(1);
+    // El tope por defecto es 5% de la denominación
+    let max_fee = yocto(NearToken::from_millinear(50));
+    assert_eq!(contract.get_relay_quote(one, U128(max_fee.0 - 1)), (max_fee, true));
+    assert_eq!(contract.get_relay_quote(one, max_fee), (max_fee, false));
+    assert_eq!(contract.get_relay_quote(one, U128(max_fee.0 + 1)), (max_fee, false));
+    
+    // No se cotiza más de lo que queda tras la comisión de protocolo
+    contract.set_max_relayer_fee_basis_points(10000);
+    assert_eq!(contract.get_relay_quote(one, U128(0)), (yocto(NearToken::from_millinear(990)), true));
+}
+
+#[test]
+fn denomination_relayer_list_restricts_only_its_pool() {
+    let (mut ctx, mut contract) = setup(0);
+    let one = NearToken::from_near(1);
+    let hundred = NearToken::from_near(100);
+    contract.add_denomination_relayer(one, accounts(5));
+    contract.add_denomination_relayer(hundred, accounts(4));
+    assert_eq!(contract.get_denomination_relayers(one, 0, 10), vec![accounts(5)]);
+    deposit_as(&mut ctx, &mut contract, accounts(1), "small", one);
+    deposit_as(&mut ctx, &mut contract, accounts(1), "large", hundred);
+    
+    testing_env!(ctx
+        .predecessor_account_id(accounts(5))
+        .attached_deposit(NearToken::from_yoctonear(0))
+        .block_timestamp(env::block_timestamp() + MIN_DELAY * 1_000_000_000)
+        .build());
+    let fee = NearToken::from_millinear(10);
+    let results = contract.batch_withdraw(vec![
+        withdraw_request(accounts(2), "small", fee),
+        withdraw_request(accounts(3), "large", fee),
+    ]);
+    assert_eq!(results, vec![
+        BatchResult { index: 0, status: BatchItemStatus::Queued, detail: Some(withdrawal_hash_for("small")) },
+        BatchResult { index: 1, status: BatchItemStatus::Skipped, detail: Some("Relayer is not allowed for this denomination".to_string()) },
+    ]);
+    
+    // Sin lista propia, el pool de 10 NEAR usa la global, abierta salvo que el owner la active
+    assert!(contract.is_relayer_allowed(NearToken::from_near(10), accounts(5)));
+    testing_env!(ctx.predecessor_account_id(accounts(0)).build());
+    contract.set_relayer_allowlist_enabled(true);
+    assert!(!contract.is_relayer_allowed(NearToken::from_near(10), accounts(5)));
+    contract.remove_denomination_relayer(hundred, accounts(4));
+    assert!(contract.get_denomination_relayers(hundred, 0, 10).is_empty());
+    contract.add_approved_relayer(accounts(4));
+    assert!(contract.is_relayer_allowed(hundred, accounts(4)));
+}
+
+#[test]
+fn batch_withdraw_pays_relayer_only_after_successful_transfer() {
+    let (mut ctx, mut contract) = setup(0);
+    let relayer_fee = Some((accounts(5), yocto(NearToken::from_millinear(10))));
+    let deposit = note(NearToken::from_near(1));
+    testing_env!(
+        ctx.predecessor_account_id(env::current_account_id()).build(),
+        near_sdk::test_vm_config(),
+        RuntimeFeesConfig::test(),
+        Default::default(),
+        vec![PromiseResult::Successful(vec![])],
+    );
+    assert!(contract.withdraw_resolve(accounts(2), commitment_for("a"), withdrawal_hash_for("a"), deposit,
+        U128(0), relayer_fee.clone()));
+    assert_eq!(transfers(), vec![(accounts(5), NearToken::from_millinear(10))]);
+    
+    let deposit = note(NearToken::from_near(1));
+    testing_env!(
+        ctx.build(),
+        near_sdk::test_vm_config(),
+        RuntimeFeesConfig::test(),
+        Default::default(),
+        vec![PromiseResult::Failed],
+    );
+    assert!(!contract.withdraw_resolve(accounts(2), commitment_for("a"), withdrawal_hash_for("a"), deposit,
+        U128(0), relayer_fee));
+    assert!(transfers().is_empty());
+    assert_eq!(contract.get_total_deposits(), 1);
+}
+
+#[test]
+#[should_panic(expected = "Batch must have between 1 and 5 withdrawals")]
+fn batch_withdraw_rejects_oversized_batch() {
+    let (_, mut contract) = setup(0);
+    let withdrawals = (0..=MAX_BATCH_WITHDRAWALS)
+        .map(|i| withdraw_request(accounts(2), &i.to_string(), NearToken::from_yoctonear(0)))
+        .collect();
+    contract.batch_withdraw(withdrawals);
+}
+
+#[test]
+fn tree_snapshot_lets_indexer_continue_inserting_off_chain() {
+    let (mut ctx, mut contract) = setup(0);
+    for secret in ["a", "b", "c"] {
+        deposit_as(&mut ctx, &mut contract, accounts(1), secret, NearToken::from_near(1));
+    }
+    let snapshot = contract.get_tree_snapshot();
+    assert_eq!(snapshot.next_index, 3);
+    assert_eq!(snapshot.height, merkle::TREE_HEIGHT);
+    assert_eq!(snapshot.current_root, contract.get_merkle_root());
+    
+    // El indexador sigue insertando con los subárboles del snapshot y los valores vacíos públicos
+    let zeros: Vec<String> = (0..merkle::TREE_HEIGHT).map(|level| contract.get_zero_value(level)).collect();
+    let mut filled_subtrees = snapshot.filled_subtrees;
+    let mut root = snapshot.current_root;
+    for (leaf_index, secret) in (snapshot.next_index..).zip(["d", "e"]) {
+        deposit_as(&mut ctx, &mut contract, accounts(1), secret, NearToken::from_near(1));
+        let mut index = leaf_index;
+        let mut current = commitment_for(secret);
+        for (filled_subtree, zero) in filled_subtrees.iter_mut().zip(&zeros) {
+            current = if index.is_multiple_of(2) {
+                *filled_subtree = current.clone();
+                merkle::hash_pair(&current, zero)
+            } else {
+                merkle::hash_pair(filled_subtree, &current)
+            };
+            index /= 2;
+        }
+        root = current;
+    }
+    assert_eq!(root, contract.get_merkle_root());
+    assert_eq!(contract.get_tree_snapshot().filled_subtrees, filled_subtrees);
+}
+
+#[test]
+fn zero_values_match_documented_vectors() {
+    let (_, contract) = setup(0);
+    // keccak256("near-mixer"), y cada nivel es hash_pair del anterior consigo mismo
+    assert_eq!(contract.get_zero_value(0), "30d9fe070515483a68fc2b4f311e11263d2c6cd7b5ea3da591cb0d467ddd87e5");
+    assert_eq!(contract.get_zero_value(1), "bfdc0c49e5e96a31dd03d8f38b7016b60ffc1057bc1b3177c6ff7b33f5a0b2b4");
+    assert_eq!(contract.get_zero_value(merkle::TREE_HEIGHT), "7c3b0c95e939a10d2cf1f9d8bb7dfe6cf1e64595e33cceb8648d509715da6873");
+    for level in 0..merkle::TREE_HEIGHT {
+        let below = contract.get_zero_value(level);
+        assert_eq!(contract.get_zero_value(level + 1), merkle::hash_pair(&below, &below));
+    }
+}
+
+#[test]
+#[should_panic(expected = "Level exceeds the tree height")]
+fn zero_value_above_tree_height_is_rejected() {
+    let (_, contract) = setup(0);
+    contract.get_zero_value(merkle::TREE_HEIGHT + 1);
+}
+
+#[test]
+#[should_panic(expected = "Deposit exceeds the maximum note value")]
+fn deposit_above_max_note_value_is_rejected() {
+    let (mut ctx, mut contract) = setup(0);
+    contract.set_max_note_value(Some(yocto(NearToken::from_near(10))));
+    assert_eq!(contract.get_max_note_value(), Some(yocto(NearToken::from_near(10))));
+    deposit_as(&mut ctx, &mut contract, accounts(1), "small", NearToken::from_near(10));
+    deposit_as(&mut ctx, &mut contract, accounts(1), "large", NearToken::from_near(100));
+}
+
+#[test]
+fn withdrawal_honors_fee_mode_stored_at_deposit() {
+    let (mut ctx, mut contract) = setup(100);
+    contract.set_fee_mode(FeeMode::AtDeposit);
+    // La denominación más el 1% de comisión, cobrado al depositar
+    deposit_as(&mut ctx, &mut contract, accounts(1), "prepaid", NearToken::from_millinear(1_010));
+    assert_eq!(contract.get_sweepable_fees(), yocto(NearToken::from_millinear(10)));
+    
+    testing_env!(ctx.predecessor_account_id(accounts(0)).attached_deposit(NearToken::from_yoctonear(0)).build());
+    contract.set_fee_mode(FeeMode::AtWithdraw);
+    deposit_as(&mut ctx, &mut contract, accounts(1), "postpaid", NearToken::from_near(1));
+    
+    testing_env!(ctx.predecessor_account_id(accounts(0)).attached_deposit(NearToken::from_yoctonear(0)).build());
+    contract.set_fee_mode(FeeMode::AtDeposit);
+    withdraw_after_delay(&mut ctx, &mut contract, accounts(2), "postpaid");
+    assert_eq!(transfers(), vec![(accounts(2), NearToken::from_millinear(990))]);
+    
+    testing_env!(ctx.predecessor_account_id(accounts(0)).build());
+    contract.set_fee_mode(FeeMode::AtWithdraw);
+    withdraw_after_delay(&mut ctx, &mut contract, accounts(3), "prepaid");
+    assert_eq!(transfers(), vec![(accounts(3), NearToken::from_near(1))]);
+}
+
+#[test]
+#[should_panic(expected = "Deposit must include the fee in at-deposit fee mode")]
+fn at_deposit_fee_mode_rejects_bare_denomination() {
+    let (mut ctx, mut contract) = setup(100);
+    contract.set_fee_mode(FeeMode::AtDeposit);
+    deposit_as(&mut ctx, &mut contract, accounts(1), "secret", NearToken::from_near(1));
+}
+
+#[test]
+fn notes_stored_before_fee_modes_pay_at_withdrawal() {
+    setup(0);
+    let stored = (NearToken::from_near(1), 5u64, None::<String>, CURRENT_SCHEME_VERSION);
+    let deposit = DepositInfo::try_from_slice(&borsh::to_vec(&stored).unwrap()).unwrap();
+    assert_eq!(deposit.fee_mode, FeeMode::AtWithdraw);
+    assert_eq!(deposit.scheme_version, CURRENT_SCHEME_VERSION);
+}
+
+#[test]
+fn unlock_info_reports_countdown() {
+    let (mut ctx, mut contract) = setup(0);
+    assert_eq!(contract.get_unlock_info(commitment_for("secret")), None);
+    
+    testing_env!(ctx.block_timestamp(1_000).build());
+    deposit_as(&mut ctx, &mut contract, accounts(1), "secret", NearToken::from_near(1));
+    let unlock_timestamp = 1_000 + MIN_DELAY * 1_000_000_000;
+    assert_eq!(contract.get_unlock_info(commitment_for("secret")), Some((1_000, unlock_timestamp, false)));
+    
+    testing_env!(ctx.block_timestamp(unlock_timestamp).build());
+    assert_eq!(contract.get_unlock_info(commitment_for("secret")), Some((1_000, unlock_timestamp, true)));
+}
+
+#[test]
+fn deposit_receipt_has_every_note_field() {
+    let (mut ctx, mut contract) = setup(0);
+    assert_eq!(contract.get_deposit_receipt(commitment_for("secret")), None);
+    
+    deposit_as(&mut ctx, &mut contract, accounts(1), "first", NearToken::from_near(1));
+    testing_env!(ctx.block_timestamp(1_000).build());
+    deposit_as(&mut ctx, &mut contract, accounts(1), "secret", NearToken::from_near(10));
+    assert_eq!(contract.get_deposit_receipt(commitment_for("secret")), Some(DepositReceipt {
+        commitment_hash: commitment_for("secret"),
+        denomination: yocto(NearToken::from_near(10)),
+        timestamp: 1_000,
+        leaf_index: Some(1),
+        scheme_version: CURRENT_SCHEME_VERSION,
+        hash_scheme: HashScheme::Sha256,
+        unlock_timestamp: 1_000 + MIN_DELAY * 1_000_000_000,
+    }));
+    
+    // Una nota retirada ya no tiene recibo
+    withdraw_after_delay(&mut ctx, &mut contract, accounts(2), "secret");
+    assert_eq!(contract.get_deposit_receipt(commitment_for("secret")), None);
+}
+
+#[test]
+fn deposit_receipts_align_with_the_requested_commitments() {
+    let (mut ctx, mut contract) = setup(0);
+    deposit_as(&mut ctx, &mut contract, accounts(1), "a", NearToken::from_near(1));
+    deposit_as(&mut ctx, &mut contract, accounts(1), "b", NearToken::from_near(10));
+    
+    let receipts = contract.get_deposit_receipts(vec![
+        commitment_for("missing"),
+        commitment_for("b"),
+        commitment_for("a"),
+        commitment_for("other"),
+    ]);
+    let denominations: Vec<_> = receipts.iter().map(|receipt| receipt.as_ref().map(|receipt| receipt.denomination)).collect();
+    assert_eq!(denominations, vec![None, Some(yocto(NearToken::from_near(10))), Some(yocto(NearToken::from_near(1))), None]);
+    assert_eq!(receipts[1].as_ref().unwrap().commitment_hash, commitment_for("b"));
+    
+    let too_many = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        contract.get_deposit_receipts(vec![commitment_for("a"); MAX_RECEIPT_BATCH + 1]);
+    }));
+    assert!(too_many.is_err());
+}
+
+#[test]
+#[should_panic(expected = "Withdrawal is below the minimum withdrawal amount")]
+fn flexible_withdrawal_below_minimum_is_rejected() {
+    let (mut ctx, mut contract) = setup(100);
+    contract.set_flexible_pool_enabled(true);
+    contract.set_min_withdrawal_amount(yocto(NearToken::from_millinear(1_500)));
+    assert_eq!(contract.get_min_withdrawal_amount(), yocto(NearToken::from_millinear(1_500)));
+    // 1.5 NEAR menos el 1% de comisión queda por debajo del mínimo
+    deposit_as(&mut ctx, &mut contract, accounts(1), "secret", NearToken::from_millinear(1_500));
+    withdraw_after_delay(&mut ctx, &mut contract, accounts(2), "secret");
+}
+
+#[test]
+fn min_withdrawal_amount_does_not_apply_to_fixed_pools() {
+    let (mut ctx, mut contract) = setup(100);
+    contract.set_min_withdrawal_amount(yocto(NearToken::from_near(5)));
+    deposit_as(&mut ctx, &mut contract, accounts(1), "secret", NearToken::from_near(1));
+    withdraw_after_delay(&mut ctx, &mut contract, accounts(2), "secret");
+    assert_eq!(transfers(), vec![(accounts(2), NearToken::from_millinear(990))]);
+}
+
+#[test]
+fn is_initialized_reflects_the_stored_state() {
+    let (_, contract) = setup(0);
+    assert!(!UtxoMixer::is_initialized());
+    env::state_write(&contract);
+    assert!(UtxoMixer::is_initialized());
+}
+
+#[test]
+fn deposits_fill_merkle_tree_up_to_capacity() {
+    let (mut ctx, mut contract) = setup(0);
+    assert_eq!(contract.get_tree_info(), (0, 1 << merkle::TREE_HEIGHT, merkle::TREE_HEIGHT));
+    assert_eq!(contract.get_merkle_root(), contract.get_zero_value(merkle::TREE_HEIGHT));
+    
+    contract.tree = merkle::MerkleTree::new(2, merkle::DEFAULT_ROOT_HISTORY_SIZE, b"q");
+    let leaves: Vec<String> = ["a", "b", "c", "d"].iter().map(|secret| commitment_for(secret)).collect();
+    for secret in ["a", "b", "c", "d"] {
+        deposit_as(&mut ctx, &mut contract, accounts(1), secret, NearToken::from_near(1));
+    }
+    assert_eq!(contract.get_tree_info(), (4, 4, 2));
+    let root = merkle::hash_pair(&merkle::hash_pair(&leaves[0], &leaves[1]), &merkle::hash_pair(&leaves[2], &leaves[3]));
+    assert_eq!(contract.get_merkle_root(), root);
+    
+    let full = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        deposit_as(&mut ctx, &mut contract, accounts(1), "e", NearToken::from_near(1));
+    }));
+    assert!(full.is_err());
+    assert_eq!(contract.get_tree_info(), (4, 4, 2));
+    assert_eq!(contract.get_total_deposits(), 4);
+}
+
+#[test]
+#[should_panic(expected = "Deposit would exceed the value locked cap of this denomination")]
+fn migrate_note_respects_the_target_pool_cap() {
+    let (mut ctx, mut contract) = setup(100);
+    deposit_as(&mut ctx, &mut contract, accounts(1), "old", NearToken::from_near(1));
+    testing_env!(ctx.predecessor_account_id(accounts(0)).attached_deposit(NearToken::from_yoctonear(0)).build());
+    contract.set_denomination_deprecated(NearToken::from_near(1), true);
+    contract.set_max_tvl_for_denomination(NearToken::from_near(10), Some(yocto(NearToken::from_near(5))));
+    
+    testing_env!(ctx
+        .predecessor_account_id(accounts(1))
+        .attached_deposit(NearToken::from_near(9))
+        .block_timestamp(env::block_timestamp() + MIN_DELAY * 1_000_000_000)
+        .build());
+    contract.migrate_note("old".to_string(), commitment_for("new"), NearToken::from_near(10));
+}
+
+#[test]
+#[should_panic(expected = "Account is not allowed to deposit")]
+fn migrate_note_respects_the_deposit_allowlist() {
+    let (mut ctx, mut contract) = setup(100);
+    deposit_as(&mut ctx, &mut contract, accounts(1), "old", NearToken::from_near(1));
+    testing_env!(ctx.predecessor_account_id(accounts(0)).attached_deposit(NearToken::from_yoctonear(0)).build());
+    contract.set_denomination_deprecated(NearToken::from_near(1), true);
+    contract.set_deposit_allowlist_enabled(true);
+    
+    testing_env!(ctx
+        .predecessor_account_id(accounts(1))
+        .attached_deposit(NearToken::from_near(9))
+        .block_timestamp(env::block_timestamp() + MIN_DELAY * 1_000_000_000)
+        .build());
+    contract.migrate_note("old".to_string(), commitment_for("new"), NearToken::from_near(10));
+}
+
+#[test]
+fn migrate_note_moves_deprecated_note_into_active_pool() {
+    let (mut ctx, mut contract) = setup(100);
+    deposit_as(&mut ctx, &mut contract, accounts(1), "old", NearToken::from_near(1));
+    testing_env!(ctx.predecessor_account_id(accounts(0)).attached_deposit(NearToken::from_yoctonear(0)).build());
+    contract.set_denomination_deprecated(NearToken::from_near(1), true);
+    
+    testing_env!(ctx
+        .predecessor_account_id(accounts(1))
+        .attached_deposit(NearToken::from_near(9))
+        .block_timestamp(env::block_timestamp() + MIN_DELAY * 1_000_000_000)
+        .build());
+    contract.migrate_note("old".to_string(), commitment_for("new"), NearToken::from_near(10));
+    
+    assert!(transfers().is_empty());
+    assert!(contract.spent_outputs.contains(&withdrawal_hash_for("old")));
+    assert_eq!(contract.deposit_counts.get(&NearToken::from_near(1)), Some(0));
+    assert_eq!(contract.deposit_counts.get(&NearToken::from_near(10)), Some(1));
+    
+    // El hash de retiro de la nota vieja se consumió una sola vez
+    let again = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        contract.migrate_note("old".to_string(), commitment_for("other"), NearToken::from_near(10));
+    }));
+    assert!(again.is_err());
+    
+    withdraw_after_delay(&mut ctx, &mut contract, accounts(2), "new");
+    assert_eq!(transfers(), vec![(accounts(2), NearToken::from_millinear(9_900))]);
+}
+
+#[test]
+#[should_panic(expected = "This denomination is deprecated")]
+fn deposit_into_deprecated_denomination_is_rejected() {
+    let (mut ctx, mut contract) = setup(0);
+    contract.set_denomination_deprecated(NearToken::from_near(1), true);
+    deposit_as(&mut ctx, &mut contract, accounts(1), "secret", NearToken::from_near(1));
+}
+
+#[test]
+fn amount_apis_serialize_as_json_strings() {
+    let (mut ctx, mut contract) = setup(100);
+    deposit_as(&mut ctx, &mut contract, accounts(1), "secret", NearToken::from_near(10));
+    
+    let stats = near_sdk::serde_json::to_value(contract.get_pool_stats()).unwrap();
+    assert_eq!(stats, near_sdk::serde_json::json!([
+        1,
+        "10000000000000000000000000",
+        [["1000000000000000000000000", 0], ["10000000000000000000000000", 1], ["100000000000000000000000000", 0]],
+    ]));
+    
+    // Ida y vuelta sin pérdida de precisión, también por encima de 2^53
+    let amount = U128(NearToken::from_near(10).as_yoctonear() + 1);
+    let json = near_sdk::serde_json::to_string(&amount).unwrap();
+    assert_eq!(json, "\"10000000000000000000000001\"");
+    let parsed: U128 = near_sdk::serde_json::from_str(&json).unwrap();
+    assert_eq!(near_token(parsed), NearToken::from_yoctonear(amount.0));
+    assert_eq!(yocto(near_token(parsed)), amount);
+}
+
+/// Datos de los eventos NEP-297 con nombre `event` emitidos en la última llamada
+fn events_named(event: &str) -> Vec<near_sdk::serde_json::Value> {
+    get_logs()
+        .iter()
+        .filter_map(|log| log.strip_prefix("EVENT_JSON:"))
+        .map(|json| near_sdk::serde_json::from_str::<near_sdk::serde_json::Value>(json).unwrap())
+        .filter(|value| value["event"] == event)
+        .map(|value| value["data"][0].clone())
+        .collect()
+}
+
+#[test]
+fn owner_setters_emit_config_change_events() {
+    let (_, mut contract) = setup(100);
+    contract.set_fee_basis_points(250);
+    assert_eq!(events_named("config_change"), vec![near_sdk::serde_json::json!({
+        "field": "fee_basis_points",
+        "old_value": 100,
+        "new_value": 250,
+    })]);
+    
+    contract.set_min_withdrawal_amount(yocto(NearToken::from_near(1)));
+    let events = events_named("config_change");
+    assert_eq!(events[1]["field"], "min_withdrawal_amount");
+    assert_eq!(events[1]["old_value"], "0");
+    assert_eq!(events[1]["new_value"], "1000000000000000000000000");
+}
+
+#[test]
+#[should_panic(expected = "Withdrawals must not attach NEAR")]
+fn withdraw_attaching_near_is_rejected() {
+    let (mut ctx, mut contract) = setup(100);
+    deposit_as(&mut ctx, &mut contract, accounts(1), "secret", NearToken::from_near(1));
+    testing_env!(ctx
+        .attached_deposit(NearToken::from_millinear(10))
+        .block_timestamp(env::block_timestamp() + MIN_DELAY * 1_000_000_000)
+        .build());
+    contract.withdraw(accounts(2), "secret".to_string(), None, None, None);
+}
+
+#[test]
+fn anonymity_floor_blocks_withdrawal_until_pool_grows() {
+    let (mut ctx, mut contract) = setup(0);
+    contract.set_min_pool_size_for_withdrawal(2);
+    deposit_as(&mut ctx, &mut contract, accounts(1), "first", NearToken::from_near(1));
+    
+    testing_env!(ctx.predecessor_account_id(accounts(2)).build());
+    let blocked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        withdraw_after_delay(&mut ctx, &mut contract, accounts(2), "first");
+    }));
+    assert!(blocked.is_err());
+    assert_eq!(contract.get_total_deposits(), 1);
+    
+    deposit_as(&mut ctx, &mut contract, accounts(3), "second", NearToken::from_near(1));
+    testing_env!(ctx.predecessor_account_id(accounts(2)).build());
+    withdraw_after_delay(&mut ctx, &mut contract, accounts(2), "first");
+    assert_eq!(transfers(), vec![(accounts(2), NearToken::from_near(1))]);
+}
+
+#[test]
+fn anonymity_floor_lets_original_depositor_exit() {
+    let (mut ctx, mut contract) = setup(0);
+    contract.set_min_pool_size_for_withdrawal(2);
+    register_storage(&mut ctx, &mut contract, accounts(1), NearToken::from_millinear(10));
+    deposit_as(&mut ctx, &mut contract, accounts(1), "secret", NearToken::from_near(1));
+    withdraw_after_delay(&mut ctx, &mut contract, accounts(1), "secret");
+    assert_eq!(transfers(), vec![(accounts(1), NearToken::from_near(1))]);
+}
+
+fn register_storage(ctx: &mut VMContextBuilder, contract: &mut UtxoMixer, account: AccountId, amount: NearToken) -> StorageBalance {
+    testing_env!(ctx.predecessor_account_id(account).attached_deposit(amount).build());
+    contract.storage_deposit(None, None)
+}
+
+#[test]
+fn storage_balance_pays_for_depositor_entries() {
+    let (mut ctx, mut contract) = setup(0);
+    let min = contract.storage_balance_bounds().min;
+    let balance = register_storage(&mut ctx, &mut contract, accounts(1), NearToken::from_millinear(10));
+    assert_eq!(balance.total, yocto(NearToken::from_millinear(10)));
+    assert_eq!(balance.available.0, NearToken::from_millinear(10).as_yoctonear() - min.0);
+    
+    // La nota consume saldo disponible mientras está activa y lo libera al retirarse
+    deposit_as(&mut ctx, &mut contract, accounts(1), "secret", NearToken::from_near(1));
+    let during = contract.storage_balance_of(accounts(1)).unwrap();
+    assert!(during.available.0 < balance.available.0);
+    assert_eq!(contract.depositors.get(&commitment_for("secret")), Some(accounts(1)));
+    
+    withdraw_after_delay(&mut ctx, &mut contract, accounts(2), "secret");
+    resolve_withdrawal(&mut ctx, &mut contract, accounts(2), "secret", note(NearToken::from_near(1)),
+        NearToken::from_yoctonear(0), PromiseResult::Successful(vec![]));
+    assert_eq!(contract.storage_balance_of(accounts(1)), Some(balance));
+    
+    // Retirar lo no usado devuelve el saldo disponible más el yoctoNEAR adjuntado
+    testing_env!(ctx.predecessor_account_id(accounts(1)).attached_deposit(NearToken::from_yoctonear(1)).build());
+    let after = contract.storage_withdraw(None);
+    assert_eq!(after, StorageBalance { total: min, available: U128(0) });
+    assert_eq!(transfers(), vec![(accounts(1), NearToken::from_yoctonear(NearToken::from_millinear(10).as_yoctonear() - min.0 + 1))]);
+}
+
+#[test]
+fn deposits_without_storage_registration_skip_depositor_entry() {
+    let (mut ctx, mut contract) = setup(0);
+    deposit_as(&mut ctx, &mut contract, accounts(1), "secret", NearToken::from_near(1));
+    assert_eq!(contract.depositors.get(&commitment_for("secret")), None);
+    assert_eq!(contract.storage_balance_of(accounts(1)), None);
+}
+
+#[test]
+#[should_panic(expected = "The attached deposit is less than the minimum storage balance")]
+fn storage_deposit_below_minimum_is_rejected() {
+    let (mut ctx, mut contract) = setup(0);
+    register_storage(&mut ctx, &mut contract, accounts(1), NearToken::from_yoctonear(1));
+}
+
+#[test]
+fn fee_history_records_changes_in_order() {
+    let (mut ctx, mut contract) = setup(100);
+    let day = FEE_CHANGE_DELAY * 1_000_000_000;
+    contract.set_fee_basis_points(200);
+    
+    testing_env!(ctx.block_timestamp(2 * day).build());
+    contract.set_fee_basis_points(300);
+    // Reemplazar el cambio aún pendiente no deja rastro del valor descartado
+    contract.set_fee_basis_points(250);
+    
+    assert_eq!(contract.get_fee_history(), vec![(0, 100), (day, 200), (3 * day, 250)]);
+}
+
+/// Commitment de `secret` como los bytes que recibe `deposit_raw`
+fn raw_commitment_for(secret: &str) -> [u8; 32] {
+    Sha256::digest(format!("commit:{}:{}", env::current_account_id(), secret).as_bytes()).into()
+}
+
+#[test]
+fn raw_commitment_is_withdrawable() {
+    let (mut ctx, mut contract) = setup(0);
+    testing_env!(ctx.predecessor_account_id(accounts(1)).attached_deposit(NearToken::from_near(1)).build());
+    contract.deposit_raw(raw_commitment_for("secret"), None, None, None, None);
+    assert_eq!(contract.get_pool_stats().0, 1);
+    
+    withdraw_after_delay(&mut ctx, &mut contract, accounts(2), "secret");
+    assert_eq!(transfers(), vec![(accounts(2), NearToken::from_near(1))]);
+    assert_eq!(contract.get_pool_stats().0, 0);
+    assert!(contract.spent_outputs.contains(&withdrawal_hash_for("secret")));
+}
+
+#[test]
+fn raw_commitment_uses_less_storage_than_hex() {
+    let (mut ctx, mut contract) = setup(0);
+    let before = env::storage_usage();
+    deposit_as(&mut ctx, &mut contract, accounts(1), "hex", NearToken::from_near(1));
+    let hex_bytes = env::storage_usage() - before;
+    
+    let before = env::storage_usage();
+    contract.deposit_raw(raw_commitment_for("raw"), None, None, None, None);
+    let raw_bytes = env::storage_usage() - before;
+    
+    assert!(raw_bytes < hex_bytes, "raw {} bytes, hex {} bytes", raw_bytes, hex_bytes);
+}
+
+#[test]
+#[should_panic(expected = "Commitment already exists")]
+fn raw_commitment_cannot_duplicate_hex_commitment() {
+    let (mut ctx, mut contract) = setup(0);
+    deposit_as(&mut ctx, &mut contract, accounts(1), "secret", NearToken::from_near(1));
+    contract.deposit_raw(raw_commitment_for("secret"), None, None, None, None);
+}
+
+#[test]
+#[should_panic(expected = "Hex commitments are disabled, use deposit_raw")]
+fn hex_deposits_can_be_disabled() {
+    let (mut ctx, mut contract) = setup(0);
+    contract.set_hex_deposits_enabled(false);
+    deposit_as(&mut ctx, &mut contract, accounts(1), "secret", NearToken::from_near(1));
+}
+
+#[test]
+fn deposit_allowlist_rejects_accounts_not_allowed() {
+    let (mut ctx, mut contract) = setup(0);
+    contract.set_deposit_allowlist_enabled(true);
+    contract.add_allowed_depositor(accounts(1));
+    deposit_as(&mut ctx, &mut contract, accounts(1), "allowed", NearToken::from_near(1));
+    
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        deposit_as(&mut ctx, &mut contract, accounts(2), "not-allowed", NearToken::from_near(1));
+    }));
+    assert!(result.is_err());
+    assert_eq!(contract.get_total_deposits(), 1);
+    
+    // Desactivada la lista, el depósito vuelve a ser libre
+    testing_env!(ctx.predecessor_account_id(accounts(0)).build());
+    contract.set_deposit_allowlist_enabled(false);
+    deposit_as(&mut ctx, &mut contract, accounts(2), "not-allowed", NearToken::from_near(1));
+    assert_eq!(contract.get_total_deposits(), 2);
+}
+
+#[test]
+fn audit_counts_detects_and_corrects_drift() {
+    let (mut ctx, mut contract) = setup(0);
+    deposit_as(&mut ctx, &mut contract, accounts(1), "a", NearToken::from_near(1));
+    deposit_as(&mut ctx, &mut contract, accounts(1), "b", NearToken::from_near(1));
+    deposit_as(&mut ctx, &mut contract, accounts(1), "c", NearToken::from_near(10));
+    withdraw_after_delay(&mut ctx, &mut contract, accounts(2), "b");
+    
+    testing_env!(ctx.predecessor_account_id(accounts(0)).build());
+    assert_eq!(contract.audit_counts(MAX_PAGE_LIMIT, false), Some(vec![]));
+    
+    contract.deposit_counts.insert(&NearToken::from_near(1), &5);
+    let expected = vec![(NearToken::from_near(1), 5, 1)];
+    assert_eq!(contract.audit_counts(MAX_PAGE_LIMIT, false), Some(expected.clone()));
+    assert_eq!(contract.deposit_counts.get(&NearToken::from_near(1)), Some(5));
+    
+    assert_eq!(contract.audit_counts(MAX_PAGE_LIMIT, true), Some(expected));
+    assert_eq!(contract.deposit_counts.get(&NearToken::from_near(1)), Some(1));
+    assert_eq!(contract.audit_counts(MAX_PAGE_LIMIT, false), Some(vec![]));
+}
+
+#[test]
+fn audit_counts_scans_the_history_across_calls() {
+    let (mut ctx, mut contract) = setup(0);
+    for secret in ["a", "b", "c"] {
+        deposit_as(&mut ctx, &mut contract, accounts(1), secret, NearToken::from_near(1));
+    }
+    testing_env!(ctx.predecessor_account_id(accounts(0)).attached_deposit(NearToken::from_yoctonear(0)).build());
+    contract.deposit_counts.insert(&NearToken::from_near(1), &5);
+    
+    // Las correcciones solo se escriben cuando se recorrió todo el historial
+    assert_eq!(contract.audit_counts(2, true), None);
+    assert_eq!(contract.deposit_counts.get(&NearToken::from_near(1)), Some(5));
+    
+    // Un depósito entre llamadas reinicia la auditoría
+    deposit_as(&mut ctx, &mut contract, accounts(1), "d", NearToken::from_near(1));
+    testing_env!(ctx.predecessor_account_id(accounts(0)).attached_deposit(NearToken::from_yoctonear(0)).build());
+    assert_eq!(contract.audit_counts(2, true), None);
+    assert_eq!(contract.audit_counts(2, true), Some(vec![(NearToken::from_near(1), 6, 4)]));
+    assert_eq!(contract.deposit_counts.get(&NearToken::from_near(1)), Some(4));
+    
+    // Un límite mayor que `MAX_PAGE_LIMIT` se recorta
+    for i in 0..MAX_PAGE_LIMIT {
+        deposit_as(&mut ctx, &mut contract, accounts(1), &format!("secret-{}", i), NearToken::from_near(1));
+    }
+    testing_env!(ctx.predecessor_account_id(accounts(0)).attached_deposit(NearToken::from_yoctonear(0)).build());
+    assert_eq!(contract.audit_counts(u64::MAX, false), None);
+    assert_eq!(contract.audit_counts(u64::MAX, false), Some(vec![]));
+}
+
+#[test]
+fn audit_counts_with_zero_limit_still_advances() {
+    let (mut ctx, mut contract) = setup(0);
+    for secret in ["a", "b"] {
+        deposit_as(&mut ctx, &mut contract, accounts(1), secret, NearToken::from_near(1));
+    }
+    testing_env!(ctx.predecessor_account_id(accounts(0)).attached_deposit(NearToken::from_yoctonear(0)).build());
+    assert_eq!(contract.audit_counts(0, false), None);
+    assert_eq!(contract.audit_counts(0, false), Some(vec![]));
+}
+
+#[test]
+fn withdraw_to_contract_method_attaches_funds_to_the_call() {
+    let (mut ctx, mut contract) = setup(100);
+    deposit_as(&mut ctx, &mut contract, accounts(1), "secret", NearToken::from_near(1));
+    testing_env!(ctx
+        .block_timestamp(env::block_timestamp() + MIN_DELAY * 1_000_000_000)
+        .attached_deposit(NearToken::from_yoctonear(0))
+        .prepaid_gas(Gas::from_tgas(100))
+        .build());
+    contract.withdraw(accounts(2), "secret".to_string(), Some("deposit_for".to_string()),
+        Some(r#"{"vault": "main"}"#.to_string()), None);
+    
+    let calls: Vec<_> = get_created_receipts()
+        .into_iter()
+        .filter(|receipt| receipt.receiver_id == accounts(2))
+        .flat_map(|receipt| receipt.actions)
+        .filter_map(|action| match action {
+            MockAction::FunctionCallWeight { method_name, args, attached_deposit, .. } =>
+                Some((method_name, args, attached_deposit)),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(calls, vec![(b"deposit_for".to_vec(), br#"{"vault": "main"}"#.to_vec(), NearToken::from_millinear(990))]);
+    assert!(transfers().is_empty());
+    
+}
+
+#[test]
+fn failed_recipient_method_keeps_the_note_spent() {
+    let (mut ctx, mut contract) = setup(100);
+    deposit_as(&mut ctx, &mut contract, accounts(1), "secret", NearToken::from_near(1));
+    testing_env!(ctx
+        .block_timestamp(env::block_timestamp() + MIN_DELAY * 1_000_000_000)
+        .attached_deposit(NearToken::from_yoctonear(0))
+        .prepaid_gas(Gas::from_tgas(100))
+        .build());
+    contract.withdraw(accounts(2), "secret".to_string(), Some("deposit_for".to_string()), None, None);
+    
+    // El método pudo quedarse con los fondos y devolver una promesa fallida: la nota no vuelve, y
+    // el monto queda como pago fallido del destinatario
+    testing_env!(
+        ctx.predecessor_account_id(env::current_account_id()).build(),
+        near_sdk::test_vm_config(),
+        RuntimeFeesConfig::test(),
+        Default::default(),
+        vec![PromiseResult::Failed],
+    );
+    assert!(!contract.recipient_call_resolve(accounts(2), commitment_for("secret"),
+        yocto(NearToken::from_millinear(10)), yocto(NearToken::from_millinear(990))));
+    assert!(contract.spent_outputs.contains(&withdrawal_hash_for("secret")));
+    assert_eq!(contract.get_pool_stats().0, 0);
+    assert_eq!(contract.get_failed_payout(accounts(2)), Some(yocto(NearToken::from_millinear(990))));
+    assert_eq!(contract.accumulated_fees, NearToken::from_millinear(10));
+    
+    testing_env!(ctx.predecessor_account_id(accounts(3)).attached_deposit(NearToken::from_yoctonear(0)).prepaid_gas(Gas::from_tgas(100)).build());
+    let again = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        contract.withdraw(accounts(3), "secret".to_string(), None, None, None);
+    }));
+    assert!(again.is_err());
+}
+
+#[test]
+#[should_panic(expected = "Failed payouts cannot be retried while the contract is insolvent")]
+fn failed_payout_retry_requires_solvency() {
+    let (mut ctx, mut contract) = setup(0);
+    testing_env!(
+        ctx.predecessor_account_id(env::current_account_id()).account_balance(NearToken::from_near(5)).build(),
+        near_sdk::test_vm_config(),
+        RuntimeFeesConfig::test(),
+        Default::default(),
+        vec![PromiseResult::Failed],
+    );
+    contract.payouts_resolve(vec![(accounts(3), yocto(NearToken::from_near(6)))], U128(0));
+    testing_env!(ctx.predecessor_account_id(accounts(4)).build());
+    contract.retry_failed_payout(accounts(3));
+}
+
+#[test]
+#[should_panic(expected = "Not enough gas attached, withdrawing to a recipient method requires at least 60 Tgas")]
+fn withdraw_to_contract_method_requires_call_gas() {
+    let (mut ctx, mut contract) = setup(0);
+    deposit_as(&mut ctx, &mut contract, accounts(1), "secret", NearToken::from_near(1));
+    testing_env!(ctx.attached_deposit(NearToken::from_yoctonear(0)).prepaid_gas(Gas::from_tgas(40)).build());
+    contract.withdraw(accounts(2), "secret".to_string(), Some("deposit_for".to_string()), None, None);
+}
+
+/// Llama a `upgrade` como el owner, con un wasm de prueba como entrada cruda
+fn start_upgrade(ctx: &mut VMContextBuilder, contract: &mut UtxoMixer) {
+    let mut context = ctx.predecessor_account_id(accounts(0)).attached_deposit(NearToken::from_yoctonear(0)).build();
+    context.input = b"new code".to_vec();
+    testing_env!(context);
+    contract.upgrade();
+}
+
+#[test]
+fn deposits_and_withdrawals_are_rejected_while_migrating() {
+    let (mut ctx, mut contract) = setup(0);
+    deposit_as(&mut ctx, &mut contract, accounts(1), "secret", NearToken::from_near(1));
+    
+    start_upgrade(&mut ctx, &mut contract);
+    assert!(contract.is_migration_in_progress());
+    
+    let deposit = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        deposit_as(&mut ctx, &mut contract, accounts(1), "other", NearToken::from_near(1));
+    }));
+    assert!(deposit.is_err());
+    let withdraw = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        withdraw_after_delay(&mut ctx, &mut contract, accounts(2), "secret");
+    }));
+    assert!(withdraw.is_err());
+    assert_eq!(contract.get_total_deposits(), 1);
+    assert!(!contract.spent_outputs.contains(&withdrawal_hash_for("secret")));
+    
+    // `migrate`, en el mismo lote que el despliegue, desbloquea el contrato
+    env::state_write(&contract);
+    let mut contract = UtxoMixer::migrate();
+    assert!(!contract.is_migration_in_progress());
+    withdraw_after_delay(&mut ctx, &mut contract, accounts(2), "secret");
+    assert_eq!(contract.get_total_deposits(), 0);
+}
+
+#[test]
+#[should_panic(expected = "Contract is being migrated, try again later")]
+fn deposit_while_migrating_is_rejected() {
+    let (mut ctx, mut contract) = setup(0);
+    start_upgrade(&mut ctx, &mut contract);
+    deposit_as(&mut ctx, &mut contract, accounts(1), "secret", NearToken::from_near(1));
+}
+
+#[test]
+fn batch_withdraw_skips_relayer_fee_above_cap() {
+    let (mut ctx, mut contract) = setup(0);
+    contract.set_max_relayer_fee_basis_points(100);
+    for secret in ["a", "b"] {
+        deposit_as(&mut ctx, &mut contract, accounts(1), secret, NearToken::from_near(1));
+    }
+    
+    testing_env!(ctx
+        .predecessor_account_id(accounts(5))
+        .block_timestamp(env::block_timestamp() + MIN_DELAY * 1_000_000_000)
+        .attached_deposit(NearToken::from_yoctonear(0))
+        .build());
+    let results = contract.batch_withdraw(vec![
+        withdraw_request(accounts(2), "a", NearToken::from_millinear(10)),
+        withdraw_request(accounts(3), "b", NearToken::from_yoctonear(NearToken::from_millinear(10).as_yoctonear() + 1)),
+    ]);
+    
+    assert_eq!(results[0].status, BatchItemStatus::Queued);
+    assert_eq!(results[1], BatchResult {
+        index: 1,
+        status: BatchItemStatus::Skipped,
+        detail: Some("Relayer fee exceeds the maximum relayer fee".to_string()),
+    });
+    assert_eq!(transfers(), vec![(accounts(2), NearToken::from_millinear(990))]);
+    assert_eq!(contract.get_total_deposits(), 1);
+}
+
+#[test]
+fn last_activity_tracks_latest_deposit_per_denomination() {
+    let (mut ctx, mut contract) = setup(0);
+    assert_eq!(contract.get_last_activity(NearToken::from_near(1)), None);
+    
+    testing_env!(ctx.block_timestamp(1_000).build());
+    deposit_as(&mut ctx, &mut contract, accounts(1), "a", NearToken::from_near(1));
+    assert_eq!(contract.get_last_activity(NearToken::from_near(1)), Some(1_000));
+    
+    testing_env!(ctx.block_timestamp(5_000).build());
+    deposit_as(&mut ctx, &mut contract, accounts(1), "b", NearToken::from_near(1));
+    deposit_as(&mut ctx, &mut contract, accounts(1), "c", NearToken::from_near(10));
+    assert_eq!(contract.get_last_activity(NearToken::from_near(1)), Some(5_000));
+    assert_eq!(contract.get_last_activity(NearToken::from_near(10)), Some(5_000));
+    assert_eq!(contract.get_last_activity(NearToken::from_near(100)), None);
+}
+
+#[test]
+fn versioned_deposit_info_reads_v1_and_v2_entries() {
+    let (mut ctx, mut contract) = setup(0);
+    let v1 = VersionedDepositInfo::V1(DepositInfoV1 { denomination: NearToken::from_near(1), timestamp: 0 });
+    // El discriminante de versión va primero, así que cada entrada se lee sin adivinar su formato
+    assert_eq!(borsh::to_vec(&v1).unwrap()[0], 0);
+    let v1_commitment = commitment_for_scheme("old", SCHEME_V1, HashScheme::Sha256);
+    contract.versioned_deposits.insert(&v1_commitment, &v1);
+    contract.deposit_counts.insert(&NearToken::from_near(1), &1);
+    deposit_as(&mut ctx, &mut contract, accounts(1), "new", NearToken::from_near(1));
+    
+    assert!(matches!(contract.versioned_deposits.get(&commitment_for("new")), Some(VersionedDepositInfo::V3(_))));
+    let old = contract.get_deposit(&v1_commitment).unwrap();
+    assert_eq!((old.scheme_version, old.recipient_commitment, old.fee_mode), (SCHEME_V1, None, FeeMode::AtWithdraw));
+    assert_eq!(contract.get_deposit(&commitment_for("new")).unwrap().scheme_version, CURRENT_SCHEME_VERSION);
+    
+    // Las dos notas se retiran, cada una con su esquema
+    withdraw_after_delay(&mut ctx, &mut contract, accounts(2), "old");
+    withdraw_after_delay(&mut ctx, &mut contract, accounts(2), "new");
+    assert_eq!(contract.get_total_deposits(), 0);
+    assert!(contract.versioned_deposits.get(&v1_commitment).is_none());
+}
+
+#[test]
+fn rescue_surplus_transfers_only_unbacked_balance() {
+    let (mut ctx, mut contract) = setup(100);
+    deposit_as(&mut ctx, &mut contract, accounts(1), "secret", NearToken::from_near(1));
+    let storage = register_storage(&mut ctx, &mut contract, accounts(1), NearToken::from_millinear(10));
+    contract.accumulated_fees = NearToken::from_millinear(3);
+    
+    // Saldo que cubre exactamente lo que el contrato debe, más 5 NEAR enviados por error. Del saldo
+    // de almacenamiento solo se reserva la parte disponible: la bloqueada ya está en el staking.
+    testing_env!(ctx.predecessor_account_id(accounts(0)).attached_deposit(NearToken::from_yoctonear(0)).build());
+    let storage_stake = env::storage_byte_cost().saturating_mul(u128::from(env::storage_usage()));
+    let backed = NearToken::from_near(1)
+        .saturating_add(NearToken::from_millinear(3))
+        .saturating_add(near_token(storage.available))
+        .saturating_add(storage_stake);
+    testing_env!(ctx.account_balance(backed.saturating_add(NearToken::from_near(5))).build());
+    assert_eq!(contract.get_surplus(), yocto(NearToken::from_near(5)));
+    contract.rescue_surplus(accounts(3));
+    assert_eq!(transfers(), vec![(accounts(3), NearToken::from_near(5))]);
+}
+
+#[test]
+fn storage_registration_keeps_the_contract_solvent() {
+    let (mut ctx, mut contract) = setup(100);
+    deposit_as(&mut ctx, &mut contract, accounts(1), "before", NearToken::from_near(1));
+    register_storage(&mut ctx, &mut contract, accounts(1), NearToken::from_millinear(100));
+    deposit_as(&mut ctx, &mut contract, accounts(1), "after", NearToken::from_near(1));
+    let storage = contract.storage_balance_of(accounts(1)).unwrap();
+    assert!(storage.available.0 < storage.total.0);
+    
+    // El saldo cubre las notas, el saldo de almacenamiento disponible y el staking, que ya incluye los
+    // bytes pagados con la parte bloqueada
+    testing_env!(ctx.attached_deposit(NearToken::from_yoctonear(0)).build());
+    let storage_stake = env::storage_byte_cost().saturating_mul(u128::from(env::storage_usage()));
+    let backed = NearToken::from_near(2).saturating_add(near_token(storage.available)).saturating_add(storage_stake);
+    testing_env!(ctx.account_balance(backed).build());
+    assert!(contract.is_solvent());
+    assert_eq!(contract.get_surplus(), U128(0));
+}
+
+#[test]
+fn health_reports_a_paused_solvent_contract() {
+    let (mut ctx, mut contract) = setup(100);
+    deposit_as(&mut ctx, &mut contract, accounts(1), "a", NearToken::from_near(1));
+    deposit_as(&mut ctx, &mut contract, accounts(1), "b", NearToken::from_near(10));
+    testing_env!(ctx
+        .predecessor_account_id(accounts(0))
+        .attached_deposit(NearToken::from_yoctonear(0))
+        .account_balance(NearToken::from_near(100))
+        .build());
+    contract.set_deposits_paused(true, false);
+    
+    let (next_index, capacity, _) = contract.get_tree_info();
+    assert_eq!(contract.get_health(), HealthStatus {
+        is_solvent: true,
+        pause_state: PauseState::Maintenance,
+        migration_in_progress: false,
+        insolvency_halt: false,
+        total_deposits: 2,
+        tree_fill_basis_points: (next_index * 10000 / capacity) as u16,
+        accumulated_fees: U128(0),
+    });
+    
+    // Con un saldo que no cubre las notas, la solvencia pasa a rojo
+    testing_env!(ctx.account_balance(NearToken::from_near(5)).build());
+    assert!(!contract.get_health().is_solvent);
+}
+
+#[test]
+fn detected_insolvency_pauses_contract_and_halts_withdrawals() {
+    let (mut ctx, mut contract) = setup(100);
+    contract.set_auto_pause_on_insolvency(true);
+    deposit_as(&mut ctx, &mut contract, accounts(1), "a", NearToken::from_near(10));
+    deposit_as(&mut ctx, &mut contract, accounts(1), "b", NearToken::from_near(10));
+    
+    // Saldo muy por debajo de las notas depositadas: el retiro no se procesa y el contrato se pausa
+    testing_env!(ctx
+        .attached_deposit(NearToken::from_yoctonear(0))
+        .account_balance(NearToken::from_near(5))
+        .block_timestamp(env::block_timestamp() + MIN_DELAY * 1_000_000_000)
+        .build());
+    assert_eq!(contract.withdraw(accounts(2), "a".to_string(), None, None, None), None);
+    assert!(transfers().is_empty());
+    assert!(get_logs().iter().any(|log| log.contains("\"event\":\"insolvency_detected\"")));
+    assert!(contract.get_deposits_paused());
+    assert!(contract.get_health().insolvency_halt);
+    assert_eq!(contract.get_total_deposits(), 2);
+    
+    let again = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        contract.withdraw(accounts(2), "a".to_string(), None, None, None);
+    }));
+    assert!(again.is_err());
+    
+    // Con el saldo repuesto, el owner levanta la detención y el retiro se completa
+    testing_env!(ctx.predecessor_account_id(accounts(0)).account_balance(NearToken::from_near(100)).build());
+    contract.clear_insolvency_halt();
+    withdraw_after_delay(&mut ctx, &mut contract, accounts(2), "a");
+    assert_eq!(transfers(), vec![(accounts(2), NearToken::from_millinear(9_900))]);
+}
+
+#[test]
+#[should_panic(expected = "Withdrawals are halted after an insolvency was detected")]
+fn insolvency_halt_blocks_batch_withdraw_entries_and_split() {
+    let (mut ctx, mut contract) = setup(100);
+    contract.set_auto_pause_on_insolvency(true);
+    deposit_as(&mut ctx, &mut contract, accounts(1), "a", NearToken::from_near(10));
+    testing_env!(ctx
+        .attached_deposit(NearToken::from_yoctonear(0))
+        .account_balance(NearToken::from_near(1))
+        .block_timestamp(env::block_timestamp() + MIN_DELAY * 1_000_000_000)
+        .build());
+    assert_eq!(contract.withdraw(accounts(2), "a".to_string(), None, None, None), None);
+    
+    testing_env!(ctx.predecessor_account_id(accounts(5)).build());
+    assert_eq!(contract.batch_withdraw(vec![withdraw_request(accounts(2), "a", NearToken::from_yoctonear(0))]), vec![
+        BatchResult {
+            index: 0,
+            status: BatchItemStatus::Skipped,
+            detail: Some("Withdrawals are halted after an insolvency was detected".to_string()),
+        },
+    ]);
+    contract.withdraw_split("a".to_string(), vec![(accounts(2), yocto(NearToken::from_millinear(9_900)))]);
+}
+
+#[test]
+fn obligations_and_storage_stake_fit_in_a_healthy_balance() {
+    let (mut ctx, mut contract) = setup(100);
+    deposit_as(&mut ctx, &mut contract, accounts(1), "a", NearToken::from_near(1));
+    deposit_as(&mut ctx, &mut contract, accounts(1), "b", NearToken::from_near(10));
+    testing_env!(ctx.attached_deposit(NearToken::from_yoctonear(0)).account_balance(NearToken::from_near(11)).build());
+    let staked = contract.get_storage_staked();
+    assert_eq!(staked.0, env::storage_byte_cost().as_yoctonear() * u128::from(env::storage_usage()));
+    
+    // Un contrato sano tiene las notas depositadas más el staking de su almacenamiento
+    testing_env!(ctx.account_balance(NearToken::from_near(11).saturating_add(near_token(staked))).build());
+    assert!(contract.get_obligations().0 + contract.get_storage_staked().0 <= env::account_balance().as_yoctonear());
+    assert!(contract.is_solvent());
+    assert_eq!(contract.get_surplus(), U128(0));
+    
+    // El staking no cuenta como saldo disponible
+    testing_env!(ctx.account_balance(NearToken::from_near(11)).build());
+    assert!(!contract.is_solvent());
+}
+
+#[test]
+#[should_panic(expected = "No surplus to rescue")]
+fn rescue_surplus_refuses_to_touch_obligations() {
+    let (mut ctx, mut contract) = setup(0);
+    deposit_as(&mut ctx, &mut contract, accounts(1), "secret", NearToken::from_near(1));
+    testing_env!(ctx
+        .predecessor_account_id(accounts(0))
+        .attached_deposit(NearToken::from_yoctonear(0))
+        .account_balance(NearToken::from_near(1))
+        .build());
+    contract.rescue_surplus(accounts(3));
+}
+
+#[test]
+fn denomination_overview_reports_each_denomination_config() {
+    let (mut ctx, mut contract) = setup(100);
+    contract.set_denomination_fee(NearToken::from_near(10), Some(300));
+    contract.set_denomination_deprecated(NearToken::from_near(100), true);
+    contract.set_max_note_value(Some(yocto(NearToken::from_near(10))));
+    contract.set_max_tvl_for_denomination(NearToken::from_near(10), Some(yocto(NearToken::from_near(50))));
+    pass_fee_change_delay(&mut ctx);
+    deposit_as(&mut ctx, &mut contract, accounts(1), "a", NearToken::from_near(1));
+    deposit_as(&mut ctx, &mut contract, accounts(1), "b", NearToken::from_near(1));
+    deposit_as(&mut ctx, &mut contract, accounts(1), "c", NearToken::from_near(10));
+    let now = env::block_timestamp();
+    
+    assert_eq!(contract.get_denomination_overview(), vec![
+        DenominationOverview {
+            denomination: yocto(NearToken::from_near(1)),
+            active_notes: 2,
+            total_amount: yocto(NearToken::from_near(2)),
+            fee_basis_points: 100,
+            withdrawal_fee: yocto(NearToken::from_millinear(10)),
+            min_delay_seconds: MIN_DELAY,
+            deprecated: false,
+            accepts_deposits: true,
+            active_at: None,
+            last_deposit_timestamp: Some(now),
+            max_tvl: None,
+        },
+        DenominationOverview {
+            denomination: yocto(NearToken::from_near(10)),
+            active_notes: 1,
+            total_amount: yocto(NearToken::from_near(10)),
+            fee_basis_points: 300,
+            withdrawal_fee: yocto(NearToken::from_millinear(300)),
+            min_delay_seconds: MIN_DELAY,
+            deprecated: false,
+            accepts_deposits: true,
+            active_at: None,
+            last_deposit_timestamp: Some(now),
+            max_tvl: Some(yocto(NearToken::from_near(50))),
+        },
+        DenominationOverview {
+            denomination: yocto(NearToken::from_near(100)),
+            active_notes: 0,
+            total_amount: U128(0),
+            fee_basis_points: 100,
+            withdrawal_fee: yocto(NearToken::from_near(1)),
+            min_delay_seconds: MIN_DELAY,
+            deprecated: true,
+            accepts_deposits: false,
+            active_at: None,
+            last_deposit_timestamp: None,
+            max_tvl: None,
+        },
+    ]);
+}
+
+#[test]
+fn reenabled_denomination_waits_for_activation_delay() {
+    let (mut ctx, mut contract) = setup(100);
+    let denomination = NearToken::from_near(10);
+    contract.set_denomination_deprecated(denomination, true);
+    contract.set_denomination_activation_delay(3_600);
+    contract.set_denomination_deprecated(denomination, false);
+    let active_at = env::block_timestamp() + 3_600 * 1_000_000_000;
+    
+    let overview = contract.get_denomination_overview();
+    assert_eq!(overview[1].active_at, Some(active_at));
+    assert!(!overview[1].accepts_deposits);
+    testing_env!(ctx.predecessor_account_id(accounts(1)).attached_deposit(denomination).build());
+    let early = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        contract.deposit(commitment_for("early"), None, None, None, None);
+    }));
+    assert!(early.is_err());
+    
+    testing_env!(ctx.block_timestamp(active_at).build());
+    contract.deposit(commitment_for("on-time"), None, None, None, None);
+    let overview = contract.get_denomination_overview();
+    assert_eq!(overview[1].active_notes, 1);
+    assert!(overview[1].accepts_deposits);
+}
+
+#[test]
+#[should_panic(expected = "This denomination is not active yet")]
+fn deposit_rejects_denomination_before_activation() {
+    let (mut ctx, mut contract) = setup(100);
+    contract.set_denomination_activation_delay(60);
+    contract.set_denomination_deprecated(NearToken::from_near(1), true);
+    contract.set_denomination_deprecated(NearToken::from_near(1), false);
+    deposit_as(&mut ctx, &mut contract, accounts(1), "secret", NearToken::from_near(1));
+}
+
+#[test]
+fn accumulated_fees_equal_sum_of_withdrawal_fees() {
+    let (mut ctx, mut contract) = setup(100);
+    deposit_as(&mut ctx, &mut contract, accounts(1), "a", NearToken::from_near(1));
+    deposit_as(&mut ctx, &mut contract, accounts(1), "b", NearToken::from_near(10));
+    deposit_as(&mut ctx, &mut contract, accounts(1), "c", NearToken::from_near(1));
+    
+    let withdrawals = [
+        ("a", NearToken::from_near(1), PromiseResult::Successful(vec![])),
+        ("b", NearToken::from_near(10), PromiseResult::Failed),
+        ("b", NearToken::from_near(10), PromiseResult::Successful(vec![])),
+        ("c", NearToken::from_near(1), PromiseResult::Successful(vec![])),
+    ];
+    for (secret, denomination, result) in withdrawals {
+        withdraw_after_delay(&mut ctx, &mut contract, accounts(2), secret);
+        let fee = NearToken::from_yoctonear(denomination.as_yoctonear() / 100);
+        // Entre el retiro y su callback la comisión queda reservada, no acreditada ni como excedente
+        assert_eq!(contract.in_flight_fees, fee);
+        resolve_withdrawal(&mut ctx, &mut contract, accounts(2), secret, note(denomination), fee, result);
+        assert_eq!(contract.in_flight_fees, NearToken::from_yoctonear(0));
+    }
+    
+    // El retiro fallido de "b" no cobra comisión; su reintento sí
+    assert_eq!(contract.get_sweepable_fees(), yocto(NearToken::from_millinear(10 + 100 + 10)));
+}
+
+#[test]
+fn denomination_stats_are_sorted_ascending_regardless_of_deposit_order() {
+    let (mut ctx, mut contract) = setup(0);
+    for (secret, near) in [("a", 100), ("b", 1), ("c", 10), ("d", 100)] {
+        deposit_as(&mut ctx, &mut contract, accounts(1), secret, NearToken::from_near(near));
+    }
+    
+    let stats = contract.get_all_denomination_stats();
+    assert!(stats.windows(2).all(|pair| pair[0].0 < pair[1].0));
+    assert_eq!(contract.get_pool_stats().2, stats);
+    let overview = contract.get_denomination_overview();
+    assert!(overview.windows(2).all(|pair| pair[0].denomination.0 < pair[1].denomination.0));
+    assert_eq!(stats, vec![
+        (NearToken::from_near(1), 1),
+        (NearToken::from_near(10), 1),
+        (NearToken::from_near(100), 2),
+    ]);
+}
+
+/// Retira `secret` a `accounts(2)` aceptando la penalización, sin esperar el plazo
+fn withdraw_early(ctx: &mut VMContextBuilder, contract: &mut UtxoMixer, secret: &str) {
+    testing_env!(ctx.attached_deposit(NearToken::from_yoctonear(0)).build());
+    contract.withdraw(accounts(2), secret.to_string(), None, None, Some(true));
+}
+
+#[test]
+fn early_withdrawal_charges_penalty_and_normal_fee_after_delay() {
+    let (mut ctx, mut contract) = setup(100);
+    contract.set_early_withdrawal_penalty_basis_points(1000);
+    deposit_as(&mut ctx, &mut contract, accounts(1), "early", NearToken::from_near(1));
+    deposit_as(&mut ctx, &mut contract, accounts(1), "late", NearToken::from_near(1));
+    
+    withdraw_early(&mut ctx, &mut contract, "early");
+    assert_eq!(transfers(), vec![(accounts(2), NearToken::from_millinear(900))]);
+    assert_eq!(contract.in_flight_fees, NearToken::from_millinear(100));
+    
+    // Después del plazo se cobra la comisión normal, acepte o no la penalización
+    testing_env!(ctx.block_timestamp(env::block_timestamp() + MIN_DELAY * 1_000_000_000).build());
+    withdraw_early(&mut ctx, &mut contract, "late");
+    assert_eq!(transfers(), vec![(accounts(2), NearToken::from_millinear(990))]);
+}
+
+/// Cotiza con `get_effective_fee` la nota de `secret` y la retira en el mismo bloque, aceptando la
+/// penalización; devuelve (comisión cotizada, comisión cobrada)
+fn quote_and_withdraw(ctx: &mut VMContextBuilder, contract: &mut UtxoMixer, secret: &str, fee_mode: FeeMode) -> (U128, U128) {
+    let deposited_at = contract.get_deposit(&commitment_for(secret)).unwrap().timestamp;
+    testing_env!(ctx.attached_deposit(NearToken::from_yoctonear(0)).build());
+    let quote = contract.get_effective_fee(NearToken::from_near(1), accounts(2), Some(fee_mode), Some(deposited_at));
+    contract.withdraw(accounts(2), secret.to_string(), None, None, Some(true));
+    let paid = transfers()[0].1;
+    (quote, U128(NearToken::from_near(1).as_yoctonear() - paid.as_yoctonear()))
+}
+
+#[test]
+fn effective_fee_matches_the_fee_charged_by_withdraw() {
+    let (mut ctx, mut contract) = setup(100);
+    contract.set_early_withdrawal_penalty_basis_points(1000);
+    deposit_as(&mut ctx, &mut contract, accounts(1), "early", NearToken::from_near(1));
+    deposit_as(&mut ctx, &mut contract, accounts(1), "postpaid", NearToken::from_near(1));
+    testing_env!(ctx.predecessor_account_id(accounts(0)).attached_deposit(NearToken::from_yoctonear(0)).build());
+    contract.set_fee_mode(FeeMode::AtDeposit);
+    deposit_as(&mut ctx, &mut contract, accounts(1), "prepaid", NearToken::from_millinear(1_010));
+    
+    // Antes del plazo, la penalización
+    let (quote, charged) = quote_and_withdraw(&mut ctx, &mut contract, "early", FeeMode::AtWithdraw);
+    assert_eq!((quote, charged), (yocto(NearToken::from_millinear(100)), yocto(NearToken::from_millinear(100))));
+    
+    // Tras el plazo, la comisión normal, o ninguna si se pagó al depositar
+    testing_env!(ctx.block_timestamp(env::block_timestamp() + MIN_DELAY * 1_000_000_000).build());
+    let (quote, charged) = quote_and_withdraw(&mut ctx, &mut contract, "postpaid", FeeMode::AtWithdraw);
+    assert_eq!((quote, charged), (yocto(NearToken::from_millinear(10)), yocto(NearToken::from_millinear(10))));
+    let (quote, charged) = quote_and_withdraw(&mut ctx, &mut contract, "prepaid", FeeMode::AtDeposit);
+    assert_eq!((quote, charged), (U128(0), U128(0)));
+    
+    // En la salida de emergencia una nota reciente paga la comisión normal, sin penalización
+    testing_env!(ctx.predecessor_account_id(accounts(0)).build());
+    contract.set_fee_mode(FeeMode::AtWithdraw);
+    deposit_as(&mut ctx, &mut contract, accounts(1), "rushed", NearToken::from_near(1));
+    testing_env!(ctx.predecessor_account_id(accounts(0)).attached_deposit(NearToken::from_yoctonear(0)).build());
+    contract.set_deposits_paused(true, true);
+    let (quote, charged) = quote_and_withdraw(&mut ctx, &mut contract, "rushed", FeeMode::AtWithdraw);
+    assert_eq!((quote, charged), (yocto(NearToken::from_millinear(10)), yocto(NearToken::from_millinear(10))));
+}
+
+#[test]
+#[should_panic(expected = "Withdrawal too early")]
+fn early_withdrawal_is_rejected_when_penalty_is_disabled() {
+    let (mut ctx, mut contract) = setup(100);
+    deposit_as(&mut ctx, &mut contract, accounts(1), "secret", NearToken::from_near(1));
+    withdraw_early(&mut ctx, &mut contract, "secret");
+}
+
+#[test]
+fn compute_nullifier_hash_matches_hash_marked_by_withdraw() {
+    let (mut ctx, mut contract) = setup(0);
+    let legacy = DepositInfo { scheme_version: SCHEME_V1, ..note(NearToken::from_near(1)) };
+    contract.store_deposit(&commitment_for_scheme("old", SCHEME_V1, HashScheme::Sha256), &legacy);
+    deposit_as(&mut ctx, &mut contract, accounts(1), "new", NearToken::from_near(1));
+    
+    for secret in ["old", "new"] {
+        let predicted = contract.compute_nullifier_hash(secret.to_string());
+        withdraw_after_delay(&mut ctx, &mut contract, accounts(2), secret);
+        assert!(contract.spent_outputs.contains(&predicted));
+    }
+    // Sin nota activa, el hash se calcula con el esquema actual
+    assert_eq!(contract.compute_nullifier_hash("old".to_string()),
+        format!("{:x}", Sha256::digest("withdraw:v3:mixer.testnet:old".as_bytes())));
+}
+
+#[test]
+fn notes_under_old_and_new_hash_schemes_both_withdraw() {
+    let (mut ctx, mut contract) = setup(0);
+    deposit_as(&mut ctx, &mut contract, accounts(1), "sha", NearToken::from_near(1));
+    
+    testing_env!(ctx.predecessor_account_id(accounts(0)).attached_deposit(NearToken::from_yoctonear(0)).build());
+    contract.set_hash_scheme_accepted(HashScheme::Keccak256, true);
+    contract.set_hash_scheme_accepted(HashScheme::Sha256, false);
+    assert_eq!(contract.get_accepted_hash_schemes(), vec![HashScheme::Keccak256]);
+    
+    testing_env!(ctx.predecessor_account_id(accounts(1)).attached_deposit(NearToken::from_near(1)).build());
+    let keccak_commitment = commitment_for_scheme("keccak", CURRENT_SCHEME_VERSION, HashScheme::Keccak256);
+    assert_eq!(keccak_commitment, to_hex(&env::keccak256(b"commit:mixer.testnet:keccak")));
+    contract.deposit(keccak_commitment.clone(), None, Some(HashScheme::Keccak256), None, None);
+    assert_eq!(contract.get_deposit(&keccak_commitment).unwrap().hash_scheme, HashScheme::Keccak256);
+    
+    // La nota SHA-256 sigue retirándose aunque la función ya no se acepte para depósitos nuevos
+    for secret in ["sha", "keccak"] {
+        let predicted = contract.compute_nullifier_hash(secret.to_string());
+        withdraw_after_delay(&mut ctx, &mut contract, accounts(2), secret);
+        assert!(contract.spent_outputs.contains(&predicted));
+    }
+    assert_eq!(contract.get_total_deposits(), 0);
+}
+
+#[test]
+fn deposit_rejects_unaccepted_or_mismatched_hash_scheme() {
+    let (mut ctx, mut contract) = setup(0);
+    testing_env!(ctx.predecessor_account_id(accounts(1)).attached_deposit(NearToken::from_near(1)).build());
+    let unaccepted = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        contract.deposit(commitment_for_scheme("s", CURRENT_SCHEME_VERSION, HashScheme::Keccak256), None,
+            Some(HashScheme::Keccak256), None, None);
+    }));
+    assert!(unaccepted.is_err());
+    let malformed = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        contract.deposit("not-a-digest".to_string(), None, None, None, None);
+    }));
+    assert!(malformed.is_err());
+    assert_eq!(contract.get_total_deposits(), 0);
+    
+    testing_env!(ctx.predecessor_account_id(accounts(0)).attached_deposit(NearToken::from_yoctonear(0)).build());
+    let last = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        contract.set_hash_scheme_accepted(HashScheme::Sha256, false);
+    }));
+    assert!(last.is_err());
+}
+
+#[test]
+fn strict_mode_rejects_commitments_outside_the_field() {
+    let (mut ctx, mut contract) = setup(0);
+    assert!(!contract.get_strict_field_elements());
+    let out_of_range = "f".repeat(64);
+    let max_element = "30644e72e131a029b85045b68181585d2833e84879b9709143e1f593f0000000".to_string();
+    
+    // Sin modo estricto se acepta cualquier digest
+    testing_env!(ctx.predecessor_account_id(accounts(1)).attached_deposit(NearToken::from_near(1)).build());
+    contract.deposit("e".repeat(64), None, None, None, None);
+    
+    testing_env!(ctx.predecessor_account_id(accounts(0)).attached_deposit(NearToken::from_yoctonear(0)).build());
+    contract.set_strict_field_elements(true);
+    testing_env!(ctx.predecessor_account_id(accounts(1)).attached_deposit(NearToken::from_near(1)).build());
+    for commitment in [out_of_range, BN254_FIELD_MODULUS_HEX.to_string()] {
+        let rejected = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            contract.deposit(commitment.clone(), None, None, None, None);
+        }));
+        assert!(rejected.is_err());
+    }
+    contract.deposit(max_element, None, None, None, None);
+    assert_eq!(contract.get_total_deposits(), 2);
+}
+
+#[test]
+fn pre_migration_commitment_cannot_be_deposited_again() {
+    let (mut ctx, _) = setup(0);
+    let legacy = migration::LegacyUtxoMixer {
+        deposits: LookupMap::new(b"d"),
+        spent_outputs: UnorderedSet::new(b"s"),
+        owner: accounts(0),
+        fee_basis_points: 0,
+        deposit_counts: LookupMap::new(b"c"),
+    };
+    let legacy_commitment = commitment_for_scheme("old-secret", SCHEME_V1, HashScheme::Sha256);
+    env::storage_write(&[b"d".as_slice(), &borsh::to_vec(&legacy_commitment).unwrap()].concat(),
+        &borsh::to_vec(&(NearToken::from_near(1), 0u64)).unwrap());
+    env::state_write(&legacy);
+    let mut contract = UtxoMixer::migrate();
+    
+    let redeposit = |ctx: &mut VMContextBuilder, contract: &mut UtxoMixer, commitment: String| {
+        testing_env!(ctx.predecessor_account_id(accounts(1)).attached_deposit(NearToken::from_near(1)).build());
+        std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| contract.deposit(commitment, None, None, None, None))).is_err()
+    };
+    // Mientras la nota heredada está activa, y después de retirarla, en ambos formatos de clave
+    assert!(redeposit(&mut ctx, &mut contract, legacy_commitment.clone()));
+    withdraw_after_delay(&mut ctx, &mut contract, accounts(2), "old-secret");
+    assert!(redeposit(&mut ctx, &mut contract, legacy_commitment.clone()));
+    assert!(redeposit(&mut ctx, &mut contract, legacy_commitment.to_uppercase()));
+    testing_env!(ctx.predecessor_account_id(accounts(1)).attached_deposit(NearToken::from_near(1)).build());
+    let mut raw = [0u8; 32];
+    raw.copy_from_slice(&Sha256::digest("old-secret".as_bytes()));
+    assert!(std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| contract.deposit_raw(raw, None, None, None, None))).is_err());
+    assert_eq!(contract.get_total_deposits(), 0);
+}
+
+#[test]
+#[should_panic(expected = "Commitment was already used")]
+fn withdrawn_commitment_cannot_be_deposited_again() {
+    let (mut ctx, mut contract) = setup(0);
+    deposit_as(&mut ctx, &mut contract, accounts(1), "secret", NearToken::from_near(1));
+    withdraw_after_delay(&mut ctx, &mut contract, accounts(2), "secret");
+    deposit_as(&mut ctx, &mut contract, accounts(1), "secret", NearToken::from_near(1));
+}
+
+#[test]
+fn withdrawal_forwards_fees_to_treasury_once_threshold_is_crossed() {
+    let (mut ctx, mut contract) = setup(100);
+    assert_eq!(contract.get_auto_sweep_threshold(), None);
+    contract.set_auto_sweep_threshold(Some(yocto(NearToken::from_millinear(15))));
+    for secret in ["a", "b", "c"] {
+        deposit_as(&mut ctx, &mut contract, accounts(1), secret, NearToken::from_near(1));
+    }
+    
+    for secret in ["a", "b"] {
+        withdraw_after_delay(&mut ctx, &mut contract, accounts(2), secret);
+        assert_eq!(transfers(), vec![(accounts(2), NearToken::from_millinear(990))]);
+        let deposit = note(NearToken::from_near(1));
+        assert!(resolve_withdrawal(&mut ctx, &mut contract, accounts(2), secret, deposit,
+            NearToken::from_millinear(10), PromiseResult::Successful(vec![])));
+    }
+    assert_eq!(contract.accumulated_fees, NearToken::from_millinear(20));
+    
+    // Las comisiones ya acreditadas superan el umbral: el retiro también las envía a la tesorería
+    withdraw_after_delay(&mut ctx, &mut contract, accounts(2), "c");
+    assert_eq!(transfers(), vec![
+        (accounts(0), NearToken::from_millinear(20)),
+        (accounts(2), NearToken::from_millinear(990)),
+    ]);
+    assert!(contract.accumulated_fees.is_zero());
+    assert_eq!(contract.in_flight_fees, NearToken::from_millinear(10));
+}
+
+#[test]
+fn withdrawal_gas_does_not_grow_with_pool_size() {
+    let (mut ctx, mut contract) = setup(0);
+    let withdrawal_gas = |ctx: &mut VMContextBuilder, contract: &mut UtxoMixer, secret: &str| {
+        deposit_as(ctx, contract, accounts(1), secret, NearToken::from_near(1));
+        withdraw_after_delay(ctx, contract, accounts(2), secret);
+        env::used_gas().as_gas()
+    };
+    let small_pool = withdrawal_gas(&mut ctx, &mut contract, "probe-1");
+    assert!(small_pool > 0);
+    
+    // Miles de notas activas y de hashes de retiro usados; el contexto se renueva para no agotar el gas
+    for i in 0..2000 {
+        if i % 100 == 0 {
+            testing_env!(ctx.build());
+        }
+        contract.register_note(&commitment_for(&format!("filler-{}", i)),
+            DepositInfo::new(NearToken::from_near(1), FeeMode::AtWithdraw, HashScheme::Sha256));
+        contract.spent_outputs.insert(&format!("spent-{}", i));
+    }
+    let large_pool = withdrawal_gas(&mut ctx, &mut contract, "probe-2");
+    
+    assert!(large_pool <= small_pool + small_pool / 20, "withdraw gas grew from {} to {}", small_pool, large_pool);
+}
+
+#[test]
+fn frozen_denomination_rejects_deposits_while_others_continue() {
+    let (mut ctx, mut contract) = setup(0);
+    deposit_as(&mut ctx, &mut contract, accounts(1), "ten", NearToken::from_near(10));
+    
+    testing_env!(ctx.predecessor_account_id(accounts(0)).attached_deposit(NearToken::from_yoctonear(0)).build());
+    contract.set_denomination_frozen(NearToken::from_near(10), true);
+    assert_eq!(contract.get_frozen_denominations(), vec![NearToken::from_near(10)]);
+    
+    let frozen = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        deposit_as(&mut ctx, &mut contract, accounts(1), "ten-2", NearToken::from_near(10));
+    }));
+    assert!(frozen.is_err());
+    deposit_as(&mut ctx, &mut contract, accounts(1), "one", NearToken::from_near(1));
+    assert_eq!(contract.deposit_counts.get(&NearToken::from_near(10)), Some(1));
+    assert_eq!(contract.deposit_counts.get(&NearToken::from_near(1)), Some(1));
+    
+    // Por defecto las notas existentes del pool congelado pueden salir
+    withdraw_after_delay(&mut ctx, &mut contract, accounts(2), "ten");
+    assert_eq!(transfers(), vec![(accounts(2), NearToken::from_near(10))]);
+}
+
+#[test]
+#[should_panic(expected = "This denomination is frozen")]
+fn frozen_denomination_blocks_withdrawals_when_configured() {
+    let (mut ctx, mut contract) = setup(0);
+    deposit_as(&mut ctx, &mut contract, accounts(1), "ten", NearToken::from_near(10));
+    testing_env!(ctx.predecessor_account_id(accounts(0)).attached_deposit(NearToken::from_yoctonear(0)).build());
+    contract.set_denomination_frozen(NearToken::from_near(10), true);
+    contract.set_freeze_blocks_withdrawals(true);
+    withdraw_after_delay(&mut ctx, &mut contract, accounts(2), "ten");
+}
+
+/// Contrato de screening simulado: responde a la llamada `is_allowed` programada por el último
+/// `withdraw` y ejecuta `aml_resolve` con su respuesta
+fn screen_last_withdrawal(
+    ctx: &mut VMContextBuilder,
+    contract: &mut UtxoMixer,
+    is_allowed: impl Fn(&AccountId) -> bool,
+) -> PromiseOrValue<bool> {
+    let calls: Vec<(AccountId, Vec<u8>, Vec<u8>)> = get_created_receipts()
+        .into_iter()
+        .flat_map(|receipt| {
+            let receiver = receipt.receiver_id.clone();
+            receipt.actions.into_iter().filter_map(move |action| match action {
+                MockAction::FunctionCallWeight { method_name, args, .. } => Some((receiver.clone(), method_name, args)),
+                _ => None,
+            })
+        })
+        .collect();
+    let (aml, _, check_args) = calls.iter().find(|(_, method, _)| method == b"is_allowed").unwrap();
+    assert_eq!(aml, &"aml.testnet".parse::<AccountId>().unwrap());
+    let account: AccountId = near_sdk::serde_json::from_slice::<near_sdk::serde_json::Value>(check_args).unwrap()
+        ["account_id"].as_str().unwrap().parse().unwrap();
+    let (_, _, resolve_args) = calls.iter().find(|(_, method, _)| method == b"aml_resolve").unwrap();
+    let pending = near_sdk::serde_json::from_slice::<near_sdk::serde_json::Value>(resolve_args).unwrap()["pending"].clone();
+    
+    testing_env!(ctx.predecessor_account_id(env::current_account_id()).build());
+    contract.aml_resolve(near_sdk::serde_json::from_value(pending).unwrap(), Ok(is_allowed(&account)))
+}
+
+#[test]
+#[should_panic(expected = "Relay-key notes are not available while AML screening is enabled")]
+fn aml_screening_rejects_relay_key_deposits() {
+    let (mut ctx, mut contract) = setup(100);
+    contract.set_aml_contract(Some("aml.testnet".parse().unwrap()));
+    let relay_key = PublicKey::from_parts(CurveType::ED25519, vec![7; 32]).unwrap();
+    testing_env!(ctx.predecessor_account_id(accounts(1)).attached_deposit(NearToken::from_near(1)).build());
+    contract.deposit(commitment_for("secret"), None, None, None, Some(relay_key));
+}
+
+#[test]
+fn aml_screening_approves_one_recipient_and_rejects_another() {
+    let (mut ctx, mut contract) = setup(100);
+    contract.set_aml_contract(Some("aml.testnet".parse().unwrap()));
+    deposit_as(&mut ctx, &mut contract, accounts(1), "approved", NearToken::from_near(1));
+    deposit_as(&mut ctx, &mut contract, accounts(1), "rejected", NearToken::from_near(1));
+    let screening = |account: &AccountId| account == &accounts(2);
+    
+    withdraw_after_delay(&mut ctx, &mut contract, accounts(2), "approved");
+    assert!(transfers().is_empty());
+    assert!(matches!(screen_last_withdrawal(&mut ctx, &mut contract, screening), PromiseOrValue::Promise(_)));
+    assert_eq!(transfers(), vec![(accounts(2), NearToken::from_millinear(990))]);
+    assert!(contract.spent_outputs.contains(&withdrawal_hash_for("approved")));
+    
+    // El destinatario rechazado no recibe nada y la nota vuelve a estar disponible
+    withdraw_after_delay(&mut ctx, &mut contract, accounts(3), "rejected");
+    assert!(matches!(screen_last_withdrawal(&mut ctx, &mut contract, screening), PromiseOrValue::Value(false)));
+    assert!(transfers().is_empty());
+    assert!(!contract.spent_outputs.contains(&withdrawal_hash_for("rejected")));
+    assert!(contract.get_deposit(&commitment_for("rejected")).is_some());
+    assert_eq!(contract.in_flight_fees, NearToken::from_millinear(10));
+}
+
+#[test]
+#[should_panic(expected = "Batch withdrawals are not available while AML screening is enabled")]
+fn batch_withdraw_is_unavailable_with_aml_screening() {
+    let (mut ctx, mut contract) = setup(0);
+    contract.set_aml_contract(Some("aml.testnet".parse().unwrap()));
+    deposit_as(&mut ctx, &mut contract, accounts(1), "secret", NearToken::from_near(1));
+    testing_env!(ctx.attached_deposit(NearToken::from_yoctonear(0)).build());
+    contract.batch_withdraw(vec![WithdrawRequest { recipient: accounts(2), secret: "secret".to_string(), relayer_fee: None, relay_signature: None }]);
+}
+
+#[test]
+fn obligations_by_denomination_match_active_notes() {
+    let (mut ctx, mut contract) = setup(0);
+    for (secret, amount) in [("a", 1), ("b", 10), ("c", 10), ("d", 1), ("e", 1)] {
+        deposit_as(&mut ctx, &mut contract, accounts(1), secret, NearToken::from_near(amount));
+    }
+    withdraw_after_delay(&mut ctx, &mut contract, accounts(2), "d");
+    
+    assert_eq!(contract.get_obligations_by_denomination(), vec![
+        (NearToken::from_near(1), yocto(NearToken::from_near(2))),
+        (NearToken::from_near(10), yocto(NearToken::from_near(20))),
+        (NearToken::from_near(100), U128(0)),
+    ]);
+    assert_eq!(contract.get_obligations(), yocto(NearToken::from_near(22)));
+}
+
+#[test]
+fn reserves_attestation_reflects_balance_and_obligations() {
+    let (mut ctx, mut contract) = setup(100);
+    deposit_as(&mut ctx, &mut contract, accounts(1), "a", NearToken::from_near(1));
+    deposit_as(&mut ctx, &mut contract, accounts(1), "b", NearToken::from_near(10));
+    contract.accumulated_fees = NearToken::from_millinear(5);
+    
+    testing_env!(ctx.block_height(42).block_timestamp(1_000).account_balance(NearToken::from_near(20)).build());
+    assert_eq!(contract.get_reserves_attestation(), ReservesAttestation {
+        account_balance: yocto(env::account_balance()),
+        total_obligations: yocto(NearToken::from_near(11)),
+        accumulated_fees: yocto(NearToken::from_millinear(5)),
+        block_height: 42,
+        block_timestamp: 1_000,
+    });
+}
+
+#[test]
+fn batch_entry_with_relayer_as_recipient_is_skipped() {
+    let (mut ctx, mut contract) = setup(0);
+    for secret in ["self", "other"] {
+        deposit_as(&mut ctx, &mut contract, accounts(1), secret, NearToken::from_near(1));
+    }
+    
+    testing_env!(ctx
+        .predecessor_account_id(accounts(5))
+        .attached_deposit(NearToken::from_yoctonear(0))
+        .block_timestamp(env::block_timestamp() + MIN_DELAY * 1_000_000_000)
+        .build());
+    let relayer_fee = NearToken::from_millinear(10);
+    let results = contract.batch_withdraw(vec![
+        withdraw_request(accounts(5), "self", relayer_fee),
+        withdraw_request(accounts(2), "other", relayer_fee),
+    ]);
+    
+    assert_eq!(results, vec![
+        BatchResult { index: 0, status: BatchItemStatus::Skipped, detail: Some("The relayer cannot be the recipient".to_string()) },
+        BatchResult { index: 1, status: BatchItemStatus::Queued, detail: Some(withdrawal_hash_for("other")) },
+    ]);
+    assert!(contract.get_deposit(&commitment_for("self")).is_some());
+}
+
+#[test]
+fn root_outside_configured_history_is_unknown() {
+    let mut ctx = context(accounts(0));
+    testing_env!(ctx.build());
+    let mut contract = UtxoMixer::new(accounts(0), 0, None, Some(2), None);
+    assert_eq!(contract.get_root_history_size(), 2);
+    let empty_root = contract.get_merkle_root();
+    assert!(contract.is_known_root(empty_root.clone()));
+    
+    deposit_as(&mut ctx, &mut contract, accounts(1), "a", NearToken::from_near(1));
+    let first_root = contract.get_merkle_root();
+    assert!(contract.is_known_root(empty_root.clone()));
+    
+    // Con dos posiciones, la raíz vacía sale del historial al entrar el segundo depósito
+    deposit_as(&mut ctx, &mut contract, accounts(1), "b", NearToken::from_near(1));
+    assert!(!contract.is_known_root(empty_root));
+    assert!(contract.is_known_root(first_root));
+    assert!(contract.is_known_root(contract.get_merkle_root()));
+    assert!(!contract.is_known_root("unknown".to_string()));
+}
+
+#[test]
+#[should_panic(expected = "Root history size must be between 2 and 256")]
+fn root_history_size_is_bounded() {
+    testing_env!(context(accounts(0)).build());
+    UtxoMixer::new(accounts(0), 0, None, Some(257), None);
+}
+
+#[test]
+fn maintenance_pause_blocks_deposits_but_keeps_the_time_lock() {
+    let (mut ctx, mut contract) = setup(100);
+    assert_eq!(contract.get_pause_state(), PauseState::Running);
+    deposit_as(&mut ctx, &mut contract, accounts(1), "early", NearToken::from_near(1));
+    deposit_as(&mut ctx, &mut contract, accounts(1), "secret", NearToken::from_near(1));
+    
+    testing_env!(ctx.predecessor_account_id(accounts(0)).attached_deposit(NearToken::from_yoctonear(0)).build());
+    contract.set_deposits_paused(true, false);
+    assert_eq!(contract.get_pause_state(), PauseState::Maintenance);
+    assert!(!contract.is_emergency_exit_active());
+    
+    let paused = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        deposit_as(&mut ctx, &mut contract, accounts(1), "other", NearToken::from_near(1));
+    }));
+    assert!(paused.is_err());
+    
+    // Los retiros siguen funcionando, pero sin saltear el plazo
+    testing_env!(ctx.attached_deposit(NearToken::from_yoctonear(0)).build());
+    let too_early = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        contract.withdraw(accounts(2), "early".to_string(), None, None, None);
+    }));
+    assert!(too_early.is_err());
+    withdraw_after_delay(&mut ctx, &mut contract, accounts(2), "secret");
+    assert_eq!(transfers(), vec![(accounts(2), NearToken::from_millinear(990))]);
+    
+    testing_env!(ctx.predecessor_account_id(accounts(0)).build());
+    contract.set_deposits_paused(true, true);
+    assert_eq!(contract.get_pause_state(), PauseState::Emergency);
+}
+
+#[test]
+fn emergency_pause_waives_time_lock_for_withdrawals() {
+    let (mut ctx, mut contract) = setup(100);
+    contract.set_early_withdrawal_penalty_basis_points(1000);
+    deposit_as(&mut ctx, &mut contract, accounts(1), "secret", NearToken::from_near(1));
+    
+    testing_env!(ctx.predecessor_account_id(accounts(0)).attached_deposit(NearToken::from_yoctonear(0)).build());
+    contract.set_deposits_paused(true, true);
+    assert!(contract.is_emergency_exit_active());
+    
+    // Sin esperar el plazo y sin penalización; el hash de retiro queda usado como siempre
+    testing_env!(ctx.attached_deposit(NearToken::from_yoctonear(0)).build());
+    contract.withdraw(accounts(2), "secret".to_string(), None, None, None);
+    assert_eq!(transfers(), vec![(accounts(2), NearToken::from_millinear(990))]);
+    assert!(contract.spent_outputs.contains(&withdrawal_hash_for("secret")));
+    
+    let paused = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        deposit_as(&mut ctx, &mut contract, accounts(1), "other", NearToken::from_near(1));
+    }));
+    assert!(paused.is_err());
+}
+
+#[test]
+#[should_panic(expected = "Withdrawal too early")]
+fn ordinary_pause_keeps_time_lock() {
+    let (mut ctx, mut contract) = setup(0);
+    deposit_as(&mut ctx, &mut contract, accounts(1), "secret", NearToken::from_near(1));
+    testing_env!(ctx.predecessor_account_id(accounts(0)).attached_deposit(NearToken::from_yoctonear(0)).build());
+    contract.set_deposits_paused(true, false);
+    assert!(!contract.is_emergency_exit_active());
+    contract.withdraw(accounts(2), "secret".to_string(), None, None, None);
+}
+
+#[test]
+fn fee_rounding_modes_on_fractional_fee() {
+    let (mut ctx, mut contract) = setup(100);
+    assert_eq!(contract.get_config().fee_rounding, FeeRounding::Down);
+    // Al 1%, 130 yoctoNEAR dan 1,3 de comisión y 150 dan 1,5
+    for (rounding, fee_130, fee_150) in [(FeeRounding::Down, 1, 1), (FeeRounding::Nearest, 1, 2), (FeeRounding::Up, 2, 2)] {
+        contract.set_fee_rounding(rounding);
+        assert_eq!(contract.get_config().fee_rounding, rounding);
+        assert_eq!(contract.base_fee(NearToken::from_yoctonear(130), None), fee_130);
+        assert_eq!(contract.base_fee(NearToken::from_yoctonear(150), None), fee_150);
+    }
+    // Redondear hacia arriba nunca supera el monto
+    assert_eq!(FeeRounding::Up.apply(1, HARD_MAX_FEE_BASIS_POINTS), 1);
+    
+    // El modo vigente se aplica al retirar
+    contract.set_flexible_pool_enabled(true);
+    let amount = NearToken::from_yoctonear(NearToken::from_near(1).as_yoctonear() + 150);
+    deposit_as(&mut ctx, &mut contract, accounts(1), "secret", amount);
+    withdraw_after_delay(&mut ctx, &mut contract, accounts(2), "secret");
+    let fee = NearToken::from_near(1).as_yoctonear() / 100 + 2;
+    assert_eq!(transfers(), vec![(accounts(2), NearToken::from_yoctonear(amount.as_yoctonear() - fee))]);
+}
+
+#[test]
+fn peek_next_root_matches_root_after_deposit() {
+    let (mut ctx, mut contract) = setup(0);
+    deposit_as(&mut ctx, &mut contract, accounts(1), "first", NearToken::from_near(1));
+    let root_before = contract.get_merkle_root();
+    
+    let predicted = contract.peek_next_root(commitment_for("second"));
+    assert_eq!(contract.get_merkle_root(), root_before);
+    assert_eq!(contract.get_tree_info().0, 1);
+    
+    deposit_as(&mut ctx, &mut contract, accounts(1), "second", NearToken::from_near(1));
+    assert_eq!(contract.get_merkle_root(), predicted);
+    assert_ne!(predicted, root_before);
+}
+
+#[test]
+fn deposit_surcharge_funds_relayer_subsidy_claims() {
+    let (mut ctx, mut contract) = setup(0);
+    let surcharge = NearToken::from_millinear(5);
+    contract.set_deposit_surcharge(yocto(surcharge));
+    contract.add_approved_relayer(accounts(5));
+    
+    deposit_as(&mut ctx, &mut contract, accounts(1), "secret", NearToken::from_near(1).saturating_add(surcharge));
+    assert_eq!(contract.get_deposit(&commitment_for("secret")).unwrap().denomination, NearToken::from_near(1));
+    assert_eq!(contract.get_relayer_subsidy_pool(), yocto(surcharge));
+    // Sin el recargo, el monto no corresponde a ninguna denominación
+    let without = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        deposit_as(&mut ctx, &mut contract, accounts(1), "other", NearToken::from_near(1));
+    }));
+    assert!(without.is_err());
+    
+    testing_env!(ctx.predecessor_account_id(accounts(5)).attached_deposit(NearToken::from_yoctonear(0)).build());
+    contract.claim_relayer_subsidy(yocto(NearToken::from_millinear(3)));
+    assert_eq!(transfers(), vec![(accounts(5), NearToken::from_millinear(3))]);
+    assert_eq!(contract.get_relayer_subsidy_pool(), yocto(NearToken::from_millinear(2)));
+    
+    testing_env!(ctx.predecessor_account_id(accounts(4)).build());
+    let unapproved = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        contract.claim_relayer_subsidy(yocto(NearToken::from_millinear(1)));
+    }));
+    assert!(unapproved.is_err());
+}
+
+#[test]
+fn amounts_one_yocto_off_a_denomination_are_rejected() {
+    let (mut ctx, mut contract) = setup(0);
+    let one_near = NearToken::from_near(1).as_yoctonear();
+    assert_eq!(contract.matches_denomination(NearToken::from_yoctonear(one_near)), Some(NearToken::from_near(1)));
+    assert!(contract.is_fixed_denomination(U128(one_near)));
+    for amount in [one_near - 1, one_near + 1] {
+        assert_eq!(contract.matches_denomination(NearToken::from_yoctonear(amount)), None);
+        assert!(!contract.is_fixed_denomination(U128(amount)));
+        let rejected = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            deposit_as(&mut ctx, &mut contract, accounts(1), "secret", NearToken::from_yoctonear(amount));
+        }));
+        assert!(rejected.is_err());
+    }
+    assert_eq!(contract.get_total_deposits(), 0);
+}
+
+#[test]
+fn rekey_moves_legacy_commitments_to_new_encoding() {
+    let (mut ctx, _) = setup(0);
+    let mut legacy = migration::LegacyUtxoMixer {
+        deposits: LookupMap::new(b"d"),
+        spent_outputs: UnorderedSet::new(b"s"),
+        owner: accounts(0),
+        fee_basis_points: 0,
+        deposit_counts: LookupMap::new(b"c"),
+    };
+    let secrets = ["old-1", "old-2", "old-3"];
+    let commitments: Vec<String> = secrets
+        .iter()
+        .map(|secret| commitment_for_scheme(secret, SCHEME_V1, HashScheme::Sha256))
+        .collect();
+    for commitment in &commitments {
+        env::storage_write(&[b"d".as_slice(), &borsh::to_vec(commitment).unwrap()].concat(),
+            &borsh::to_vec(&(NearToken::from_near(1), 0u64)).unwrap());
+    }
+    legacy.deposit_counts.insert(&NearToken::from_near(1), &3);
+    env::state_write(&legacy);
+    let mut contract = UtxoMixer::migrate();
+    
+    // Del mapa heredado al versionado; los desconocidos se omiten
+    let mut batch = commitments.clone();
+    batch.push(commitment_for("unknown"));
+    assert_eq!(contract.rekey_deposits(batch, false), 3);
+    assert!(commitments.iter().all(|c| contract.deposits.get(c).is_none() && contract.versioned_deposits.get(c).is_some()));
+    
+    // Del hex a la clave en bytes
+    assert_eq!(contract.rekey_deposits(commitments.clone(), true), 3);
+    assert!(commitments.iter().all(|c| contract.get_deposit(c).is_none()
+        && contract.get_deposit(&format!("{}{}", RAW_COMMITMENT_PREFIX, c)).is_some()));
+    assert_eq!(contract.get_total_deposits(), 3);
+    
+    for secret in secrets {
+        withdraw_after_delay(&mut ctx, &mut contract, accounts(2), secret);
+        assert_eq!(transfers(), vec![(accounts(2), NearToken::from_near(1))]);
+    }
+    assert_eq!(contract.get_total_deposits(), 0);
+}
+
+#[test]
+fn thin_pool_withdrawal_pays_the_low_anonymity_fee() {
+    let (mut ctx, mut contract) = setup(100);
+    assert_eq!(contract.get_low_anonymity_fee(), (0, 0));
+    contract.set_low_anonymity_fee(500, 3);
+    assert_eq!(contract.get_low_anonymity_fee(), (500, 3));
+    
+    // Con la nota que se retira, el pool tiene 2 notas: por debajo del piso
+    deposit_as(&mut ctx, &mut contract, accounts(1), "a", NearToken::from_near(1));
+    deposit_as(&mut ctx, &mut contract, accounts(1), "b", NearToken::from_near(1));
+    withdraw_after_delay(&mut ctx, &mut contract, accounts(2), "a");
+    assert_eq!(transfers(), vec![(accounts(2), NearToken::from_millinear(950))]);
+    
+    // Con 3 notas el pool ya está sano y se cobra la comisión normal
+    deposit_as(&mut ctx, &mut contract, accounts(1), "c", NearToken::from_near(1));
+    deposit_as(&mut ctx, &mut contract, accounts(1), "d", NearToken::from_near(1));
+    withdraw_after_delay(&mut ctx, &mut contract, accounts(2), "b");
+    assert_eq!(transfers(), vec![(accounts(2), NearToken::from_millinear(990))]);
+}
+
+#[test]
+fn low_anonymity_fee_applies_to_batch_and_split_withdrawals() {
+    let (mut ctx, mut contract) = setup(100);
+    contract.set_low_anonymity_fee(500, 3);
+    deposit_as(&mut ctx, &mut contract, accounts(1), "a", NearToken::from_near(1));
+    deposit_as(&mut ctx, &mut contract, accounts(1), "b", NearToken::from_near(1));
+    assert_eq!(contract.get_effective_fee(NearToken::from_near(1), accounts(2), None, None), yocto(NearToken::from_millinear(50)));
+    assert_eq!(contract.get_denomination_overview()[0].withdrawal_fee, yocto(NearToken::from_millinear(50)));
+    
+    // Sin comisión de relayer, `batch_withdraw` cobra lo mismo que `withdraw`
+    testing_env!(ctx
+        .predecessor_account_id(accounts(5))
+        .attached_deposit(NearToken::from_yoctonear(0))
+        .block_timestamp(env::block_timestamp() + MIN_DELAY * 1_000_000_000)
+        .build());
+    contract.batch_withdraw(vec![withdraw_request(accounts(2), "a", NearToken::from_yoctonear(0))]);
+    assert_eq!(transfers(), vec![(accounts(2), NearToken::from_millinear(950))]);
+    
+    // El retiro repartido también: los pagos deben sumar exactamente el neto tras la comisión de pool escaso
+    split_after_delay(&mut ctx, &mut contract, "b", vec![
+        (accounts(2), NearToken::from_millinear(500)),
+        (accounts(3), NearToken::from_millinear(450)),
+    ]);
+    assert_eq!(transfers(), vec![
+        (accounts(2), NearToken::from_millinear(500)),
+        (accounts(3), NearToken::from_millinear(450)),
+    ]);
+}
+
+#[test]
+fn live_note_can_never_be_spent_at_the_same_time() {
+    let (mut ctx, mut contract) = setup(100);
+    let pair = vec![(commitment_for("secret"), withdrawal_hash_for("secret"))];
+    deposit_as(&mut ctx, &mut contract, accounts(1), "secret", NearToken::from_near(1));
+    
+    // En vuelo, la nota ya no está activa y su hash de retiro está gastado
+    withdraw_after_delay(&mut ctx, &mut contract, accounts(2), "secret");
+    testing_env!(ctx.predecessor_account_id(accounts(0)).build());
+    assert!(contract.check_note_consistency(pair.clone()).is_empty());
+    
+    let fee = NearToken::from_millinear(10);
+    assert!(!resolve_withdrawal(&mut ctx, &mut contract, accounts(2), "secret", note(NearToken::from_near(1)), fee,
+        PromiseResult::Failed));
+    // Un callback repetido encuentra la nota activa y no la restaura dos veces
+    let repeated = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        resolve_withdrawal(&mut ctx, &mut contract, accounts(2), "secret", note(NearToken::from_near(1)), fee,
+            PromiseResult::Failed)
+    }));
+    assert!(repeated.is_err());
+    assert_eq!(contract.get_pool_stats().0, 1);
+    
+    testing_env!(ctx.predecessor_account_id(accounts(0)).build());
+    assert!(contract.check_note_consistency(pair).is_empty());
+    assert!(!contract.spent_outputs.contains(&withdrawal_hash_for("secret")));
+}
+
+#[test]
+fn required_denomination_for_net_inverts_the_fee() {
+    let (_, mut contract) = setup(100);
+    // Al 1% el neto de 1 NEAR es 0,99 NEAR
+    assert_eq!(contract.required_denomination_for_net(NearToken::from_millinear(990)), Some(NearToken::from_near(1)));
+    assert_eq!(contract.required_denomination_for_net(NearToken::from_millinear(991)), Some(NearToken::from_near(10)));
+    assert_eq!(contract.required_denomination_for_net(NearToken::from_near(99)), Some(NearToken::from_near(100)));
+    assert_eq!(contract.required_denomination_for_net(NearToken::from_millinear(99_001)), None);
+    
+    // Las denominaciones que no aceptan depósitos se saltean
+    contract.set_denomination_deprecated(NearToken::from_near(10), true);
+    assert_eq!(contract.required_denomination_for_net(NearToken::from_near(5)), Some(NearToken::from_near(100)));
+}
+
+#[test]
+#[should_panic(expected = "Deposit would exceed the value locked cap of this denomination")]
+fn deposit_beyond_tvl_cap_is_rejected() {
+    let (mut ctx, mut contract) = setup(0);
+    assert_eq!(contract.get_tvl_headroom(Some(NearToken::from_near(1))), None);
+    contract.set_max_tvl_for_denomination(NearToken::from_near(1), Some(yocto(NearToken::from_near(2))));
+    contract.set_max_tvl_global(Some(yocto(NearToken::from_near(15))));
+    assert_eq!(contract.get_max_tvl_for_denomination(NearToken::from_near(1)), Some(yocto(NearToken::from_near(2))));
+    assert_eq!(contract.get_max_tvl_global(), Some(yocto(NearToken::from_near(15))));
+    
+    // El tope global también cuenta las demás denominaciones
+    deposit_as(&mut ctx, &mut contract, accounts(1), "a", NearToken::from_near(10));
+    deposit_as(&mut ctx, &mut contract, accounts(1), "b", NearToken::from_near(1));
+    assert_eq!(contract.get_tvl_headroom(None), Some(yocto(NearToken::from_near(4))));
+    assert_eq!(contract.get_tvl_headroom(Some(NearToken::from_near(1))), Some(yocto(NearToken::from_near(1))));
+    deposit_as(&mut ctx, &mut contract, accounts(1), "c", NearToken::from_near(1));
+    assert_eq!(contract.get_tvl_headroom(Some(NearToken::from_near(1))), Some(U128(0)));
+    deposit_as(&mut ctx, &mut contract, accounts(1), "d", NearToken::from_near(1));
+}
+
+#[test]
+#[should_panic(expected = "Deposit would exceed the total value locked cap")]
+fn deposit_beyond_global_tvl_cap_is_rejected() {
+    let (mut ctx, mut contract) = setup(0);
+    contract.set_max_tvl_global(Some(yocto(NearToken::from_near(5))));
+    deposit_as(&mut ctx, &mut contract, accounts(1), "a", NearToken::from_near(10));
+}
+
+#[test]
+fn withdraw_returns_a_receipt_matching_the_transfer() {
+    let (mut ctx, mut contract) = setup(100);
+    deposit_as(&mut ctx, &mut contract, accounts(1), "secret", NearToken::from_near(10));
+    testing_env!(ctx
+        .attached_deposit(NearToken::from_yoctonear(0))
+        .block_timestamp(env::block_timestamp() + MIN_DELAY * 1_000_000_000)
+        .build());
+    let receipt = contract.withdraw(accounts(2), "secret".to_string(), None, None, None).unwrap();
+    assert_eq!(receipt, WithdrawalReceipt {
+        recipient: accounts(2),
+        net_amount: yocto(NearToken::from_near(10).saturating_sub(NearToken::from_millinear(100))),
+        fee: yocto(NearToken::from_millinear(100)),
+        relayer_fee: U128(0),
+        nullifier_hash: withdrawal_hash_for("secret"),
+    });
+    assert_eq!(transfers(), vec![(receipt.recipient, near_token(receipt.net_amount))]);
+}
+
+#[test]
+fn expired_note_is_forfeited_and_swept() {
+    let (mut ctx, mut contract) = setup(0);
+    deposit_as(&mut ctx, &mut contract, accounts(1), "before", NearToken::from_near(1));
+    
+    testing_env!(ctx
+        .predecessor_account_id(accounts(0))
+        .attached_deposit(NearToken::from_yoctonear(0))
+        .block_timestamp(1_000)
+        .build());
+    let ttl = 3600;
+    contract.set_commitment_ttl(Some(ttl));
+    assert_eq!(contract.get_commitment_ttl(), Some((ttl, env::block_timestamp())));
+    deposit_as(&mut ctx, &mut contract, accounts(1), "parked", NearToken::from_near(10));
+    
+    // Antes del vencimiento nada se decomisa
+    let commitments = vec![commitment_for("before"), commitment_for("parked")];
+    testing_env!(ctx.predecessor_account_id(accounts(0)).attached_deposit(NearToken::from_yoctonear(0)).build());
+    assert_eq!(contract.forfeit_expired_notes(commitments.clone()), 0);
+    
+    // Al vencer solo cae la nota depositada con el plazo activo
+    testing_env!(ctx.block_timestamp(env::block_timestamp() + ttl * 1_000_000_000).build());
+    assert_eq!(contract.forfeit_expired_notes(commitments), 1);
+    assert_eq!(contract.get_forfeited_pool(), yocto(NearToken::from_near(10)));
+    assert_eq!(contract.get_deposit_receipt(commitment_for("parked")), None);
+    assert!(contract.get_deposit_receipt(commitment_for("before")).is_some());
+    let withdrawn = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        withdraw_after_delay(&mut ctx, &mut contract, accounts(2), "parked");
+    }));
+    assert!(withdrawn.is_err());
+    
+    testing_env!(ctx.predecessor_account_id(accounts(0)).build());
+    contract.sweep_forfeited(Some(accounts(4)));
+    assert_eq!(transfers(), vec![(accounts(4), NearToken::from_near(10))]);
+    assert_eq!(contract.get_forfeited_pool(), U128(0));
+}
+
+#[test]
+fn commitment_cannot_impersonate_a_nullifier() {
+    let (mut ctx, mut contract) = setup(0);
+    // Sin separación de dominio, el commitment de "withdraw:s" era el hash de retiro de "s"
+    assert_eq!(commitment_for_scheme("withdraw:s", SCHEME_V1, HashScheme::Sha256),
+        withdrawal_hash_for_scheme("s", SCHEME_V1, HashScheme::Sha256));
+    let crafted = format!("withdraw:{}:s", env::current_account_id());
+    assert_ne!(commitment_for(&crafted), withdrawal_hash_for("s"));
+    
+    // Aun así, un commitment igual a un hash de retiro gastado se rechaza
+    deposit_as(&mut ctx, &mut contract, accounts(1), "s", NearToken::from_near(1));
+    withdraw_after_delay(&mut ctx, &mut contract, accounts(2), "s");
+    testing_env!(ctx.attached_deposit(NearToken::from_near(1)).build());
+    let collision = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        contract.deposit(withdrawal_hash_for("s"), None, None, None, None);
+    }));
+    assert!(collision.is_err());
+    assert_eq!(contract.get_total_deposits(), 0);
+}
+
+#[test]
+fn denomination_labels_fall_back_to_the_near_value() {
+    let (_, mut contract) = setup(0);
+    assert_eq!(contract.get_denomination_label(NearToken::from_near(10)), "10 NEAR");
+    contract.set_denomination_label(NearToken::from_near(10), Some("10 USDC".to_string()));
+    assert_eq!(contract.get_denomination_label(NearToken::from_near(10)), "10 USDC");
+    assert_eq!(contract.get_denomination_label(NearToken::from_near(1)), "1 NEAR");
+    
+    contract.set_denomination_label(NearToken::from_near(10), None);
+    assert_eq!(contract.get_denomination_label(NearToken::from_near(10)), "10 NEAR");
+}
+
+#[test]
+fn deposits_past_the_per_block_limit_wait_for_the_next_block() {
+    let (mut ctx, mut contract) = setup(0);
+    contract.set_max_inserts_per_block(2);
+    assert_eq!(contract.get_max_inserts_per_block(), 2);
+    
+    testing_env!(ctx.block_height(10).build());
+    deposit_as(&mut ctx, &mut contract, accounts(1), "a", NearToken::from_near(1));
+    deposit_as(&mut ctx, &mut contract, accounts(1), "b", NearToken::from_near(1));
+    let deferred = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        deposit_as(&mut ctx, &mut contract, accounts(1), "c", NearToken::from_near(1));
+    }));
+    assert!(deferred.is_err());
+    assert_eq!(contract.get_total_deposits(), 2);
+    
+    testing_env!(ctx.block_height(11).build());
+    deposit_as(&mut ctx, &mut contract, accounts(1), "c", NearToken::from_near(1));
+    assert_eq!(contract.get_total_deposits(), 3);
+}
+
+#[test]
+fn encrypted_note_is_stored_opaquely_and_kept_out_of_stats() {
+    let (mut ctx, mut contract) = setup(0);
+    let blob = vec![0xde, 0xad, 0xbe, 0xef];
+    testing_env!(ctx.predecessor_account_id(accounts(1)).attached_deposit(NearToken::from_near(1)).build());
+    contract.deposit(commitment_for("secret"), None, None, Some(blob.clone().into()), None);
+    deposit_as(&mut ctx, &mut contract, accounts(1), "plain", NearToken::from_near(1));
+    
+    assert_eq!(contract.get_encrypted_note(commitment_for("secret")).map(Vec::from), Some(blob.clone()));
+    assert_eq!(contract.get_encrypted_note(commitment_for("plain")), None);
+    // Ni el recibo ni las estadísticas exponen la nota cifrada
+    let encoded = near_sdk::serde_json::to_string(&Base64VecU8::from(blob)).unwrap();
+    let receipt = near_sdk::serde_json::to_string(&contract.get_deposit_receipt(commitment_for("secret"))).unwrap();
+    let overview = near_sdk::serde_json::to_string(&contract.get_denomination_overview()).unwrap();
+    assert!(!receipt.contains(encoded.trim_matches('"')) && !overview.contains(encoded.trim_matches('"')));
+    
+    testing_env!(ctx.attached_deposit(NearToken::from_near(1)).build());
+    let oversized = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        contract.deposit(commitment_for("big"), None, None, Some(vec![0; MAX_ENCRYPTED_NOTE_LEN + 1].into()), None);
+    }));
+    assert!(oversized.is_err());
+}
+
+#[test]
+fn optimal_deposit_splits_amount_into_greedy_denominations() {
+    let (mut ctx, mut contract) = setup(100);
+    testing_env!(ctx.predecessor_account_id(accounts(1)).attached_deposit(NearToken::from_near(21)).build());
+    let commitments = vec![commitment_for("a"), commitment_for("b"), commitment_for("c")];
+    let denominations = contract.deposit_optimal(commitments.clone());
+    
+    let ten = NearToken::from_near(10);
+    let one = NearToken::from_near(1);
+    assert_eq!(denominations, vec![ten, ten, one]);
+    for (commitment, denomination) in commitments.into_iter().zip(denominations) {
+        assert_eq!(contract.get_deposit_receipt(commitment).unwrap().denomination, yocto(denomination));
+    }
+    assert_eq!(contract.get_all_denomination_stats(), vec![(one, 1), (ten, 2), (NearToken::from_near(100), 0)]);
+}
+
+#[test]
+#[should_panic(expected = "Commitment count does not match the split of the amount")]
+fn optimal_deposit_rejects_wrong_commitment_count() {
+    let (mut ctx, mut contract) = setup(100);
+    testing_env!(ctx.predecessor_account_id(accounts(1)).attached_deposit(NearToken::from_near(21)).build());
+    contract.deposit_optimal(vec![commitment_for("a"), commitment_for("b")]);
+}
+
+#[test]
+#[should_panic(expected = "Secret is shorter than the minimum secret length")]
+fn withdrawal_rejects_secret_below_minimum_length() {
+    let (mut ctx, mut contract) = setup(100);
+    contract.set_min_secret_bytes(32);
+    deposit_as(&mut ctx, &mut contract, accounts(1), "short", NearToken::from_near(1));
+    withdraw_after_delay(&mut ctx, &mut contract, accounts(2), "short");
+}
+
+#[test]
+fn minimum_secret_length_spares_earlier_notes() {
+    let (mut ctx, mut contract) = setup(100);
+    deposit_as(&mut ctx, &mut contract, accounts(1), "short", NearToken::from_near(1));
+    testing_env!(ctx.predecessor_account_id(accounts(0)).block_timestamp(env::block_timestamp() + 1).build());
+    contract.set_min_secret_bytes(32);
+    
+    let too_short = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| contract.compute_commitment("short".to_string(), None, None)));
+    assert!(too_short.is_err());
+    assert_eq!(contract.compute_commitment("a".repeat(32), None, None), commitment_for(&"a".repeat(32)));
+    
+    withdraw_after_delay(&mut ctx, &mut contract, accounts(2), "short");
+    assert_eq!(transfers(), vec![(accounts(2), NearToken::from_millinear(990))]);
+}
+
+#[test]
+fn minimum_secret_length_is_measured_on_the_withdrawn_secret() {
+    let (mut ctx, mut contract) = setup(100);
+    contract.set_min_secret_bytes(32);
+    let one = NearToken::from_near(1);
+    
+    // El prefijo del pool no cuenta: un secreto de 31 bytes se rechaza aunque la nota lleve más
+    let unsalted = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| contract.compute_commitment("a".repeat(31), Some(one), None)));
+    assert!(unsalted.is_err());
+    
+    // La sal sí cuenta, en la vista y en el retiro
+    let commitment = contract.compute_commitment("a".repeat(31), Some(one), Some("1".to_string()));
+    testing_env!(ctx.predecessor_account_id(accounts(1)).block_timestamp(env::block_timestamp() + 1).attached_deposit(one).build());
+    contract.deposit(commitment, None, None, None, None);
+    withdraw_after_delay(&mut ctx, &mut contract, accounts(2), &format!("1:{}", "a".repeat(31)));
+    assert_eq!(transfers(), vec![(accounts(2), NearToken::from_millinear(990))]);
+}
+
+#[test]
+fn same_secret_can_be_deposited_in_each_pool() {
+    let (mut ctx, mut contract) = setup(100);
+    let one_near = contract.compute_commitment("shared_secret".to_string(), Some(NearToken::from_near(1)), None);
+    let ten_near = contract.compute_commitment("shared_secret".to_string(), Some(NearToken::from_near(10)), None);
+    assert_ne!(one_near, ten_near);
+    
+    testing_env!(ctx.predecessor_account_id(accounts(1)).attached_deposit(NearToken::from_near(1)).build());
+    contract.deposit(one_near, None, None, None, None);
+    testing_env!(ctx.attached_deposit(NearToken::from_near(10)).build());
+    contract.deposit(ten_near, None, None, None, None);
+    
+    // Con el mismo secreto se retira primero el pool menor, y cada nota tiene su propio hash de retiro
+    let first_nullifier = contract.compute_nullifier_hash("shared_secret".to_string());
+    withdraw_after_delay(&mut ctx, &mut contract, accounts(2), "shared_secret");
+    assert_eq!(transfers(), vec![(accounts(2), NearToken::from_millinear(990))]);
+    assert_ne!(contract.compute_nullifier_hash("shared_secret".to_string()), first_nullifier);
+    withdraw_after_delay(&mut ctx, &mut contract, accounts(2), "shared_secret");
+    assert_eq!(transfers(), vec![(accounts(2), NearToken::from_millinear(9900))]);
+}
+
+#[test]
+fn salted_notes_share_a_secret_within_one_pool() {
+    let (mut ctx, mut contract) = setup(100);
+    for salt in ["1", "2"] {
+        let commitment = contract.compute_commitment("shared_secret".to_string(), Some(NearToken::from_near(1)), Some(salt.to_string()));
+        testing_env!(ctx.predecessor_account_id(accounts(1)).attached_deposit(NearToken::from_near(1)).build());
+        contract.deposit(commitment, None, None, None, None);
+    }
+    
+    withdraw_after_delay(&mut ctx, &mut contract, accounts(2), "2:shared_secret");
+    assert_eq!(transfers(), vec![(accounts(2), NearToken::from_millinear(990))]);
+    withdraw_after_delay(&mut ctx, &mut contract, accounts(3), "1:shared_secret");
+    assert_eq!(transfers(), vec![(accounts(3), NearToken::from_millinear(990))]);
+}
+
+#[test]
+fn paginated_views_clamp_the_limit() {
+    let (mut ctx, mut contract) = setup(100);
+    let one = NearToken::from_near(1);
+    for i in 0..=MAX_PAGE_LIMIT {
+        deposit_as(&mut ctx, &mut contract, accounts(1), &format!("secret-{}", i), one);
+    }
+    assert_eq!(contract.get_deposits_in_range(0, u64::MAX, 0, u64::MAX).len() as u64, MAX_PAGE_LIMIT);
+    assert_eq!(contract.get_deposits_in_range(0, u64::MAX, MAX_PAGE_LIMIT, u64::MAX).len(), 1);
+    
+    for i in 0..=MAX_PAGE_LIMIT {
+        testing_env!(ctx.predecessor_account_id(accounts(0)).attached_deposit(NearToken::from_yoctonear(0)).build());
+        contract.add_denomination_relayer(one, format!("relayer-{}.testnet", i).parse().unwrap());
+    }
+    assert_eq!(contract.get_denomination_relayers(one, 0, u64::MAX).len() as u64, MAX_PAGE_LIMIT);
+    assert_eq!(contract.get_denomination_relayers(one, MAX_PAGE_LIMIT, u64::MAX).len(), 1);
+}
+
+#[test]
+fn withdrawal_allowlist_only_pays_approved_destinations() {
+    let (mut ctx, mut contract) = setup(100);
+    contract.add_withdrawal_destination(accounts(2));
+    contract.set_withdrawal_allowlist_enabled(true);
+    deposit_as(&mut ctx, &mut contract, accounts(1), "closed_loop_secret", NearToken::from_near(1));
+    
+    let rejected = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        withdraw_after_delay(&mut ctx, &mut contract, accounts(3), "closed_loop_secret")
+    }));
+    assert!(rejected.is_err());
+    assert!(!contract.is_withdrawal_destination_approved(accounts(3)));
+    
+    withdraw_after_delay(&mut ctx, &mut contract, accounts(2), "closed_loop_secret");
+    assert_eq!(transfers(), vec![(accounts(2), NearToken::from_millinear(990))]);
+}
+
+#[test]
+fn reserve_buffer_is_excluded_from_the_surplus() {
+    let (mut ctx, mut contract) = setup(100);
+    deposit_as(&mut ctx, &mut contract, accounts(1), "secret", NearToken::from_near(1));
+    testing_env!(ctx.predecessor_account_id(accounts(0)).attached_deposit(NearToken::from_near(2)).build());
+    contract.add_reserve();
+    assert_eq!(contract.get_reserve_buffer(), yocto(NearToken::from_near(2)));
+    
+    // Saldo con la nota, la reserva y 5 NEAR enviados por error: solo estos últimos son excedente
+    testing_env!(ctx.attached_deposit(NearToken::from_yoctonear(0)).build());
+    let storage_stake = env::storage_byte_cost().saturating_mul(u128::from(env::storage_usage()));
+    let backed = NearToken::from_near(3).saturating_add(storage_stake);
+    testing_env!(ctx.account_balance(backed.saturating_add(NearToken::from_near(5))).build());
+    assert_eq!(contract.get_surplus(), yocto(NearToken::from_near(5)));
+    contract.rescue_surplus(accounts(3));
+    assert_eq!(transfers(), vec![(accounts(3), NearToken::from_near(5))]);
+    
+    testing_env!(ctx.build());
+    contract.withdraw_reserve(yocto(NearToken::from_near(2)), accounts(3));
+    assert_eq!(transfers(), vec![(accounts(3), NearToken::from_near(2))]);
+    assert_eq!(contract.get_reserve_buffer(), U128(0));
+}